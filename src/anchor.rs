@@ -1,38 +1,223 @@
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// A collateral of a loan, valued at `price`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Collateral {
+    pub amount: Decimal,
+    pub price: Decimal,
+    pub max_ltv: Decimal,
+}
+
+/// The price of a collateral at which a loan gets liquidated
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LiquidationPrice {
+    /// The loan gets liquidated once the price drops below this
+    Price(Decimal),
+    /// The loan stays safe whatever the price, e.g. the other collaterals cover it
+    NeverLiquidatable,
+    /// The loan is liquidatable whatever the price, e.g. without any of this collateral
+    AlreadyLiquidatable,
+    /// The price doesn't fit in a `Decimal`, e.g. with a near-zero collateral
+    Overflow,
+}
+
+/// The max debt allowed before the loan gets liquidated,
+/// given the value and max LTV of each collateral: `sum(collateral_i * price_i * ltv_i)`,
+/// or `None` if it overflows
+pub fn checked_borrow_limit<I>(collaterals: I) -> Option<Decimal>
+where
+    I: IntoIterator<Item = (Decimal, Decimal)>,
+{
+    collaterals
+        .into_iter()
+        .try_fold(Decimal::ZERO, |limit, (value, max_ltv)| {
+            limit.checked_add(value.checked_mul(max_ltv)?)
+        })
+}
 
 /// Calculates the liquidation price based on the amount of bLUNA collateral
 pub fn liquidation_price(
     loan_amount: &Decimal,
     bluna_collateral: &Decimal,
-    max_ltv: &Decimal,
-) -> Decimal {
-    loan_amount / (bluna_collateral * max_ltv)
+    bluna_max_ltv: &Decimal,
+) -> LiquidationPrice {
+    solve_liquidation_price(loan_amount, bluna_collateral, bluna_max_ltv, &[])
 }
 
 /// A variation of `liquidation_price` that also takes bETH collateral into account,
-/// assuming a fixed bETH price to calculate the bLUNA liquidation price
+/// assuming a fixed bETH price to calculate the bLUNA liquidation price.
+/// Solves `bluna * price * bluna_ltv + beth * beth_price * beth_ltv = loan_amount`.
 pub fn liquidation_price_multi(
     loan_amount: &Decimal,
     beth_collateral: &Decimal,
     beth_price: &Decimal,
+    beth_max_ltv: &Decimal,
     bluna_collateral: &Decimal,
+    bluna_max_ltv: &Decimal,
+) -> LiquidationPrice {
+    let beth = Collateral {
+        amount: *beth_collateral,
+        price: *beth_price,
+        max_ltv: *beth_max_ltv,
+    };
+    solve_liquidation_price(loan_amount, bluna_collateral, bluna_max_ltv, &[beth])
+}
+
+/// The price of a collateral (`amount` at `max_ltv`) at which the loan gets liquidated,
+/// the prices of its `others` collaterals being fixed.
+/// Solves `sum(collateral_i * price_i * ltv_i) = debt` for any number of collaterals,
+/// with checked arithmetic so that no amount can make it panic.
+pub fn solve_liquidation_price(
+    debt: &Decimal,
+    amount: &Decimal,
     max_ltv: &Decimal,
-) -> Decimal {
-    ((loan_amount / max_ltv) - (beth_collateral * beth_price)) / bluna_collateral
+    others: &[Collateral],
+) -> LiquidationPrice {
+    let others_limit = others
+        .iter()
+        .map(|c| {
+            c.amount
+                .checked_mul(c.price)
+                .map(|value| (value, c.max_ltv))
+        })
+        .collect::<Option<Vec<_>>>()
+        .and_then(checked_borrow_limit);
+    // borrowing up to the limit is still allowed
+    let uncovered = match others_limit.and_then(|limit| debt.checked_sub(limit)) {
+        Some(uncovered) => uncovered,
+        None => return LiquidationPrice::Overflow,
+    };
+    if uncovered.is_sign_negative() || uncovered.is_zero() {
+        return LiquidationPrice::NeverLiquidatable;
+    }
+    let weight = match amount.checked_mul(*max_ltv) {
+        Some(weight) => weight,
+        None => return LiquidationPrice::Overflow,
+    };
+    if weight.is_sign_negative() || weight.is_zero() {
+        return LiquidationPrice::AlreadyLiquidatable;
+    }
+    match uncovered.checked_div(weight) {
+        Some(price) => LiquidationPrice::Price(price),
+        None => LiquidationPrice::Overflow,
+    }
+}
+
+/// Parameters of the overseer's partial liquidations, as set in the liquidation queue's config
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LiquidationConfig {
+    /// Share of the borrow limit a liquidation brings the debt back to
+    pub safe_ratio: Decimal,
+    /// Share of the repaid amount kept as a bid fee
+    pub bid_fee: Decimal,
+    /// Share of the repaid amount paid to the liquidator
+    pub liquidator_fee: Decimal,
+    /// Highest premium a bid can claim, assumed by the overseer when sizing a liquidation
+    pub max_premium_rate: Decimal,
+    /// Collateral value below which the whole loan gets liquidated, in UST
+    pub liquidation_threshold: Decimal,
+}
+
+impl Default for LiquidationConfig {
+    /// The mainnet config: a safe ratio of 80%, 1% fees, 30 premium slots of 1% and a 500 UST threshold
+    fn default() -> Self {
+        LiquidationConfig {
+            safe_ratio: Decimal::new(8, 1),
+            bid_fee: Decimal::new(1, 2),
+            liquidator_fee: Decimal::new(1, 2),
+            max_premium_rate: Decimal::new(3, 1),
+            liquidation_threshold: Decimal::new(500, 0),
+        }
+    }
+}
+
+/// The share of every collateral sold to liquidate a loan of `debt`,
+/// from 0 if it's safe (`debt` within `borrow_limit`) to 1 for the whole position.
+/// Mirrors the liquidation queue's `liquidation_amount` query. `None` if it overflows.
+pub fn liquidation_ratio(
+    debt: &Decimal,
+    borrow_limit: &Decimal,
+    collateral_value: &Decimal,
+    config: &LiquidationConfig,
+) -> Option<Decimal> {
+    if debt <= borrow_limit {
+        return Some(Decimal::ZERO);
+    }
+    partial_liquidation_ratio(debt, borrow_limit, collateral_value, config)
+}
+
+/// `liquidation_ratio` of a loan known to be liquidatable, e.g. right at its liquidation price.
+/// Sells just enough collateral for the repaid amount, net of the max premium and the fees,
+/// to bring the debt back to `safe_ratio` of the borrow limit.
+/// Positions worth less than `liquidation_threshold` are sold whole.
+/// Computed with checked arithmetic, `None` if it overflows.
+pub fn partial_liquidation_ratio(
+    debt: &Decimal,
+    borrow_limit: &Decimal,
+    collateral_value: &Decimal,
+    config: &LiquidationConfig,
+) -> Option<Decimal> {
+    if *collateral_value < config.liquidation_threshold {
+        return Some(Decimal::ONE);
+    }
+    let repaid = collateral_value
+        .checked_mul(Decimal::ONE.checked_sub(config.max_premium_rate)?)?
+        .checked_mul(Decimal::ONE.checked_sub(config.bid_fee)?)?
+        .checked_mul(Decimal::ONE.checked_sub(config.liquidator_fee)?)?;
+    // selling everything doesn't even repay the debt
+    if repaid <= *debt {
+        return Some(Decimal::ONE);
+    }
+    let safe_debt = borrow_limit.checked_mul(config.safe_ratio)?;
+    // already back to the safe ratio, which also keeps the repaid amount above `safe_debt`
+    if *debt <= safe_debt {
+        return Some(Decimal::ZERO);
+    }
+    let ratio = debt
+        .checked_sub(safe_debt)?
+        .checked_div(repaid.checked_sub(safe_debt)?)?;
+    Some(ratio.min(Decimal::ONE))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use rust_decimal::Decimal;
 
+    /// Amounts and prices from 0.000001 to 1,000,000
+    fn amount() -> impl Strategy<Value = Decimal> {
+        (1i64..1_000_000_000_000).prop_map(|micro| Decimal::new(micro, 6))
+    }
+
+    /// Non-negative amounts from 0 up to `Decimal::MAX`, of any scale
+    fn any_amount() -> impl Strategy<Value = Decimal> {
+        (any::<u32>(), any::<u32>(), any::<u32>(), 0u32..=28)
+            .prop_map(|(lo, mid, hi, scale)| Decimal::from_parts(lo, mid, hi, false, scale))
+    }
+
+    /// Max LTVs from 0.01 to 0.99
+    fn max_ltv() -> impl Strategy<Value = Decimal> {
+        (1i64..100).prop_map(|ltv| Decimal::new(ltv, 2))
+    }
+
+    fn collateral() -> impl Strategy<Value = Collateral> {
+        (amount(), amount(), max_ltv()).prop_map(|(amount, price, max_ltv)| Collateral {
+            amount,
+            price,
+            max_ltv,
+        })
+    }
+
     #[test]
     fn calculates_correct_liquidation_price() {
         let loan_amount = Decimal::new(1_200_000_000, 6);
         let bluna_collateral = Decimal::new(100_000_000, 6);
         let max_ltv = Decimal::new(6, 1);
         let liq_price = liquidation_price(&loan_amount, &bluna_collateral, &max_ltv);
-        assert_eq!(Decimal::new(20, 0), liq_price);
+        assert_eq!(LiquidationPrice::Price(Decimal::new(20, 0)), liq_price);
     }
 
     #[test]
@@ -46,9 +231,244 @@ mod tests {
             &loan_amount,
             &beth_collateral,
             &beth_price,
+            &max_ltv,
             &bluna_collateral,
             &max_ltv,
         );
-        assert_eq!(Decimal::new(267, 1), liq_price.round_dp(1));
+        match liq_price {
+            LiquidationPrice::Price(price) => assert_eq!(Decimal::new(267, 1), price.round_dp(1)),
+            other => panic!("Unexpected liquidation price {:?}", other),
+        }
+    }
+
+    #[test]
+    fn uses_max_ltv_of_each_collateral() {
+        let loan_amount = Decimal::new(10_000_000_000, 6);
+        let beth_collateral = Decimal::new(5_000_000, 6);
+        let beth_price = Decimal::new(2_800_000_000, 6);
+        let bluna_collateral = Decimal::new(100_000_000, 6);
+        let solved = liquidation_price_multi(
+            &loan_amount,
+            &beth_collateral,
+            &beth_price,
+            &Decimal::new(5, 1),
+            &bluna_collateral,
+            &Decimal::new(6, 1),
+        );
+        // 100 * 50 * 0.6 + 5 * 2800 * 0.5 = 10000
+        let liq_price = Decimal::new(50, 0);
+        assert_eq!(LiquidationPrice::Price(liq_price), solved);
+        assert_eq!(
+            loan_amount,
+            checked_borrow_limit(vec![
+                (bluna_collateral * liq_price, Decimal::new(6, 1)),
+                (beth_collateral * beth_price, Decimal::new(5, 1)),
+            ])
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn solves_edge_cases() {
+        let beth = Collateral {
+            amount: Decimal::new(5, 0),
+            price: Decimal::new(2_800, 0),
+            max_ltv: Decimal::new(5, 1),
+        };
+        let max_ltv = Decimal::new(6, 1);
+        // bETH alone covers up to 7,000
+        assert_eq!(
+            LiquidationPrice::NeverLiquidatable,
+            solve_liquidation_price(&Decimal::new(7_000, 0), &Decimal::ONE, &max_ltv, &[beth])
+        );
+        assert_eq!(
+            LiquidationPrice::AlreadyLiquidatable,
+            solve_liquidation_price(&Decimal::new(7_001, 0), &Decimal::ZERO, &max_ltv, &[beth])
+        );
+        assert_eq!(
+            LiquidationPrice::NeverLiquidatable,
+            solve_liquidation_price(&Decimal::ZERO, &Decimal::ZERO, &max_ltv, &[])
+        );
+        assert_eq!(
+            LiquidationPrice::Price(Decimal::new(50, 0)),
+            solve_liquidation_price(
+                &Decimal::new(10_000, 0),
+                &Decimal::new(100, 0),
+                &max_ltv,
+                &[beth]
+            )
+        );
+    }
+
+    #[test]
+    fn liquidates_down_to_the_safe_ratio() {
+        let config = LiquidationConfig::default();
+        // 100 bLUNA at $20 and a 0.6 max LTV: a 1,200 limit
+        let limit = Decimal::new(1_200, 0);
+        let value = Decimal::new(2_000, 0);
+        assert_eq!(
+            Some(Decimal::ZERO),
+            liquidation_ratio(&limit, &limit, &value, &config)
+        );
+        // (1,200 - 960) / (2,000 * 0.7 * 0.99 * 0.99 - 960)
+        assert_eq!(
+            Some(Decimal::new(5823, 4)),
+            partial_liquidation_ratio(&limit, &limit, &value, &config).map(|r| r.round_dp(4))
+        );
+        // the repaid amount can't cover 1,400
+        assert_eq!(
+            Some(Decimal::ONE),
+            liquidation_ratio(&Decimal::new(1_400, 0), &limit, &value, &config)
+        );
+        // below the threshold, the whole collateral gets sold
+        let small = Decimal::new(120, 0);
+        assert_eq!(
+            Some(Decimal::ONE),
+            partial_liquidation_ratio(&small, &small, &Decimal::new(200, 0), &config)
+        );
+        // the repaid amount exactly matches the safe debt
+        let repaid = value * Decimal::new(7, 1) * Decimal::new(99, 2) * Decimal::new(99, 2);
+        let limit = repaid / config.safe_ratio;
+        assert_eq!(
+            Some(Decimal::ZERO),
+            partial_liquidation_ratio(&(repaid - Decimal::ONE), &limit, &value, &config)
+        );
+    }
+
+    #[test]
+    fn never_panics_on_extreme_amounts() {
+        let max_ltv = Decimal::new(6, 1);
+        // a near-zero collateral backing a large debt
+        assert_eq!(
+            LiquidationPrice::Overflow,
+            liquidation_price(&Decimal::MAX, &Decimal::new(1, 28), &max_ltv)
+        );
+        assert_eq!(
+            LiquidationPrice::AlreadyLiquidatable,
+            liquidation_price(&Decimal::ONE, &Decimal::ZERO, &max_ltv)
+        );
+        let huge = Collateral {
+            amount: Decimal::MAX,
+            price: Decimal::MAX,
+            max_ltv,
+        };
+        assert_eq!(
+            LiquidationPrice::Overflow,
+            solve_liquidation_price(&Decimal::ONE, &Decimal::ONE, &max_ltv, &[huge])
+        );
+        assert_eq!(
+            LiquidationPrice::Overflow,
+            solve_liquidation_price(
+                &Decimal::MIN,
+                &Decimal::ONE,
+                &max_ltv,
+                &[Collateral {
+                    amount: Decimal::MAX,
+                    price: Decimal::ONE,
+                    max_ltv: Decimal::ONE,
+                }]
+            )
+        );
+        assert_eq!(
+            None,
+            checked_borrow_limit(vec![(Decimal::MAX, Decimal::new(2, 0))])
+        );
+        // a safe debt out of range
+        let config = LiquidationConfig {
+            safe_ratio: Decimal::new(2, 0),
+            ..LiquidationConfig::default()
+        };
+        assert_eq!(
+            None,
+            partial_liquidation_ratio(&Decimal::ONE, &Decimal::MAX, &Decimal::MAX, &config)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn matches_single_collateral_price(debt in amount(), bluna in amount(), ltv in max_ltv()) {
+            prop_assert_eq!(
+                LiquidationPrice::Price(debt / (bluna * ltv)),
+                liquidation_price(&debt, &bluna, &ltv)
+            );
+        }
+
+        #[test]
+        fn matches_bluna_and_beth_price(
+            debt in amount(),
+            bluna in amount(),
+            bluna_ltv in max_ltv(),
+            beth in collateral(),
+        ) {
+            let expected =
+                (debt - beth.amount * beth.price * beth.max_ltv) / (bluna * bluna_ltv);
+            let solved = liquidation_price_multi(
+                &debt,
+                &beth.amount,
+                &beth.price,
+                &beth.max_ltv,
+                &bluna,
+                &bluna_ltv,
+            );
+            if expected.is_sign_positive() && !expected.is_zero() {
+                prop_assert_eq!(LiquidationPrice::Price(expected), solved);
+            } else {
+                prop_assert_eq!(LiquidationPrice::NeverLiquidatable, solved);
+            }
+        }
+
+        #[test]
+        fn borrow_limit_at_solved_price_is_debt(
+            debt in amount(),
+            amount in amount(),
+            ltv in max_ltv(),
+            others in prop::collection::vec(collateral(), 0..4),
+        ) {
+            if let LiquidationPrice::Price(price) =
+                solve_liquidation_price(&debt, &amount, &ltv, &others)
+            {
+                let limit = checked_borrow_limit(
+                    others
+                        .iter()
+                        .map(|c| (c.amount * c.price, c.max_ltv))
+                        .chain(std::iter::once((amount * price, ltv))),
+                )
+                .unwrap();
+                prop_assert!((limit - debt).abs() <= debt * Decimal::new(1, 12));
+            }
+        }
+
+        #[test]
+        fn liquidation_ratio_stays_within_bounds(
+            debt in any_amount(),
+            borrow_limit in any_amount(),
+            collateral_value in any_amount(),
+            safe_ratio in max_ltv(),
+        ) {
+            let config = LiquidationConfig {
+                safe_ratio,
+                ..LiquidationConfig::default()
+            };
+            if let Some(ratio) = liquidation_ratio(&debt, &borrow_limit, &collateral_value, &config) {
+                prop_assert!(ratio >= Decimal::ZERO && ratio <= Decimal::ONE);
+            }
+            if let Some(ratio) =
+                partial_liquidation_ratio(&debt, &borrow_limit, &collateral_value, &config)
+            {
+                prop_assert!(ratio >= Decimal::ZERO && ratio <= Decimal::ONE);
+            }
+        }
+
+        #[test]
+        fn ignores_order_of_other_collaterals(
+            debt in amount(),
+            amount in amount(),
+            ltv in max_ltv(),
+            mut others in prop::collection::vec(collateral(), 0..4),
+        ) {
+            let solved = solve_liquidation_price(&debt, &amount, &ltv, &others);
+            others.reverse();
+            prop_assert_eq!(solved, solve_liquidation_price(&debt, &amount, &ltv, &others));
+        }
     }
 }
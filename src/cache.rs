@@ -1,253 +1,1316 @@
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::fs::read_to_string;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::string::ToString;
 use std::sync::Arc;
 
 use crate::anchor;
 use crate::mantle::client::{MantleClient, MantleExt};
-use crate::mantle::Contracts;
-use anyhow::{Error, Result};
+use crate::mantle::crawler::SeedCrawler;
+use crate::oracle::Prices;
+use crate::persistence::{Persistence, SharedPersistence};
+use crate::queue::{LiquidationQueue, QueueEvent, SharedQueue};
+use crate::snapshot::Snapshot;
+use crate::storage::{LoanStore, MemoryStore};
+use crate::types::{Address, CollateralToken, Micro};
+use crate::whitelist::{MaxLtvs, SharedMaxLtvs, WhitelistUpdate};
+use anyhow::{anyhow, Error, Result};
 use cached::proc_macro::cached;
 use cached::TimedCache;
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use strum_macros::{EnumDiscriminants, EnumString, ToString};
-use tokio::sync::{mpsc::Receiver, RwLock};
-use tracing::{debug, warn};
+use strum_macros::{Display, EnumDiscriminants, EnumString};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
 
-pub type Borrowers = Arc<RwLock<BTreeMap<String, Loan>>>;
+/// The loan book, a persistent map: a modified copy shares every untouched loan with the original
+pub type LoanBook = im::OrdMap<Address, Loan>;
+
+/// An immutable view of the loan book, shared without copying
+pub type Borrowers = Arc<LoanBook>;
+
+/// bETH price (in uusd) assumed when none is provided
+pub const DEFAULT_BETH_PRICE: i64 = 2_800_000_000;
+
+/// Upper bound of blocks to backfill from Mantle when the stream skips ahead
+const MAX_BACKFILL_BLOCKS: u64 = 10_000;
+
+/// Seeds older than this (about `MAX_BACKFILL_BLOCKS`) can't be fully caught up with
+const MAX_SEED_AGE_HOURS: i64 = 16;
+
+/// Max number of liquidation levels returned when including empty buckets
+pub const MAX_BUCKETS: i64 = 10_000;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Loan {
-    pub amount: Decimal,
-    pub collaterals: HashMap<String, Decimal>,
+    pub amount: Micro,
+    pub collaterals: HashMap<CollateralToken, Micro>,
+    /// The block and transaction which last modified the loan
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_updated: Option<Provenance>,
+    /// ANC rewards, as last reported by the market
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewards: Option<Rewards>,
+}
+
+impl Loan {
+    /// The value of the collaterals, borrow limit and LTV of the loan at `prices`,
+    /// `None` if the price of one of its collaterals is unknown. Fails if they overflow.
+    pub fn metrics(&self, prices: &Prices, max_ltvs: &MaxLtvs) -> Result<Option<LoanMetrics>> {
+        let values = match self.collateral_values(|token| prices.get(token), max_ltvs) {
+            Some(values) => values?,
+            None => return Ok(None),
+        };
+        let overflow = || anyhow!("Metrics of a loan of {} overflow", self.amount);
+        let collateral_value = values
+            .iter()
+            .try_fold(Decimal::ZERO, |total, (value, _)| total.checked_add(*value))
+            .ok_or_else(overflow)?;
+        let ltv = match Some(collateral_value).filter(|value| !value.is_zero()) {
+            Some(value) => Some(
+                self.amount
+                    .units()
+                    .checked_div(value)
+                    .ok_or_else(overflow)?,
+            ),
+            None => None,
+        };
+        let borrow_limit = anchor::checked_borrow_limit(values).ok_or_else(overflow)?;
+        let liquidation_prices = self
+            .collaterals
+            .keys()
+            .filter_map(|token| {
+                self.liquidation_price(*token, prices, max_ltvs)
+                    .map(|price| (*token, price))
+            })
+            .collect();
+        Ok(Some(LoanMetrics {
+            collateral_value,
+            borrow_limit,
+            ltv,
+            liquidation_prices,
+            pending_rewards: self.rewards.as_ref().map(|r| r.pending),
+            price_height: prices.height,
+        }))
+    }
+
+    /// The value and max LTV of each collateral at `price`, `None` if one of the prices is unknown
+    fn collateral_values<F>(
+        &self,
+        price: F,
+        max_ltvs: &MaxLtvs,
+    ) -> Option<Result<Vec<(Decimal, Decimal)>>>
+    where
+        F: Fn(CollateralToken) -> Option<Decimal>,
+    {
+        self.collaterals
+            .iter()
+            .map(|(token, amount)| {
+                let price = price(*token)?;
+                Some(
+                    amount
+                        .units()
+                        .checked_mul(price)
+                        .map(|value| (value, max_ltvs.get(*token)))
+                        .ok_or_else(|| anyhow!("Value of {} {} overflows", amount, token)),
+                )
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.into_iter().collect())
+    }
+
+    /// The collateral sold once the price of `token` drops below `price`, its liquidation price,
+    /// the other collaterals being valued at `prices`. `None` if the price of one of them is unknown.
+    /// Fails if the amounts overflow.
+    pub fn liquidation_amounts(
+        &self,
+        token: CollateralToken,
+        price: &Decimal,
+        prices: &Prices,
+        max_ltvs: &MaxLtvs,
+        config: &anchor::LiquidationConfig,
+    ) -> Result<Option<HashMap<CollateralToken, Micro>>> {
+        let price = |other| {
+            if other == token {
+                Some(*price)
+            } else {
+                prices.get(other)
+            }
+        };
+        let values = match self.collateral_values(price, max_ltvs) {
+            Some(values) => values?,
+            None => return Ok(None),
+        };
+        let overflow = || anyhow!("Liquidation of a loan of {} overflows", self.amount);
+        let collateral_value = values
+            .iter()
+            .try_fold(Decimal::ZERO, |total, (value, _)| total.checked_add(*value))
+            .ok_or_else(overflow)?;
+        let borrow_limit = anchor::checked_borrow_limit(values).ok_or_else(overflow)?;
+        let ratio = anchor::partial_liquidation_ratio(
+            &self.amount.units(),
+            &borrow_limit,
+            &collateral_value,
+            config,
+        )
+        .ok_or_else(overflow)?;
+        self.collaterals
+            .iter()
+            .map(|(token, amount)| {
+                let sold = amount.units().checked_mul(ratio).ok_or_else(overflow)?;
+                Ok((*token, Micro::from_units(sold)))
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    /// The price of `token` at which the loan gets liquidated, the other collaterals
+    /// being valued at `prices`. `None` if the price of one of them is unknown.
+    pub fn liquidation_price(
+        &self,
+        token: CollateralToken,
+        prices: &Prices,
+        max_ltvs: &MaxLtvs,
+    ) -> Option<anchor::LiquidationPrice> {
+        let others = self
+            .collaterals
+            .iter()
+            .filter(|(other, _)| **other != token)
+            .map(|(other, amount)| {
+                Some(anchor::Collateral {
+                    amount: amount.units(),
+                    price: prices.get(*other)?,
+                    max_ltv: max_ltvs.get(*other),
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let amount = self.collaterals.get(&token).copied().unwrap_or_default();
+        Some(anchor::solve_liquidation_price(
+            &self.amount.units(),
+            &amount.units(),
+            &max_ltvs.get(token),
+            &others,
+        ))
+    }
+}
+
+/// ANC rewards of a borrower. They accrue continuously, so they're only exact at `height`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rewards {
+    /// ANC accrued and not claimed yet
+    pub pending: Micro,
+    /// Reward index of the borrower when `pending` was computed
+    pub reward_index: Decimal,
+    pub height: u64,
+}
+
+/// Risk metrics of a loan at given prices, in UST
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoanMetrics {
+    pub collateral_value: Decimal,
+    /// Max debt before liquidation
+    pub borrow_limit: Decimal,
+    /// Debt over collateral value, `None` without collateral
+    pub ltv: Option<Decimal>,
+    /// The price of each collateral which triggers a liquidation, the others' being fixed
+    pub liquidation_prices: BTreeMap<CollateralToken, anchor::LiquidationPrice>,
+    pub pending_rewards: Option<Micro>,
+    /// Height of the prices
+    pub price_height: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub height: u64,
+    pub txhash: String,
+}
+
+/// An event applied to a loan, along with the debt (or collateral) balance it modified
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub height: u64,
+    pub txhash: String,
+    pub action: String,
+    /// The collateral token, for collateral events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract_address: Option<CollateralToken>,
+    pub delta: Micro,
+    pub before: Micro,
+    pub after: Micro,
 }
 
+/// Max number of `AuditEntry`s kept per loan
+pub const AUDIT_TRAIL_LEN: usize = 20;
+
+/// `AuditEntry::action` of the balances overwritten by a reconciliation, which have no txhash
+pub const CORRECTION_ACTION: &str = "correction";
+
+impl AuditEntry {
+    /// The entries of a loan corrected from `before` to `after` at `height`,
+    /// one per modified balance
+    pub fn corrections(height: u64, before: &Loan, after: &Loan) -> Vec<AuditEntry> {
+        let tokens: BTreeSet<CollateralToken> = before
+            .collaterals
+            .keys()
+            .chain(after.collaterals.keys())
+            .copied()
+            .collect();
+        let amount = |loan: &Loan, token| loan.collaterals.get(&token).copied().unwrap_or_default();
+        std::iter::once((None, before.amount, after.amount))
+            .chain(
+                tokens
+                    .into_iter()
+                    .map(|token| (Some(token), amount(before, token), amount(after, token))),
+            )
+            .filter(|(_, before, after)| before != after)
+            .map(|(contract_address, before, after)| AuditEntry {
+                height,
+                txhash: String::new(),
+                action: CORRECTION_ACTION.to_string(),
+                contract_address,
+                delta: after - before,
+                before,
+                after,
+            })
+            .collect()
+    }
+}
+
+/// The provenance of a loan and its most recent `AuditEntry`s, latest first
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoanHistory {
+    pub last_updated: Option<Provenance>,
+    pub events: Vec<AuditEntry>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct LiquidationLevel {
-    pub bluna_vol: Decimal,
-    pub beth_vol: Decimal,
-    pub borrowers: HashSet<String>,
+    /// The whole bLUNA and bETH collateral of the loans liquidated at this level
+    pub bluna_vol: Micro,
+    pub beth_vol: Micro,
+    /// The part of it the overseer sells, liquidating the loans only down to the safe ratio
+    pub bluna_sold: Micro,
+    pub beth_sold: Micro,
+    pub borrowers: HashSet<Address>,
 }
 
-#[derive(ToString, EnumDiscriminants, Debug, Clone, PartialEq)]
-#[strum_discriminants(derive(EnumString))]
+#[derive(Display, EnumDiscriminants, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[strum_discriminants(derive(EnumString, Display))]
 #[strum_discriminants(name(AnchorAction))]
 pub enum CacheEvent {
     #[strum_discriminants(strum(serialize = "borrow_stable"))]
-    BorrowStable { address: String, amount: Decimal },
+    BorrowStable { address: Address, amount: Micro },
     #[strum_discriminants(strum(serialize = "repay_stable"))]
-    RepayStable { address: String, amount: Decimal },
+    RepayStable { address: Address, amount: Micro },
     #[strum_discriminants(strum(serialize = "deposit_collateral"))]
     DepositCollateral {
-        address: String,
-        amount: Decimal,
-        contract_address: String,
+        address: Address,
+        amount: Micro,
+        contract_address: CollateralToken,
     },
     #[strum_discriminants(strum(serialize = "withdraw_collateral"))]
     WithdrawCollateral {
-        address: String,
-        amount: Decimal,
-        contract_address: String,
+        address: Address,
+        amount: Micro,
+        contract_address: CollateralToken,
     },
 }
 
+impl CacheEvent {
+    pub fn address(&self) -> &Address {
+        match self {
+            CacheEvent::BorrowStable { address, .. }
+            | CacheEvent::RepayStable { address, .. }
+            | CacheEvent::DepositCollateral { address, .. }
+            | CacheEvent::WithdrawCollateral { address, .. } => address,
+        }
+    }
+
+    pub fn amount(&self) -> Micro {
+        match self {
+            CacheEvent::BorrowStable { amount, .. }
+            | CacheEvent::RepayStable { amount, .. }
+            | CacheEvent::DepositCollateral { amount, .. }
+            | CacheEvent::WithdrawCollateral { amount, .. } => *amount,
+        }
+    }
+
+    /// The collateral token of a collateral event
+    pub fn contract_address(&self) -> Option<CollateralToken> {
+        match self {
+            CacheEvent::DepositCollateral {
+                contract_address, ..
+            }
+            | CacheEvent::WithdrawCollateral {
+                contract_address, ..
+            } => Some(*contract_address),
+            _ => None,
+        }
+    }
+}
+
+/// A `CacheEvent` along with the hash of the transaction which emitted it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxEvent {
+    pub txhash: String,
+    pub event: CacheEvent,
+}
+
+/// All the `CacheEvent`s emitted in a single block
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockEvents {
+    pub height: u64,
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+    pub events: Vec<TxEvent>,
+    /// Max LTV changes of the collaterals
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub whitelist: Vec<WhitelistUpdate>,
+    /// Events of the liquidation queue
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub queue: Vec<QueueEvent>,
+}
+
+impl BlockEvents {
+    pub fn cache_events(&self) -> impl Iterator<Item = &CacheEvent> {
+        self.events.iter().map(|e| &e.event)
+    }
+
+    /// Applies every event of the block to `borrowers`,
+    /// returning the `AuditEntry`s along with the address of the modified loans
+    pub fn apply_to(&self, borrowers: &mut LoanBook) -> Vec<(Address, AuditEntry)> {
+        self.events
+            .iter()
+            .filter_map(|e| {
+                apply_tx_event(borrowers, self.height, e)
+                    .map(|entry| (e.event.address().clone(), entry))
+            })
+            .collect()
+    }
+}
+
 /// A cache containing data about borrowers on Anchor and their loans
 pub struct AnchorCache {
-    pub borrowers: Borrowers,
+    pub store: Arc<dyn LoanStore>,
+    /// Max LTV of the collaterals, kept up to date from the whitelist events
+    pub max_ltvs: SharedMaxLtvs,
+    /// Bid pools of the liquidation queue, kept up to date from its events between syncs
+    pub queue: SharedQueue,
+}
+
+impl Default for AnchorCache {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStore::new()))
+    }
 }
 
 impl AnchorCache {
-    pub fn new() -> Self {
+    pub fn new(store: Arc<dyn LoanStore>) -> Self {
         AnchorCache {
-            borrowers: Arc::new(RwLock::new(BTreeMap::new())),
+            store,
+            max_ltvs: Arc::new(RwLock::new(MaxLtvs::default())),
+            queue: Arc::new(RwLock::new(LiquidationQueue::default())),
         }
     }
 
-    pub async fn seed_borrowers<P: AsRef<str>>(&self, path: P) -> Result<()> {
-        let seed: BTreeMap<String, Loan> =
-            serde_json::from_str(read_to_string(path.as_ref())?.as_ref())
-                .map_err(|e| Error::from(e))?;
-        self.borrowers.clone().write().await.extend(seed);
-        Ok(())
+    /// Loads the borrowers from a validated seed file, returning the height it was crawled at
+    /// (0 if unknown)
+    pub async fn seed_borrowers<P: AsRef<str>>(&self, path: P) -> Result<u64> {
+        let seed = Snapshot::read(path.as_ref())?;
+        let age = Utc::now() - seed.timestamp;
+        if age > Duration::hours(MAX_SEED_AGE_HOURS) {
+            warn!(
+                "Seed is {} hours old, the blocks since then may not all be backfilled",
+                age.num_hours()
+            );
+        }
+        self.store.load(seed.height, seed.borrowers).await?;
+        Ok(seed.height)
+    }
+
+    /// Crawls the loan book from the chain, returning the height it was brought to
+    pub async fn bootstrap(&self, crawler: &SeedCrawler) -> Result<u64> {
+        let seed = crawler.crawl().await?;
+        self.store.load(seed.height, seed.borrowers).await?;
+        Ok(seed.height)
+    }
+
+    /// Restores the borrowers from the latest snapshot and write-ahead log,
+    /// along with the whitelist updates and liquidation queue events of the replayed blocks.
+    /// Returns the restored block height, or `None` if nothing has been persisted yet.
+    pub async fn restore(&self, persistence: &Persistence) -> Result<Option<u64>> {
+        match persistence.restore()? {
+            Some(restored) => {
+                self.store.load(restored.height, restored.borrowers).await?;
+                let mut max_ltvs = self.max_ltvs.write().await;
+                for (height, update) in &restored.whitelist {
+                    max_ltvs.apply(*height, update);
+                }
+                let mut queue = self.queue.write().await;
+                for (height, event) in &restored.queue {
+                    queue.apply(*height, event);
+                }
+                Ok(Some(restored.height))
+            }
+            None => Ok(None),
+        }
     }
 
-    /// Initiate the listener. Populates the cache based on the incoming events.
-    pub fn init_listener(&self, mut rx: Receiver<CacheEvent>) {
-        let borrowers = self.borrowers.clone();
+    /// Initiate the listener. Populates the cache based on the incoming events,
+    /// recording every applied block with `persistence` if provided.
+    pub fn init_listener(
+        &self,
+        mut rx: Receiver<BlockEvents>,
+        persistence: Option<SharedPersistence>,
+    ) {
+        let store = self.store.clone();
+        let max_ltvs = self.max_ltvs.clone();
+        let queue = self.queue.clone();
 
         tokio::spawn(async move {
-            while let Some(event) = &rx.recv().await {
-                debug!("Received Cache Event: {}", event.to_string());
-                match event {
-                    CacheEvent::BorrowStable { address, amount }
-                    | CacheEvent::RepayStable { address, amount } => {
-                        match borrowers.write().await.get_mut(address) {
-                            Some(loan) => {
-                                match event {
-                                    CacheEvent::BorrowStable { .. } => {
-                                        // increase loan amount
-                                        loan.amount += amount;
-                                    }
-                                    CacheEvent::RepayStable { .. } => {
-                                        // decrease loan amount
-                                        loan.amount -= amount;
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            None => {},
-                            // FIXME: Columbus-5 broke this
-                            // None => match MantleClient::query_loan(address).await {
-                            //     Ok(loan) => {
-                            //         borrowers.write().await.insert(
-                            //             address.clone(),
-                            //             Loan {
-                            //                 amount: loan.amount,
-                            //                 collaterals: loan.collaterals,
-                            //             },
-                            //         );
-                            //     }
-                            //     Err(e) => warn!("{}", e),
-                            // },
-                        }
-                    }
-                    CacheEvent::DepositCollateral {
-                        address,
-                        amount,
-                        contract_address,
+            while let Some(block) = rx.recv().await {
+                let last_height = match store.height().await {
+                    Ok(height) => height,
+                    Err(e) => {
+                        error!(
+                            "Error reading height, dropping block {}: {}",
+                            block.height, e
+                        );
+                        continue;
                     }
-                    | CacheEvent::WithdrawCollateral {
-                        address,
-                        amount,
-                        contract_address,
-                    } => match borrowers.write().await.get_mut(address) {
-                        Some(loan) => {
-                            let beth = loan.collaterals.get(Contracts::BETH);
-                            let bluna = loan.collaterals.get(Contracts::BLUNA);
-
-                            match event {
-                                CacheEvent::DepositCollateral { .. } => {
-                                    // increase collaterals
-                                    match (beth, bluna, contract_address.as_ref()) {
-                                        (Some(beth), _, Contracts::BETH) => {
-                                            let new_amount = *beth + *amount;
-                                            loan.collaterals
-                                                .insert(Contracts::BETH.to_string(), new_amount);
-                                        }
-                                        (_, Some(bluna), Contracts::BLUNA) => {
-                                            let new_amount = *bluna + *amount;
-                                            loan.collaterals
-                                                .insert(Contracts::BLUNA.to_string(), new_amount);
-                                        }
-                                        (_, _, _) => {}
-                                    }
-                                }
-                                CacheEvent::WithdrawCollateral { .. } => {
-                                    // decrease collaterals
-                                    match (beth, bluna, contract_address.as_ref()) {
-                                        (Some(beth), _, Contracts::BETH) => {
-                                            let new_amount = *beth - *amount;
-                                            loan.collaterals
-                                                .insert(Contracts::BETH.to_string(), new_amount);
-                                        }
-                                        (_, Some(bluna), Contracts::BLUNA) => {
-                                            let new_amount = *bluna - *amount;
-                                            loan.collaterals
-                                                .insert(Contracts::BLUNA.to_string(), new_amount);
-                                        }
-                                        (_, _, _) => {}
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                        None => {},
-                        // FIXME: Columbus-5 broke this
-                        // None => match MantleClient::query_loan(address).await {
-                        //     Ok(loan) => {
-                        //         borrowers.write().await.insert(
-                        //             address.clone(),
-                        //             Loan {
-                        //                 amount: loan.amount,
-                        //                 collaterals: loan.collaterals,
-                        //             },
-                        //         );
-                        //     }
-                        //     Err(e) => warn!("{}", e),
-                        // },
-                    },
+                };
+                if block.height <= last_height {
+                    warn!(
+                        "Skipping block {}, already at height {}",
+                        block.height, last_height
+                    );
+                    continue;
                 }
+
+                if last_height > 0 && block.height > last_height + 1 {
+                    backfill(
+                        last_height + 1,
+                        block.height,
+                        &*store,
+                        &max_ltvs,
+                        &queue,
+                        &persistence,
+                    )
+                    .await;
+                }
+
+                apply_block(&block, &*store, &max_ltvs, &queue, &persistence).await;
             }
         });
     }
 }
 
+/// Fetches and applies the blocks in `from..to` which never reached the listener
+async fn backfill(
+    from: u64,
+    to: u64,
+    store: &dyn LoanStore,
+    max_ltvs: &SharedMaxLtvs,
+    queue: &SharedQueue,
+    persistence: &Option<SharedPersistence>,
+) {
+    let from = if to - from > MAX_BACKFILL_BLOCKS {
+        warn!(
+            "Missed {} blocks, only backfilling the last {}",
+            to - from,
+            MAX_BACKFILL_BLOCKS
+        );
+        to - MAX_BACKFILL_BLOCKS
+    } else {
+        from
+    };
+
+    info!("Backfilling blocks {} to {}...", from, to - 1);
+    for h in from..to {
+        match MantleClient::query_block_events(h).await {
+            Ok(block) => apply_block(&block, store, max_ltvs, queue, persistence).await,
+            Err(e) => error!("Error backfilling block {}: {}", h, e),
+        }
+    }
+}
+
+async fn apply_block(
+    block: &BlockEvents,
+    store: &dyn LoanStore,
+    max_ltvs: &SharedMaxLtvs,
+    queue: &SharedQueue,
+    persistence: &Option<SharedPersistence>,
+) {
+    block
+        .cache_events()
+        .for_each(|event| debug!("Received Cache Event: {}", event));
+    match store.apply_block(block).await {
+        Ok(true) => {}
+        Ok(false) => {
+            debug!("Block {} was already applied", block.height);
+            return;
+        }
+        Err(e) => {
+            error!("Error applying block {}: {}", block.height, e);
+            return;
+        }
+    }
+
+    if !block.whitelist.is_empty() {
+        let mut max_ltvs = max_ltvs.write().await;
+        for update in &block.whitelist {
+            if max_ltvs.apply(block.height, update) {
+                info!(
+                    "Max LTV of {} is now {}",
+                    update.collateral_token, update.max_ltv
+                );
+            }
+        }
+    }
+
+    if !block.queue.is_empty() {
+        let mut queue = queue.write().await;
+        for event in &block.queue {
+            debug!("Liquidation queue event: {:?}", event);
+            queue.apply(block.height, event);
+        }
+    }
+
+    if let Some(persistence) = persistence {
+        if let Err(e) = persist_block(block, store, &mut *persistence.lock().await).await {
+            error!("Error persisting block {}: {}", block.height, e);
+        }
+    }
+}
+
+async fn persist_block(
+    block: &BlockEvents,
+    store: &dyn LoanStore,
+    persistence: &mut Persistence,
+) -> Result<()> {
+    persistence.append(block)?;
+    if persistence.snapshot_due(block.height) {
+        persistence.snapshot(block.height, &*store.all().await?)?;
+    }
+    Ok(())
+}
+
+/// Applies an event emitted at `height`, recording its provenance on the loan.
+/// Returns the resulting `AuditEntry`, or `None` if the event didn't modify any loan.
+/// Events overflowing a balance are logged and dropped.
+pub fn apply_tx_event(
+    borrowers: &mut LoanBook,
+    height: u64,
+    tx_event: &TxEvent,
+) -> Option<AuditEntry> {
+    let event = &tx_event.event;
+    let before = tracked_balance(borrowers.get(event.address())?, event)?;
+    if let Err(e) = apply_event(borrowers, event) {
+        warn!("Dropped event of tx {}: {}", tx_event.txhash, e);
+        return None;
+    }
+
+    let loan = borrowers.get_mut(event.address())?;
+    let after = tracked_balance(loan, event)?;
+    loan.last_updated = Some(Provenance {
+        height,
+        txhash: tx_event.txhash.clone(),
+    });
+
+    Some(AuditEntry {
+        height,
+        txhash: tx_event.txhash.clone(),
+        action: AnchorAction::from(event).to_string(),
+        contract_address: event.contract_address(),
+        delta: after - before,
+        before,
+        after,
+    })
+}
+
+/// The balance of `loan` modified by `event`, i.e. the debt or one of the collaterals
+fn tracked_balance(loan: &Loan, event: &CacheEvent) -> Option<Micro> {
+    match event.contract_address() {
+        Some(token) => loan.collaterals.get(&token).copied(),
+        None => Some(loan.amount),
+    }
+}
+
+/// Applies a single event to the borrowers map.
+/// Fails without modifying the loan if the resulting balance overflows.
+pub fn apply_event(borrowers: &mut LoanBook, event: &CacheEvent) -> Result<()> {
+    let (balance, amount, increase) = match event {
+        // increase loan amount
+        CacheEvent::BorrowStable { address, amount } => (
+            borrowers.get_mut(address).map(|loan| &mut loan.amount),
+            amount,
+            true,
+        ),
+        // decrease loan amount
+        CacheEvent::RepayStable { address, amount } => (
+            borrowers.get_mut(address).map(|loan| &mut loan.amount),
+            amount,
+            false,
+        ),
+        // increase collaterals
+        CacheEvent::DepositCollateral {
+            address,
+            amount,
+            contract_address,
+        } => (
+            collateral_mut(borrowers, address, *contract_address),
+            amount,
+            true,
+        ),
+        // decrease collaterals
+        CacheEvent::WithdrawCollateral {
+            address,
+            amount,
+            contract_address,
+        } => (
+            collateral_mut(borrowers, address, *contract_address),
+            amount,
+            false,
+        ),
+    };
+    // FIXME: Columbus-5 broke the fallback of fetching unknown borrowers
+    // using `MantleClient::query_loan()`, so those events are dropped for now
+    let balance = match balance {
+        Some(balance) => balance,
+        None => return Ok(()),
+    };
+    let result = if increase {
+        balance.checked_add(*amount)
+    } else {
+        balance.checked_sub(*amount)
+    };
+    *balance = result.ok_or_else(|| anyhow!("Balance of {} overflows", event.address()))?;
+    Ok(())
+}
+
+fn collateral_mut<'a>(
+    borrowers: &'a mut LoanBook,
+    address: &Address,
+    token: CollateralToken,
+) -> Option<&'a mut Micro> {
+    borrowers
+        .get_mut(address)
+        .and_then(|loan| loan.collaterals.get_mut(&token))
+}
+
 #[cached(
     result = true,
     type = "TimedCache<String, String>",
     create = "{ TimedCache::with_lifespan_and_capacity(10, 100000) }",
-    convert = r#"{ "borrowers".to_string() }"#
+    convert = r#"{ format!("borrowers@{:?}", _height) }"#
 )]
-/// TLRU cache of the serialized borrower data
-pub fn cached_borrowers(borrowers: BTreeMap<String, Loan>) -> Result<String> {
-    serde_json::to_string(&borrowers).map_err(|e| Error::from(e))
+/// TLRU cache of the serialized borrower data, either current or as of `_height`
+pub fn cached_borrowers(borrowers: &LoanBook, _height: Option<u64>) -> Result<String> {
+    serde_json::to_string(borrowers).map_err(Error::from)
+}
+
+/// Default width of the liquidation price buckets of `token`, in UST
+pub fn default_liquidation_step(token: CollateralToken) -> Decimal {
+    match token {
+        CollateralToken::Bluna => Decimal::new(1, 1),
+        CollateralToken::Beth => Decimal::new(10, 0),
+    }
+}
+
+/// The width of the buckets liquidation prices are grouped into
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BucketScale {
+    /// Buckets of a fixed width, in UST, aligned on its multiples
+    Linear(Decimal),
+    /// Buckets each wider than the previous one by this percentage, aligned on $1
+    Log(Decimal),
+}
+
+/// How liquidation prices are grouped into levels. Each level is keyed by the lower bound
+/// of its bucket, so a price always falls in the same bucket whatever the other loans.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Bucketing {
+    pub scale: BucketScale,
+    /// Liquidation prices below `min` or above `max` are left out
+    pub min: Option<Decimal>,
+    pub max: Option<Decimal>,
+    /// Whether to include the buckets no loan falls in, from `min` (or the lowest level) to `max` (or the highest)
+    pub include_empty: bool,
+}
+
+impl Bucketing {
+    pub fn linear(step: Decimal) -> Self {
+        Bucketing {
+            scale: BucketScale::Linear(step),
+            min: None,
+            max: None,
+            include_empty: false,
+        }
+    }
+
+    /// Buckets as wide as `percent` of the current `price`
+    pub fn percent_of(price: &Decimal, percent: &Decimal) -> Result<Self> {
+        price
+            .checked_mul(*percent)
+            .and_then(|step| step.checked_div(Decimal::ONE_HUNDRED))
+            .map(Self::linear)
+            .ok_or_else(|| anyhow!("{}% of {} is out of range", percent, price))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match self.scale {
+            BucketScale::Linear(step) if step.is_sign_negative() || step.is_zero() => {
+                return Err(anyhow!("The step must be positive, not {}", step))
+            }
+            BucketScale::Log(percent) if percent.is_sign_negative() || percent.is_zero() => {
+                return Err(anyhow!("The log step must be positive, not {}%", percent))
+            }
+            BucketScale::Log(percent) => {
+                // the buckets are the narrowest at the bottom of the range,
+                // where their rounded bounds may collide or drop to 0
+                let lowest = self
+                    .min
+                    .filter(|min| !min.is_sign_negative() && !min.is_zero())
+                    .unwrap_or(Decimal::ONE);
+                if self
+                    .index(&lowest)
+                    .and_then(|index| self.lower_bound(index))
+                    .is_none()
+                {
+                    return Err(anyhow!(
+                        "The log step of {}% is too narrow at {}",
+                        percent,
+                        lowest
+                    ));
+                }
+            }
+            _ => {}
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Err(anyhow!("Invalid range {} to {}", min, max));
+            }
+        }
+        Ok(())
+    }
+
+    /// How much wider each log bucket is than the previous one
+    fn growth(percent: Decimal) -> Option<f64> {
+        percent
+            .checked_div(Decimal::ONE_HUNDRED)
+            .and_then(|percent| Decimal::ONE.checked_add(percent))?
+            .to_f64()
+    }
+
+    /// Whether `price` is within the range
+    pub fn contains(&self, price: &Decimal) -> bool {
+        self.min.is_none_or(|min| *price >= min) && self.max.is_none_or(|max| *price <= max)
+    }
+
+    /// Index of the bucket of `price`, `None` if it can't be bucketed
+    /// (e.g. a log bucket of 0, or an index out of range)
+    pub fn index(&self, price: &Decimal) -> Option<i64> {
+        match self.scale {
+            BucketScale::Linear(step) => price.checked_div(step)?.floor().to_i64(),
+            BucketScale::Log(percent) => {
+                if price.is_sign_negative() || price.is_zero() {
+                    return None;
+                }
+                let growth = Self::growth(percent)?;
+                let index = (price.to_f64()?.ln() / growth.ln()).floor();
+                if !index.is_finite() {
+                    return None;
+                }
+                // the lower bounds are rounded, so align on them rather than on the exact powers
+                let mut index = index as i64;
+                while self.lower_bound(index)? > *price {
+                    index -= 1;
+                }
+                while self.lower_bound(index.checked_add(1)?)? <= *price {
+                    index += 1;
+                }
+                Some(index)
+            }
+        }
+    }
+
+    /// The lower bound of bucket `index`, the key of its level.
+    /// `None` if it's out of range, or for a log bucket rounded to 0 or to the key of the next one.
+    pub fn lower_bound(&self, index: i64) -> Option<Decimal> {
+        match self.scale {
+            BucketScale::Linear(step) => {
+                let mut bound = Decimal::from(index).checked_mul(step)?;
+                bound.rescale(step.scale());
+                Some(bound)
+            }
+            BucketScale::Log(percent) => {
+                let growth = Self::growth(percent)?;
+                let bound = |index: i64| {
+                    Decimal::from_f64(growth.powf(index as f64))
+                        .map(|bound| bound.round_dp(Micro::DECIMALS).normalize())
+                };
+                let lower = bound(index)?;
+                let next = index.checked_add(1).and_then(bound);
+                if lower.is_zero() || next.is_some_and(|next| next <= lower) {
+                    return None;
+                }
+                Some(lower)
+            }
+        }
+    }
 }
 
 #[cached(
     result = true,
     type = "TimedCache<String, String>",
     create = "{ TimedCache::with_lifespan_and_capacity(10, 100000) }",
-    convert = r#"{ beth_price.clone().to_string() }"#
+    convert = r#"{
+        format!(
+            "{}:{:?}/{:?}@{:?}/{}/{}",
+            collateral,
+            prices.prices,
+            bucketing,
+            _height,
+            max_ltvs.get(CollateralToken::Bluna),
+            max_ltvs.get(CollateralToken::Beth)
+        )
+    }"#
 )]
-/// TLRU cache of the calculated serialized liquidation levels
+/// TLRU cache of the calculated serialized liquidation levels of `collateral`,
+/// the other collaterals being valued at `prices`, either current or as of `_height`.
+/// Each level holds both the whole collateral of its loans and the part of it actually sold.
 pub fn cached_liquidations(
-    borrowers: BTreeMap<String, Loan>,
-    beth_price: &Decimal,
+    borrowers: &LoanBook,
+    collateral: CollateralToken,
+    prices: &Prices,
+    bucketing: &Bucketing,
+    max_ltvs: &MaxLtvs,
+    _height: Option<u64>,
 ) -> Result<String> {
-    let data = borrowers.iter().fold(
-        BTreeMap::new(),
-        |mut acc: BTreeMap<Decimal, LiquidationLevel>, (address, loan)| {
-            let beth = loan
-                .collaterals
-                .get(Contracts::BETH)
-                .map(|c| c.clone())
-                .unwrap_or(Decimal::new(0, 0));
-            let max_ltv = Decimal::new(6, 1);
-
-            if let Some(bluna) = loan.collaterals.get(Contracts::BLUNA) {
-                let liq_price = anchor::liquidation_price_multi(
-                    &loan.amount,
-                    &beth,
-                    beth_price,
-                    bluna,
-                    &max_ltv,
-                );
-                let liq_key = liq_price.round_dp(1);
+    bucketing.validate()?;
+    let config = anchor::LiquidationConfig::default();
+    let mut levels: BTreeMap<i64, LiquidationLevel> = BTreeMap::new();
+    for (address, loan) in borrowers {
+        if !loan.collaterals.contains_key(&collateral) {
+            continue;
+        }
+        let price = match loan.liquidation_price(collateral, prices, max_ltvs) {
+            Some(anchor::LiquidationPrice::Price(price)) => price,
+            // loans which a price drop of `collateral` alone can't liquidate are left out
+            Some(anchor::LiquidationPrice::NeverLiquidatable)
+            | Some(anchor::LiquidationPrice::AlreadyLiquidatable) => continue,
+            Some(anchor::LiquidationPrice::Overflow) => {
+                warn!("Liquidation price of {} overflows", address);
+                continue;
+            }
+            // the price of one of its other collaterals is unknown
+            None => continue,
+        };
+        let index = match bucketing.index(&price) {
+            Some(index) if bucketing.contains(&price) => index,
+            _ => continue,
+        };
+        let sold = match loan.liquidation_amounts(collateral, &price, prices, max_ltvs, &config) {
+            Ok(sold) => sold.unwrap_or_default(),
+            Err(e) => {
+                warn!("Error liquidating {}: {}", address, e);
+                continue;
+            }
+        };
+        let amount = |token| loan.collaterals.get(&token).copied().unwrap_or_default();
+        let sold = |token| sold.get(&token).copied().unwrap_or_default();
+        let level = levels.entry(index).or_default();
+        let sums = (
+            level.bluna_vol.checked_add(amount(CollateralToken::Bluna)),
+            level.beth_vol.checked_add(amount(CollateralToken::Beth)),
+            level.bluna_sold.checked_add(sold(CollateralToken::Bluna)),
+            level.beth_sold.checked_add(sold(CollateralToken::Beth)),
+        );
+        match sums {
+            (Some(bluna_vol), Some(beth_vol), Some(bluna_sold), Some(beth_sold)) => {
+                level.bluna_vol = bluna_vol;
+                level.beth_vol = beth_vol;
+                level.bluna_sold = bluna_sold;
+                level.beth_sold = beth_sold;
+                level.borrowers.insert(address.clone());
+            }
+            _ => warn!("Collateral of the level of {} overflows", address),
+        }
+    }
+    if bucketing.include_empty {
+        let bound = |bound: Option<Decimal>, level: Option<&i64>| match bound {
+            Some(bound) => bucketing.index(&bound),
+            None => level.copied(),
+        };
+        let first = bound(bucketing.min, levels.keys().next());
+        let last = bound(bucketing.max, levels.keys().next_back());
+        if let (Some(first), Some(last)) = (first, last) {
+            if last.saturating_sub(first) >= MAX_BUCKETS {
+                return Err(anyhow!("More than {} buckets", MAX_BUCKETS));
+            }
+            for index in first..=last {
+                levels.entry(index).or_default();
+            }
+        }
+    }
+    let data = levels
+        .into_iter()
+        .map(|(index, level)| {
+            bucketing
+                .lower_bound(index)
+                .map(|bound| (bound, level))
+                .ok_or_else(|| anyhow!("Invalid bucket {}", index))
+        })
+        .collect::<Result<BTreeMap<Decimal, LiquidationLevel>>>()?;
+    serde_json::to_string(&data).map_err(Error::from)
+}
 
-                match acc.get_mut(&liq_key) {
-                    Some(liq_level) if liq_price.is_sign_positive() => {
-                        liq_level.bluna_vol += bluna.clone();
-                        liq_level.beth_vol += beth.clone();
-                        liq_level.borrowers.insert(address.clone());
-                    }
-                    None if liq_price.is_sign_positive() => {
-                        let mut borrowers = HashSet::new();
-                        borrowers.insert(address.clone());
-                        acc.insert(
-                            liq_key,
-                            LiquidationLevel {
-                                bluna_vol: bluna.clone(),
-                                beth_vol: beth.clone(),
-                                borrowers,
-                            },
-                        );
-                    }
-                    _ => {}
+/// Loans shared by the tests of the modules built on the loan book
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    /// Borrowers of the market, from the seed
+    pub const BORROWERS: [&str; 6] = [
+        "terra1003n7ppfqy9fvv0xxr0n24aqrsh0pkhwcsyxrf",
+        "terra10026lupxzp95c5r6q6spvgl8nvtll2ntgu05z5",
+        "terra10054ma5t3zyvz0qjke4yrgn7l8kl090zynwfq8",
+        "terra1004rfyte5t5srhgtqwmd8hnds5ersqtenep650",
+        "terra1fhv4r0rm43cznxyxf0uv8jl4eapgn3tnq5dntv",
+        "terra1m6lg87pz0e54wzvhn09jqgec0xk8u2229rlxkv",
+    ];
+
+    pub fn borrower(index: usize) -> Address {
+        BORROWERS[index].parse().unwrap()
+    }
+
+    /// A loan of `amount` micro UST backed by `collaterals` in micro units
+    pub fn loan(amount: i64, collaterals: &[(CollateralToken, i64)]) -> Loan {
+        Loan {
+            amount: Micro::new(amount),
+            collaterals: collaterals
+                .iter()
+                .map(|(token, amount)| (*token, Micro::new(*amount)))
+                .collect(),
+            last_updated: None,
+            rewards: None,
+        }
+    }
+
+    /// The loan book lending `loans` to `BORROWERS` in order
+    pub fn loan_book(loans: Vec<Loan>) -> LoanBook {
+        assert!(loans.len() <= BORROWERS.len());
+        loans
+            .into_iter()
+            .enumerate()
+            .map(|(index, loan)| (borrower(index), loan))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{borrower, loan, loan_book};
+    use super::*;
+    use proptest::prelude::*;
+
+    #[tokio::test]
+    async fn seeds_borrowers_with_typed_loans() {
+        let cache = AnchorCache::default();
+        cache.seed_borrowers("borrowers_seed.json").await.unwrap();
+
+        let borrower: Address = "terra1003n7ppfqy9fvv0xxr0n24aqrsh0pkhwcsyxrf"
+            .parse()
+            .unwrap();
+        let loan = cache.store.get(&borrower).await.unwrap().unwrap();
+        assert_eq!(Micro::new(397_843_444), loan.amount);
+        assert_eq!(
+            Some(&Micro::new(40_400_217)),
+            loan.collaterals.get(&CollateralToken::Bluna)
+        );
+    }
+
+    #[test]
+    fn computes_loan_metrics_at_prices() {
+        let mut collaterals = HashMap::new();
+        collaterals.insert(CollateralToken::Bluna, Micro::new(100_000_000));
+        collaterals.insert(CollateralToken::Beth, Micro::new(1_000_000));
+        let loan = Loan {
+            amount: Micro::new(1_500_000_000),
+            collaterals,
+            last_updated: None,
+            rewards: Some(Rewards {
+                pending: Micro::new(3_000_000),
+                reward_index: Decimal::ONE,
+                height: 40,
+            }),
+        };
+        let mut prices = Prices {
+            height: 42,
+            prices: BTreeMap::new(),
+        };
+        prices
+            .prices
+            .insert(CollateralToken::Bluna, Decimal::new(40, 0));
+        let max_ltvs = MaxLtvs::default();
+        // the bETH price is missing
+        assert_eq!(None, loan.metrics(&prices, &max_ltvs).unwrap());
+
+        prices
+            .prices
+            .insert(CollateralToken::Beth, Decimal::new(1_000, 0));
+        let mut liquidation_prices = BTreeMap::new();
+        // 100 * 15 * 0.6 + 1 * 1000 * 0.6 = 1500
+        liquidation_prices.insert(
+            CollateralToken::Bluna,
+            anchor::LiquidationPrice::Price(Decimal::new(15, 0)),
+        );
+        liquidation_prices.insert(
+            CollateralToken::Beth,
+            anchor::LiquidationPrice::NeverLiquidatable,
+        );
+        assert_eq!(
+            Some(LoanMetrics {
+                collateral_value: Decimal::new(5_000, 0),
+                borrow_limit: Decimal::new(3_000, 0),
+                ltv: Some(Decimal::new(3, 1)),
+                liquidation_prices,
+                pending_rewards: Some(Micro::new(3_000_000)),
+                price_height: 42,
+            }),
+            loan.metrics(&prices, &max_ltvs).unwrap()
+        );
+    }
+
+    #[test]
+    fn buckets_liquidations_along_beth_price() {
+        let loan = |amount, bluna, beth| {
+            loan(
+                amount,
+                &[
+                    (CollateralToken::Bluna, bluna),
+                    (CollateralToken::Beth, beth),
+                ],
+            )
+        };
+        let borrowers = loan_book(vec![
+            // (1500 - 100 * 10 * 0.6) / (1 * 0.6) = 1500
+            loan(1_500_000_000, 100_000_000, 1_000_000),
+            // 2000 / (2 * 0.6) = 1666.67
+            loan(2_000_000_000, 0, 2_000_000),
+            // covered by bLUNA alone
+            loan(500_000_000, 100_000_000, 0),
+        ]);
+        let mut prices = Prices::default();
+        prices
+            .prices
+            .insert(CollateralToken::Bluna, Decimal::new(10, 0));
+
+        let levels: BTreeMap<Decimal, LiquidationLevel> = serde_json::from_str(
+            &cached_liquidations(
+                &borrowers,
+                CollateralToken::Beth,
+                &prices,
+                &Bucketing::linear(Decimal::new(100, 0)),
+                &MaxLtvs::default(),
+                Some(1),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        // floored, not rounded
+        assert_eq!(
+            vec![Decimal::new(1_500, 0), Decimal::new(1_600, 0)],
+            levels.keys().copied().collect::<Vec<_>>()
+        );
+        let level = &levels[&Decimal::new(1_600, 0)];
+        assert_eq!(Micro::new(2_000_000), level.beth_vol);
+        // 58.23% of it gets sold to bring the loan back to the safe ratio
+        assert_eq!(Micro::new(1_164_653), level.beth_sold);
+        assert_eq!(Micro::zero(), level.bluna_sold);
+        assert!(level.borrowers.contains(&borrower(1)));
+    }
+
+    #[test]
+    fn aligns_buckets_on_their_lower_bound() {
+        let linear = Bucketing::linear(Decimal::new(1, 1));
+        assert_eq!(Some(29), linear.index(&Decimal::new(2_99, 2)));
+        assert_eq!(Some(Decimal::new(29, 1)), linear.lower_bound(29));
+        assert_eq!(Some(-1), linear.index(&Decimal::new(-1, 2)));
+
+        // 10% wider each: $1, $1.10, $1.21, $1.331...
+        let log = Bucketing {
+            scale: BucketScale::Log(Decimal::new(10, 0)),
+            ..linear
+        };
+        assert_eq!(Some(2), log.index(&Decimal::new(1_21, 2)));
+        assert_eq!(Some(1), log.index(&Decimal::new(1_2099, 4)));
+        assert_eq!(Some(Decimal::new(1_331, 3)), log.lower_bound(3));
+        assert_eq!(Some(-1), log.index(&Decimal::new(95, 2)));
+        assert_eq!(None, log.index(&Decimal::ZERO));
+
+        let percent = Bucketing::percent_of(&Decimal::new(40, 0), &Decimal::new(5, 0)).unwrap();
+        assert_eq!(BucketScale::Linear(Decimal::new(2, 0)), percent.scale);
+        assert!(Bucketing::percent_of(&Decimal::MAX, &Decimal::new(200, 0)).is_err());
+        assert!(Bucketing::linear(Decimal::ZERO).validate().is_err());
+
+        // out of range rather than overflowing
+        let tiny = Bucketing::linear(Decimal::new(1, 28));
+        assert_eq!(None, tiny.index(&Decimal::new(40, 0)));
+        assert_eq!(None, linear.index(&Decimal::MAX));
+        assert_eq!(None, Bucketing::linear(Decimal::MAX).lower_bound(2));
+        assert_eq!(None, log.index(&Decimal::MAX));
+        // 1.1^-200 rounds to 0
+        assert_eq!(None, log.lower_bound(-200));
+        let narrow = Bucketing {
+            min: Some(Decimal::new(1, 9)),
+            ..log
+        };
+        assert!(narrow.validate().is_err());
+        let colliding = Bucketing {
+            scale: BucketScale::Log(Decimal::new(1, 28)),
+            ..linear
+        };
+        assert!(colliding.validate().is_err());
+        assert_eq!(None, colliding.index(&Decimal::new(40, 0)));
+        let huge = Bucketing {
+            scale: BucketScale::Log(Decimal::MAX),
+            ..linear
+        };
+        assert_eq!(None, huge.index(&Decimal::MAX));
+
+        // 1,200 / (100 * 0.6) = $20
+        let borrowers = loan_book(vec![loan(
+            1_200_000_000,
+            &[(CollateralToken::Bluna, 100_000_000)],
+        )]);
+        let liquidations = |bucketing: &Bucketing| {
+            serde_json::from_str::<BTreeMap<Decimal, LiquidationLevel>>(
+                &cached_liquidations(
+                    &borrowers,
+                    CollateralToken::Bluna,
+                    &Prices::default(),
+                    bucketing,
+                    &MaxLtvs::default(),
+                    Some(2),
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+        let charted = Bucketing {
+            scale: BucketScale::Linear(Decimal::new(5, 0)),
+            min: Some(Decimal::new(12, 0)),
+            max: Some(Decimal::new(30, 0)),
+            include_empty: true,
+        };
+        let levels = liquidations(&charted);
+        assert_eq!(
+            vec![10, 15, 20, 25, 30],
+            levels
+                .keys()
+                .map(|price| price.to_i64().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, levels[&Decimal::new(20, 0)].borrowers.len());
+        assert!(levels[&Decimal::new(25, 0)].borrowers.is_empty());
+        let outside = Bucketing {
+            max: Some(Decimal::new(19, 0)),
+            include_empty: false,
+            ..charted
+        };
+        assert!(liquidations(&outside).is_empty());
+        // a $20 liquidation price is out of range of 1e-28 steps
+        assert!(liquidations(&tiny).is_empty());
+    }
+
+    #[test]
+    fn drops_events_overflowing_a_balance() {
+        let mut borrowers = loan_book(vec![loan(1_000_000, &[(CollateralToken::Bluna, 0)])]);
+        borrowers[&borrower(0)].amount = Micro::from_units(Decimal::MAX);
+        let before = borrowers.clone();
+        let event = |event| TxEvent {
+            txhash: "TXHASH".to_string(),
+            event,
+        };
+
+        let borrow = event(CacheEvent::BorrowStable {
+            address: borrower(0),
+            amount: Micro::new(1_000_000),
+        });
+        assert_eq!(None, apply_tx_event(&mut borrowers, 42, &borrow));
+        let withdraw = event(CacheEvent::WithdrawCollateral {
+            address: borrower(0),
+            amount: Micro::from_units(Decimal::MAX),
+            contract_address: CollateralToken::Bluna,
+        });
+        assert!(apply_tx_event(&mut borrowers, 42, &withdraw).is_some());
+        let withdraw_more = event(CacheEvent::WithdrawCollateral {
+            address: borrower(0),
+            amount: Micro::new(1_000_000),
+            contract_address: CollateralToken::Bluna,
+        });
+        assert_eq!(None, apply_tx_event(&mut borrowers, 43, &withdraw_more));
+        assert_eq!(before[&borrower(0)].amount, borrowers[&borrower(0)].amount);
+        assert_eq!(
+            -Micro::from_units(Decimal::MAX),
+            borrowers[&borrower(0)].collaterals[&CollateralToken::Bluna]
+        );
+    }
+
+    /// Amounts and prices from 0 up to `Decimal::MAX`
+    fn any_units() -> impl Strategy<Value = Decimal> {
+        (any::<u32>(), any::<u32>(), any::<u32>(), 0u32..=28)
+            .prop_map(|(lo, mid, hi, scale)| Decimal::from_parts(lo, mid, hi, false, scale))
+    }
+
+    proptest! {
+        #[test]
+        fn never_panics_on_extreme_loans(
+            debt in any_units(),
+            bluna in any_units(),
+            beth in any_units(),
+            bluna_price in any_units(),
+            beth_price in any_units(),
+        ) {
+            let mut loan = loan(0, &[]);
+            loan.amount = Micro::from_units(debt);
+            loan.collaterals.insert(CollateralToken::Bluna, Micro::from_units(bluna));
+            loan.collaterals.insert(CollateralToken::Beth, Micro::from_units(beth));
+            let mut prices = Prices::default();
+            prices.prices.insert(CollateralToken::Bluna, bluna_price);
+            prices.prices.insert(CollateralToken::Beth, beth_price);
+            let max_ltvs = MaxLtvs::default();
+
+            if let Ok(Some(metrics)) = loan.metrics(&prices, &max_ltvs) {
+                prop_assert!(metrics.borrow_limit <= metrics.collateral_value);
+            }
+            let config = anchor::LiquidationConfig::default();
+            if let Ok(Some(sold)) = loan.liquidation_amounts(
+                CollateralToken::Bluna,
+                &bluna_price,
+                &prices,
+                &max_ltvs,
+                &config,
+            ) {
+                for (token, amount) in sold {
+                    prop_assert!(amount <= loan.collaterals[&token]);
                 }
             }
-            acc
-        },
-    );
-    serde_json::to_string(&data).map_err(|e| Error::from(e))
+
+            // the same loan twice, summed into the same level
+            let borrowers = loan_book(vec![loan.clone(), loan]);
+            prop_assert!(cached_liquidations(
+                &borrowers,
+                CollateralToken::Bluna,
+                &prices,
+                &Bucketing::linear(Decimal::new(1, 1)),
+                &max_ltvs,
+                None,
+            )
+            .is_ok());
+        }
+    }
 }
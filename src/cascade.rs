@@ -0,0 +1,252 @@
+use anyhow::{anyhow, Result};
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::anchor::LiquidationPrice;
+use crate::cache::{Loan, LoanBook};
+use crate::oracle::Prices;
+use crate::types::{CollateralToken, Micro};
+use crate::whitelist::MaxLtvs;
+
+/// Default max number of liquidation rounds of a cascade
+pub const MAX_CASCADE_ROUNDS: usize = 100;
+
+/// A constant-product pool of a collateral against UST, as Terraswap's
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Pool {
+    pub collateral_reserve: Decimal,
+    pub ust_reserve: Decimal,
+    /// Share of the return amount kept by the pool, 0.3% on Terraswap
+    pub commission_rate: Decimal,
+}
+
+impl Pool {
+    pub fn new(collateral_reserve: Decimal, ust_reserve: Decimal) -> Result<Self> {
+        if !collateral_reserve.is_sign_positive()
+            || collateral_reserve.is_zero()
+            || !ust_reserve.is_sign_positive()
+            || ust_reserve.is_zero()
+        {
+            return Err(anyhow!("Pool reserves must be positive"));
+        }
+        Ok(Pool {
+            collateral_reserve,
+            ust_reserve,
+            commission_rate: Decimal::new(3, 3),
+        })
+    }
+
+    /// The spot price of the collateral, in UST
+    pub fn price(&self) -> Decimal {
+        self.ust_reserve / self.collateral_reserve
+    }
+
+    /// Sells `amount` of collateral, returning the UST received
+    pub fn sell(&mut self, amount: &Decimal) -> Decimal {
+        let returned = self.ust_reserve * amount / (self.collateral_reserve + amount);
+        let commission = returned * self.commission_rate;
+        // the commission stays in the pool
+        self.collateral_reserve += amount;
+        self.ust_reserve -= returned - commission;
+        returned - commission
+    }
+
+    /// Moves the price to `price` as an outside trade would, keeping the product of the reserves
+    pub fn shock(&mut self, price: &Decimal) -> Result<()> {
+        let k = (self.collateral_reserve * self.ust_reserve)
+            .to_f64()
+            .ok_or_else(|| anyhow!("Pool reserves overflow"))?;
+        let target = price.to_f64().filter(|price| *price > 0.0);
+        let collateral_reserve = target
+            .and_then(|price| Decimal::from_f64((k / price).sqrt()))
+            .ok_or_else(|| anyhow!("Invalid shocked price {}", price))?;
+        self.ust_reserve = collateral_reserve * price;
+        self.collateral_reserve = collateral_reserve;
+        Ok(())
+    }
+}
+
+/// The loans liquidated in a round, at the price the previous one left
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CascadeRound {
+    pub round: usize,
+    pub price: Decimal,
+    pub borrowers: u64,
+    pub debt: Micro,
+    /// Collateral sold to the pool
+    pub liquidated: Micro,
+    /// UST the pool paid for it
+    pub proceeds: Decimal,
+    pub price_after: Decimal,
+}
+
+/// Outcome of a cascade, from the shock until no more loans get liquidated
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Cascade {
+    pub collateral_token: CollateralToken,
+    pub initial_price: Decimal,
+    pub shocked_price: Decimal,
+    pub final_price: Decimal,
+    pub liquidated: Micro,
+    pub debt: Micro,
+    pub borrowers: u64,
+    /// Whether it stopped by itself, rather than after the max number of rounds
+    pub converged: bool,
+    pub rounds: Vec<CascadeRound>,
+    pub pool: Pool,
+}
+
+/// Simulates a cascade of liquidations of `collateral`: after the price drops by `shock` (a fraction),
+/// each round sells the collateral of the loans it liquidates to `pool`,
+/// whose new price liquidates more loans in the next round. The other collaterals stay at `prices`.
+///
+/// Each loan's liquidation price is solved once, so every round only walks the newly liquidated loans.
+pub fn simulate_cascade(
+    borrowers: &LoanBook,
+    collateral: CollateralToken,
+    prices: &Prices,
+    max_ltvs: &MaxLtvs,
+    mut pool: Pool,
+    shock: &Decimal,
+    max_rounds: usize,
+) -> Result<Cascade> {
+    if shock.is_sign_negative() || *shock >= Decimal::ONE {
+        return Err(anyhow!("The shock must be in [0, 1), not {}", shock));
+    }
+    let initial_price = pool.price();
+    pool.shock(&(initial_price * (Decimal::ONE - shock)))?;
+    let shocked_price = pool.price();
+
+    // highest liquidation price first, i.e. liquidated first as the price drops
+    let mut queue: Vec<(Decimal, &Loan)> = borrowers
+        .values()
+        .filter(|loan| {
+            loan.collaterals
+                .get(&collateral)
+                .is_some_and(|amount| !amount.is_zero())
+        })
+        .filter_map(
+            |loan| match loan.liquidation_price(collateral, prices, max_ltvs)? {
+                LiquidationPrice::Price(price) => Some((price, loan)),
+                // holding some of the collateral, a loan can't be liquidatable whatever its price
+                _ => None,
+            },
+        )
+        .collect();
+    queue.sort_by_key(|(price, _)| std::cmp::Reverse(*price));
+
+    let mut next = 0;
+    let mut rounds = vec![];
+    let mut converged = false;
+    while rounds.len() < max_rounds {
+        let price = pool.price();
+        let liquidated = queue[next..]
+            .iter()
+            .take_while(|(liquidation_price, _)| price < *liquidation_price)
+            .count();
+        if liquidated == 0 {
+            converged = true;
+            break;
+        }
+        let loans = &queue[next..next + liquidated];
+        next += liquidated;
+        let amount: Micro = loans
+            .iter()
+            .map(|(_, loan)| loan.collaterals[&collateral])
+            .sum();
+        let proceeds = pool.sell(&amount.units());
+        rounds.push(CascadeRound {
+            round: rounds.len() + 1,
+            price,
+            borrowers: liquidated as u64,
+            debt: loans.iter().map(|(_, loan)| loan.amount).sum(),
+            liquidated: amount,
+            proceeds,
+            price_after: pool.price(),
+        });
+    }
+    Ok(Cascade {
+        collateral_token: collateral,
+        initial_price,
+        shocked_price,
+        final_price: pool.price(),
+        liquidated: rounds.iter().map(|round| round.liquidated).sum(),
+        debt: rounds.iter().map(|round| round.debt).sum(),
+        borrowers: rounds.iter().map(|round| round.borrowers).sum(),
+        converged,
+        rounds,
+        pool,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{loan, loan_book};
+
+    #[test]
+    fn cascades_until_no_more_loans_are_liquidated() {
+        let loan = |amount| loan(amount, &[(CollateralToken::Bluna, 1_000_000_000)]);
+        let borrowers = loan_book(vec![
+            // liquidated below $19
+            loan(11_400_000_000),
+            // below $17, $15 and $10
+            loan(10_200_000_000),
+            loan(9_000_000_000),
+            loan(6_000_000_000),
+        ]);
+        // $20 with 10,000 bLUNA of depth
+        let pool = Pool::new(Decimal::new(10_000, 0), Decimal::new(200_000, 0)).unwrap();
+        let cascade = simulate_cascade(
+            &borrowers,
+            CollateralToken::Bluna,
+            &Prices::default(),
+            &MaxLtvs::default(),
+            pool,
+            &Decimal::new(1, 1),
+            MAX_CASCADE_ROUNDS,
+        )
+        .unwrap();
+
+        assert_eq!(Decimal::new(20, 0), cascade.initial_price);
+        assert_eq!(Decimal::new(18, 0), cascade.shocked_price.round_dp(6));
+        assert!(cascade.converged);
+        // every 1,000 bLUNA sold take the price down by $2-3: to ~$15.02, ~$12.72 then ~$10.92
+        assert_eq!(
+            vec![1, 1, 1],
+            cascade
+                .rounds
+                .iter()
+                .map(|round| round.borrowers)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Decimal::new(1502, 2), cascade.rounds[1].price.round_dp(2));
+        assert_eq!(Micro::new(3_000_000_000), cascade.liquidated);
+        assert_eq!(Micro::new(30_600_000_000), cascade.debt);
+        assert_eq!(Decimal::new(1092, 2), cascade.final_price.round_dp(2));
+
+        let stopped = simulate_cascade(
+            &borrowers,
+            CollateralToken::Bluna,
+            &Prices::default(),
+            &MaxLtvs::default(),
+            pool,
+            &Decimal::new(1, 1),
+            1,
+        )
+        .unwrap();
+        assert!(!stopped.converged);
+        assert_eq!(1, stopped.rounds.len());
+    }
+
+    #[test]
+    fn sells_along_the_constant_product() {
+        let mut pool = Pool::new(Decimal::new(1_000, 0), Decimal::new(20_000, 0)).unwrap();
+        pool.commission_rate = Decimal::ZERO;
+        // 1,000 * 20,000 = 2,000 * 10,000
+        assert_eq!(Decimal::new(10_000, 0), pool.sell(&Decimal::new(1_000, 0)));
+        assert_eq!(Decimal::new(5, 0), pool.price());
+        assert!(Pool::new(Decimal::ZERO, Decimal::ONE).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Error, Result};
+use cached::proc_macro::cached;
+use cached::TimedCache;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::anchor::{LiquidationConfig, LiquidationPrice};
+use crate::cache::{Bucketing, Loan, LoanBook, MAX_BUCKETS};
+use crate::oracle::Prices;
+use crate::types::{CollateralToken, Micro};
+use crate::whitelist::MaxLtvs;
+
+/// What gets liquidated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct DepthTotals {
+    /// In uusd
+    pub debt: u64,
+    /// The whole collateral of the loans
+    pub bluna: Micro,
+    pub beth: Micro,
+    /// The part of it the overseer sells, see `anchor::liquidation_ratio`
+    pub bluna_sold: Micro,
+    pub beth_sold: Micro,
+    pub borrowers: u64,
+}
+
+impl DepthTotals {
+    /// Adds `loan` and the collateral `sold` from it, leaving the totals untouched on error
+    fn add_loan(&mut self, loan: &Loan, sold: &HashMap<CollateralToken, Micro>) -> Result<()> {
+        let amount = |token| loan.collaterals.get(&token).copied().unwrap_or_default();
+        let sold = |token| sold.get(&token).copied().unwrap_or_default();
+        let debt = u64::try_from(loan.amount.micro_units().max(0))
+            .map_err(|_| anyhow!("Debt {} out of range", loan.amount))?;
+        self.add(&DepthTotals {
+            debt,
+            bluna: amount(CollateralToken::Bluna),
+            beth: amount(CollateralToken::Beth),
+            bluna_sold: sold(CollateralToken::Bluna),
+            beth_sold: sold(CollateralToken::Beth),
+            borrowers: 1,
+        })
+    }
+
+    /// Adds `other`, leaving the totals untouched on error
+    fn add(&mut self, other: &DepthTotals) -> Result<()> {
+        let sum = || {
+            Some(DepthTotals {
+                debt: self.debt.checked_add(other.debt)?,
+                bluna: self.bluna.checked_add(other.bluna)?,
+                beth: self.beth.checked_add(other.beth)?,
+                bluna_sold: self.bluna_sold.checked_add(other.bluna_sold)?,
+                beth_sold: self.beth_sold.checked_add(other.beth_sold)?,
+                borrowers: self.borrowers.checked_add(other.borrowers)?,
+            })
+        };
+        *self = sum().ok_or_else(|| anyhow!("Liquidated totals overflow"))?;
+        Ok(())
+    }
+}
+
+/// Everything liquidated by the time the price drops to `price`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DepthPoint {
+    /// Lower bound of the bucket, in UST
+    pub price: Decimal,
+    /// How far below the current price, in percent
+    pub drop: Decimal,
+    #[serde(flatten)]
+    pub cumulative: DepthTotals,
+}
+
+/// Cumulative liquidations as the price of a collateral drops, like the depth of an order book
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DepthCurve {
+    pub collateral_token: CollateralToken,
+    pub current_price: Decimal,
+    /// Lower bound of the bucket of the current price, where the curve starts
+    pub current_bucket: Decimal,
+    /// The loans already liquidatable at the current price, left out of the curve
+    pub liquidatable: DepthTotals,
+    /// Highest price first
+    pub points: Vec<DepthPoint>,
+}
+
+/// The depth curve of `collateral` from its price in `prices` down to `bucketing.min`,
+/// the other collaterals being valued at `prices`
+pub fn depth_curve(
+    borrowers: &LoanBook,
+    collateral: CollateralToken,
+    prices: &Prices,
+    bucketing: &Bucketing,
+    max_ltvs: &MaxLtvs,
+) -> Result<DepthCurve> {
+    bucketing.validate()?;
+    let current_price = prices
+        .get(collateral)
+        .ok_or_else(|| anyhow!("Missing price of {}", collateral))?;
+    let current = bucketing
+        .index(&current_price)
+        .ok_or_else(|| anyhow!("Can't bucket the current price {}", current_price))?;
+    let config = LiquidationConfig::default();
+    let mut liquidatable = DepthTotals::default();
+    let mut levels: BTreeMap<i64, DepthTotals> = BTreeMap::new();
+    for (address, loan) in borrowers {
+        if !loan.collaterals.contains_key(&collateral) {
+            continue;
+        }
+        let price = match loan.liquidation_price(collateral, prices, max_ltvs) {
+            Some(LiquidationPrice::Price(price)) => price,
+            _ => continue,
+        };
+        let sold = match loan.liquidation_amounts(collateral, &price, prices, max_ltvs, &config) {
+            Ok(sold) => sold.unwrap_or_default(),
+            Err(e) => {
+                warn!("Error liquidating {}: {}", address, e);
+                continue;
+            }
+        };
+        let totals = if price > current_price {
+            &mut liquidatable
+        } else {
+            match bucketing.index(&price) {
+                Some(index) if bucketing.min.is_none_or(|min| price >= min) => {
+                    levels.entry(index).or_default()
+                }
+                _ => continue,
+            }
+        };
+        if let Err(e) = totals.add_loan(loan, &sold) {
+            warn!("Error adding {} to the depth curve: {}", address, e);
+        }
+    }
+    if bucketing.include_empty {
+        let lowest = match bucketing.min {
+            Some(min) => bucketing.index(&min),
+            None => levels.keys().next().copied(),
+        };
+        if let Some(lowest) = lowest {
+            if current.saturating_sub(lowest) >= MAX_BUCKETS {
+                return Err(anyhow!("More than {} buckets", MAX_BUCKETS));
+            }
+            for index in lowest..=current {
+                levels.entry(index).or_default();
+            }
+        }
+    }
+
+    let mut cumulative = DepthTotals::default();
+    let mut points = vec![];
+    for (index, totals) in levels.iter().rev() {
+        let price = bucketing
+            .lower_bound(*index)
+            .ok_or_else(|| anyhow!("Invalid bucket {}", index))?;
+        cumulative.add(totals)?;
+        let drop = if current_price.is_zero() {
+            Decimal::ZERO
+        } else {
+            (Decimal::ONE - price / current_price) * Decimal::ONE_HUNDRED
+        };
+        points.push(DepthPoint {
+            price,
+            drop,
+            cumulative,
+        });
+    }
+    Ok(DepthCurve {
+        collateral_token: collateral,
+        current_price,
+        current_bucket: bucketing
+            .lower_bound(current)
+            .ok_or_else(|| anyhow!("Invalid bucket {}", current))?,
+        liquidatable,
+        points,
+    })
+}
+
+#[cached(
+    result = true,
+    type = "TimedCache<String, String>",
+    create = "{ TimedCache::with_lifespan_and_capacity(10, 1000) }",
+    convert = r#"{
+        format!(
+            "{}:{:?}/{:?}@{:?}/{}/{}",
+            collateral,
+            prices.prices,
+            bucketing,
+            _height,
+            max_ltvs.get(CollateralToken::Bluna),
+            max_ltvs.get(CollateralToken::Beth)
+        )
+    }"#
+)]
+/// TLRU cache of the serialized depth curve, either current or as of `_height`
+pub fn cached_depth_curve(
+    borrowers: &LoanBook,
+    collateral: CollateralToken,
+    prices: &Prices,
+    bucketing: &Bucketing,
+    max_ltvs: &MaxLtvs,
+    _height: Option<u64>,
+) -> Result<String> {
+    serde_json::to_string(&depth_curve(
+        borrowers, collateral, prices, bucketing, max_ltvs,
+    )?)
+    .map_err(Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{loan, loan_book};
+
+    #[test]
+    fn accumulates_liquidations_below_the_current_price() {
+        let loan = |amount| loan(amount, &[(CollateralToken::Bluna, 100_000_000)]);
+        // liquidated below $45, $35, $32 and $15 with 100 bLUNA at 0.6
+        let borrowers = loan_book(vec![
+            loan(2_700_000_000),
+            loan(2_100_000_000),
+            loan(1_920_000_000),
+            loan(900_000_000),
+        ]);
+        let mut prices = Prices::default();
+        prices
+            .prices
+            .insert(CollateralToken::Bluna, Decimal::new(40, 0));
+        let bucketing = Bucketing {
+            min: Some(Decimal::new(20, 0)),
+            include_empty: true,
+            ..Bucketing::linear(Decimal::new(10, 0))
+        };
+        let curve = depth_curve(
+            &borrowers,
+            CollateralToken::Bluna,
+            &prices,
+            &bucketing,
+            &MaxLtvs::default(),
+        )
+        .unwrap();
+
+        assert_eq!(Decimal::new(40, 0), curve.current_bucket);
+        assert_eq!(1, curve.liquidatable.borrowers);
+        assert_eq!(2_700_000_000, curve.liquidatable.debt);
+        // $40 is empty, $30 holds two loans and $15 is below the range
+        assert_eq!(
+            vec![(40, 0), (30, 2), (20, 2)],
+            curve
+                .points
+                .iter()
+                .map(|point| (point.price.mantissa() as i64, point.cumulative.borrowers))
+                .collect::<Vec<_>>()
+        );
+        let point = &curve.points[2];
+        assert_eq!(Decimal::new(50, 0), point.drop);
+        assert_eq!(4_020_000_000, point.cumulative.debt);
+        assert_eq!(Micro::new(200_000_000), point.cumulative.bluna);
+        assert!(point.cumulative.bluna_sold < point.cumulative.bluna);
+    }
+
+    #[test]
+    fn leaves_totals_untouched_on_overflow() {
+        let sold = HashMap::new();
+        let mut totals = DepthTotals::default();
+        let mut huge = loan(0, &[(CollateralToken::Bluna, 1_000_000)]);
+        huge.amount = Micro::from_units(Decimal::from(u64::MAX));
+        assert!(totals.add_loan(&huge, &sold).is_err());
+        assert_eq!(DepthTotals::default(), totals);
+
+        let large = loan(i64::MAX, &[(CollateralToken::Bluna, 1_000_000)]);
+        totals.add_loan(&large, &sold).unwrap();
+        totals.add_loan(&large, &sold).unwrap();
+        let before = totals;
+        assert!(totals.add_loan(&large, &sold).is_err());
+        assert_eq!(before, totals);
+        assert_eq!(2, totals.borrowers);
+        assert_eq!(Micro::new(2_000_000), totals.bluna);
+    }
+}
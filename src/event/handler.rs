@@ -1,15 +1,19 @@
 use std::collections::HashMap;
 use std::str::FromStr;
 
-use rust_decimal::Decimal;
+use chrono::{DateTime, Utc};
 use tokio::sync::mpsc::Sender;
 use tracing::{debug, error, trace};
 use tungstenite::Message;
 
-use crate::cache::{AnchorAction, CacheEvent};
+use crate::cache::{AnchorAction, BlockEvents, CacheEvent, TxEvent};
 use crate::event::{Attribute, EventDataSlim, EventTypeSlim, LogEvent};
+use crate::mantle::Contracts;
+use crate::queue::QueueEvent;
+use crate::types::{Address, CollateralToken, Micro};
+use crate::whitelist::{parse_max_ltv, WhitelistUpdate};
 
-pub async fn handle_msg(msg: Message, tx: Sender<CacheEvent>) {
+pub async fn handle_msg(msg: Message, tx: Sender<BlockEvents>) {
     match msg {
         Message::Text(txt) => {
             trace!("Received message: {}", &txt);
@@ -29,160 +33,389 @@ pub async fn handle_msg(msg: Message, tx: Sender<CacheEvent>) {
     }
 }
 
-pub async fn handle_new_block(data: EventDataSlim, tx: Sender<CacheEvent>) {
-    data.txs
+pub async fn handle_new_block(data: EventDataSlim, tx: Sender<BlockEvents>) {
+    let height = match data
+        .block
+        .header
+        .height
+        .as_ref()
+        .and_then(|h| h.parse::<u64>().ok())
+    {
+        Some(height) => height,
+        None => {
+            error!("Caught block without height: {:?}", data.block.header);
+            return;
+        }
+    };
+
+    let timestamp = data
+        .block
+        .header
+        .time
+        .as_ref()
+        .and_then(|t| t.parse::<DateTime<Utc>>().ok());
+
+    let from_contract = data
+        .txs
         .into_iter()
-        .flat_map(|tx| tx.logs.into_iter())
-        .flat_map(|log| log.events.into_iter())
-        .for_each(|event| {
-            let tx = tx.clone();
-            tokio::spawn(async move {
-                match event {
+        .flat_map(|tx| {
+            let txhash = tx.txhash.0;
+            tx.logs
+                .into_iter()
+                .flat_map(|log| log.events.into_iter())
+                .filter_map(move |event| match event {
                     LogEvent::FromContract { attributes: attrs } => {
-                        let map = attribute_map(attrs);
-                        handle_from_contract(map, tx).await;
+                        Some((txhash.clone(), attribute_map(attrs)))
                     }
-                    _ => trace!("Ignoring event: {:?}", event),
-                }
-            });
-        });
+                    _ => {
+                        trace!("Ignoring event: {:?}", event);
+                        None
+                    }
+                })
+        })
+        .collect();
+
+    tx.send(parse_block(height, timestamp, from_contract))
+        .await
+        .expect("Error sending BlockEvents");
 }
 
-fn attribute_map(attrs: Vec<Attribute>) -> HashMap<String, String> {
-    attrs
+/// Parses the `from_contract` events of a block, given along with the hash of their transaction
+pub fn parse_block(
+    height: u64,
+    timestamp: Option<DateTime<Utc>>,
+    from_contract: Vec<(String, HashMap<String, String>)>,
+) -> BlockEvents {
+    let whitelist = from_contract
+        .iter()
+        .filter_map(|(_, attrs)| parse_whitelist_update(attrs))
+        .collect();
+    let queue = from_contract
+        .iter()
+        .filter_map(|(_, attrs)| parse_queue_event(attrs))
+        .collect();
+    let events = from_contract
         .into_iter()
-        .filter(|attr| attr.value.is_some())
-        .fold(HashMap::new(), |mut acc, attr| {
-            if let Some(value) = attr.value {
-                acc.insert(attr.key.clone(), value.clone());
-                acc
-            } else {
-                acc
-            }
+        .filter_map(|(txhash, attrs)| {
+            parse_from_contract(attrs).map(|event| TxEvent { txhash, event })
         })
-        .clone()
+        .collect();
+
+    BlockEvents {
+        height,
+        timestamp,
+        events,
+        whitelist,
+        queue,
+    }
 }
 
-pub async fn handle_from_contract(attrs: HashMap<String, String>, tx: Sender<CacheEvent>) {
+pub fn attribute_map(attrs: Vec<Attribute>) -> HashMap<String, String> {
+    attrs
+        .into_iter()
+        .filter_map(|Attribute { key, value, .. }| value.map(|value| (key, value)))
+        .collect()
+}
+
+pub fn parse_from_contract(attrs: HashMap<String, String>) -> Option<CacheEvent> {
     match attrs.get("action") {
         Some(action) => {
             trace!("Handling action: {}", action);
             match AnchorAction::from_str(action.as_ref()) {
-                Ok(AnchorAction::BorrowStable) => process_borrow_stable(attrs, tx).await,
-                Ok(AnchorAction::RepayStable) => process_repay_stable(attrs, tx).await,
-                Ok(AnchorAction::DepositCollateral) => process_deposit_collateral(attrs, tx).await,
-                Ok(AnchorAction::WithdrawCollateral) => {
-                    process_withdraw_collateral(attrs, tx).await
+                Ok(AnchorAction::BorrowStable) => parse_borrow_stable(attrs),
+                Ok(AnchorAction::RepayStable) => parse_repay_stable(attrs),
+                Ok(AnchorAction::DepositCollateral) => parse_deposit_collateral(attrs),
+                Ok(AnchorAction::WithdrawCollateral) => parse_withdraw_collateral(attrs),
+                _ => {
+                    trace!("Ignoring action: {}", action);
+                    None
                 }
-                _ => trace!("Ignoring action: {}", action),
             }
         }
-        None => error!("Caught empty action. Attributes: {:?}", attrs),
+        None => {
+            error!("Caught empty action. Attributes: {:?}", attrs);
+            None
+        }
     }
 }
 
-pub async fn process_borrow_stable(attrs: HashMap<String, String>, tx: Sender<CacheEvent>) {
-    debug!("borrow_stable event: {:?}", attrs);
-    let pair = (attrs.get("borrower"), attrs.get("borrow_amount"));
-    let statement = match pair {
-        (Some(borrower), Some(amount)) => {
-            let parsed = amount
-                .parse::<i64>()
-                .map(|a| Decimal::new(a, 6))
-                .expect(&format!(
-                    "Error parsing borrow amount from event: {:?}",
-                    attrs
-                ));
-            Some((borrower.clone(), parsed.clone()))
+/// Parses the max LTV set by a `register_whitelist` or `update_whitelist` event of the overseer,
+/// ignoring collaterals which aren't tracked
+pub fn parse_whitelist_update(attrs: &HashMap<String, String>) -> Option<WhitelistUpdate> {
+    if !is_from(attrs, Contracts::OVERSEER) {
+        return None;
+    }
+    match attrs.get("action").map(String::as_str) {
+        Some("register_whitelist") | Some("update_whitelist") => {}
+        _ => return None,
+    }
+    debug!("Whitelist event: {:?}", attrs);
+    let token = attrs.get("collateral_token")?;
+    let collateral_token = match CollateralToken::from_contract_address(token) {
+        Some(token) => token,
+        None => {
+            debug!("Ignoring whitelist update of untracked token {}", token);
+            return None;
         }
-        _ => None,
     };
-    if let Some(statement) = statement {
-        tx.send(CacheEvent::BorrowStable {
-            address: statement.0,
-            amount: statement.1,
-        })
-        .await
-        .expect("Error sending BorrowStable Event")
-    }
+    Some(WhitelistUpdate {
+        collateral_token,
+        max_ltv: log_invalid(attrs, "LTV", parse_max_ltv(attrs.get("LTV")?))?,
+    })
 }
 
-pub async fn process_repay_stable(attrs: HashMap<String, String>, tx: Sender<CacheEvent>) {
-    debug!("repay_stable event: {:?}", attrs);
-    let pair = (attrs.get("borrower"), attrs.get("repay_amount"));
-    let statement = match pair {
-        (Some(borrower), Some(amount)) => {
-            let parsed = amount
-                .parse::<i64>()
-                .map(|a| Decimal::new(a, 6))
-                .expect(&format!(
-                    "Error parsing repay amount from event: {:?}",
-                    attrs
-                ));
-            Some((borrower.clone(), parsed.clone()))
+/// Parses the bids and liquidations of the liquidation queue.
+/// Bid events don't name their collateral nor premium slot, these are queried when syncing.
+pub fn parse_queue_event(attrs: &HashMap<String, String>) -> Option<QueueEvent> {
+    if !is_from(attrs, Contracts::LIQUIDATION_QUEUE) {
+        return None;
+    }
+    let event = match attrs.get("action").map(String::as_str) {
+        Some("submit_bid") => QueueEvent::SubmitBid {
+            bid_idx: parse_bid_idx(attrs)?,
+            amount: parse_amount(attrs, "amount")?,
+        },
+        Some("activate_bids") => QueueEvent::ActivateBids {
+            amount: parse_amount(attrs, "amount")?,
+        },
+        Some("retract_bid") => QueueEvent::RetractBid {
+            bid_idx: parse_bid_idx(attrs)?,
+            amount: parse_amount(attrs, "amount")?,
+        },
+        Some("execute_bid") => {
+            let token = attrs.get("collateral_token")?;
+            let collateral_token = match CollateralToken::from_contract_address(token) {
+                Some(token) => token,
+                None => {
+                    debug!("Ignoring liquidation of untracked token {}", token);
+                    return None;
+                }
+            };
+            QueueEvent::ExecuteBid {
+                collateral_token,
+                collateral_amount: parse_amount(attrs, "collateral_amount")?,
+                repay_amount: parse_amount(attrs, "repay_amount")?,
+                bid_fee: parse_amount(attrs, "bid_fee")?,
+                liquidator_fee: parse_amount(attrs, "liquidator_fee")?,
+            }
         }
-        _ => None,
+        _ => return None,
     };
-    if let Some(statement) = statement {
-        tx.send(CacheEvent::RepayStable {
-            address: statement.0,
-            amount: statement.1,
-        })
-        .await
-        .expect("Error sending RepayStable Event");
+    debug!("Liquidation queue event: {:?}", attrs);
+    Some(event)
+}
+
+/// Whether the event was emitted by `contract`, as actions share their names across contracts
+fn is_from(attrs: &HashMap<String, String>, contract: &str) -> bool {
+    attrs.get("contract_address").map(String::as_str) == Some(contract)
+}
+
+fn parse_bid_idx(attrs: &HashMap<String, String>) -> Option<u64> {
+    log_invalid(
+        attrs,
+        "bid_idx",
+        attrs.get("bid_idx")?.parse().map_err(anyhow::Error::from),
+    )
+}
+
+/// Parses a micro-unit amount attribute (e.g. `"1000000"` for 1 UST)
+fn parse_amount(attrs: &HashMap<String, String>, key: &str) -> Option<Micro> {
+    log_invalid(attrs, key, Micro::parse_micro(attrs.get(key)?))
+}
+
+fn parse_address(attrs: &HashMap<String, String>, key: &str) -> Option<Address> {
+    log_invalid(attrs, key, attrs.get(key)?.parse())
+}
+
+/// Parses the collateral token attribute, ignoring tokens which aren't tracked
+fn parse_token(attrs: &HashMap<String, String>) -> Option<CollateralToken> {
+    let token = CollateralToken::from_contract_address(attrs.get("contract_address")?);
+    if token.is_none() {
+        debug!("Ignoring unknown collateral token: {:?}", attrs);
     }
+    token
+}
+
+fn log_invalid<T>(
+    attrs: &HashMap<String, String>,
+    key: &str,
+    value: anyhow::Result<T>,
+) -> Option<T> {
+    value
+        .map_err(|e| error!("Error parsing {} from event: {} {:?}", key, e, attrs))
+        .ok()
 }
 
-pub async fn process_deposit_collateral(attrs: HashMap<String, String>, tx: Sender<CacheEvent>) {
+pub fn parse_borrow_stable(attrs: HashMap<String, String>) -> Option<CacheEvent> {
+    debug!("borrow_stable event: {:?}", attrs);
+    Some(CacheEvent::BorrowStable {
+        address: parse_address(&attrs, "borrower")?,
+        amount: parse_amount(&attrs, "borrow_amount")?,
+    })
+}
+
+pub fn parse_repay_stable(attrs: HashMap<String, String>) -> Option<CacheEvent> {
+    debug!("repay_stable event: {:?}", attrs);
+    Some(CacheEvent::RepayStable {
+        address: parse_address(&attrs, "borrower")?,
+        amount: parse_amount(&attrs, "repay_amount")?,
+    })
+}
+
+pub fn parse_deposit_collateral(attrs: HashMap<String, String>) -> Option<CacheEvent> {
     debug!("deposit_collateral event: {:?}", attrs);
-    let pair = (attrs.get("borrower"), attrs.get("amount"));
-    let statement = match pair {
-        (Some(borrower), Some(amount)) => {
-            let parsed = amount
-                .parse::<i64>()
-                .map(|a| Decimal::new(a, 6))
-                .expect(&format!(
-                    "Error parsing deposit amount from event: {:?}",
-                    attrs
-                ));
-            Some((borrower.clone(), parsed.clone()))
-        }
-        _ => None,
-    };
-    if let (Some(statement), Some(contract_address)) = (statement, attrs.get("contract_address")) {
-        tx.send(CacheEvent::DepositCollateral {
-            address: statement.0,
-            amount: statement.1,
-            contract_address: contract_address.clone(),
-        })
-        .await
-        .expect("Error sending DepositCollateral Event");
-    }
+    Some(CacheEvent::DepositCollateral {
+        address: parse_address(&attrs, "borrower")?,
+        amount: parse_amount(&attrs, "amount")?,
+        contract_address: parse_token(&attrs)?,
+    })
 }
 
-pub async fn process_withdraw_collateral(attrs: HashMap<String, String>, tx: Sender<CacheEvent>) {
+pub fn parse_withdraw_collateral(attrs: HashMap<String, String>) -> Option<CacheEvent> {
     debug!("withdraw_collateral event: {:?}", attrs);
-    let pair = (attrs.get("borrower"), attrs.get("amount"));
-    let statement = match pair {
-        (Some(borrower), Some(amount)) => {
-            let parsed = amount
-                .parse::<i64>()
-                .map(|a| Decimal::new(a, 6))
-                .expect(&format!(
-                    "Error parsing withdraw amount from event: {:?}",
-                    attrs
-                ));
-            Some((borrower.clone(), parsed.clone()))
-        }
-        _ => None,
-    };
-    if let (Some(statement), Some(contract_address)) = (statement, attrs.get("contract_address")) {
-        tx.send(CacheEvent::WithdrawCollateral {
-            address: statement.0,
-            amount: statement.1,
-            contract_address: contract_address.clone(),
-        })
-        .await
-        .expect("Error sending WithdrawCollateral Event");
+    Some(CacheEvent::WithdrawCollateral {
+        address: parse_address(&attrs, "borrower")?,
+        amount: parse_amount(&attrs, "amount")?,
+        contract_address: parse_token(&attrs)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn attrs(attrs: &[(&str, &str)]) -> HashMap<String, String> {
+        attrs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_whitelist_updates_along_with_loan_events() {
+        let block = parse_block(
+            42,
+            None,
+            vec![
+                (
+                    "TXHASH1".to_string(),
+                    attrs(&[
+                        ("contract_address", Contracts::OVERSEER),
+                        ("action", "update_whitelist"),
+                        ("collateral_token", Contracts::BETH),
+                        ("custody_contract", Contracts::MARKET),
+                        ("LTV", "0.55"),
+                    ]),
+                ),
+                (
+                    "TXHASH2".to_string(),
+                    attrs(&[
+                        ("action", "borrow_stable"),
+                        ("borrower", "terra1003n7ppfqy9fvv0xxr0n24aqrsh0pkhwcsyxrf"),
+                        ("borrow_amount", "1000000"),
+                    ]),
+                ),
+                // untracked collateral
+                (
+                    "TXHASH3".to_string(),
+                    attrs(&[
+                        ("contract_address", Contracts::OVERSEER),
+                        ("action", "register_whitelist"),
+                        ("collateral_token", Contracts::MARKET),
+                        ("LTV", "0.5"),
+                    ]),
+                ),
+                // not from the overseer
+                (
+                    "TXHASH4".to_string(),
+                    attrs(&[
+                        ("contract_address", Contracts::MARKET),
+                        ("action", "update_whitelist"),
+                        ("collateral_token", Contracts::BLUNA),
+                        ("LTV", "0.9"),
+                    ]),
+                ),
+            ],
+        );
+
+        assert_eq!(
+            vec![WhitelistUpdate {
+                collateral_token: CollateralToken::Beth,
+                max_ltv: Decimal::new(55, 2),
+            }],
+            block.whitelist
+        );
+        assert_eq!(1, block.events.len());
+        assert_eq!("TXHASH2", block.events[0].txhash);
+    }
+
+    #[test]
+    fn parses_liquidation_queue_events() {
+        let block = parse_block(
+            42,
+            None,
+            vec![
+                (
+                    "TXHASH1".to_string(),
+                    attrs(&[
+                        ("contract_address", Contracts::LIQUIDATION_QUEUE),
+                        ("action", "submit_bid"),
+                        ("bid_idx", "123"),
+                        ("amount", "500000000"),
+                    ]),
+                ),
+                (
+                    "TXHASH2".to_string(),
+                    attrs(&[
+                        ("contract_address", Contracts::LIQUIDATION_QUEUE),
+                        ("action", "execute_bid"),
+                        ("stable_denom", "uusd"),
+                        ("repay_amount", "990000000"),
+                        ("bid_fee", "5000000"),
+                        ("liquidator_fee", "5000000"),
+                        ("collateral_token", Contracts::BLUNA),
+                        ("collateral_amount", "25000000"),
+                    ]),
+                ),
+                (
+                    "TXHASH3".to_string(),
+                    attrs(&[
+                        ("contract_address", Contracts::LIQUIDATION_QUEUE),
+                        ("action", "retract_bid"),
+                        ("bid_idx", "NaN"),
+                        ("amount", "1"),
+                    ]),
+                ),
+                // not from the liquidation queue
+                (
+                    "TXHASH4".to_string(),
+                    attrs(&[
+                        ("contract_address", Contracts::MARKET),
+                        ("action", "submit_bid"),
+                        ("bid_idx", "124"),
+                        ("amount", "1"),
+                    ]),
+                ),
+            ],
+        );
+
+        assert_eq!(
+            vec![
+                QueueEvent::SubmitBid {
+                    bid_idx: 123,
+                    amount: Micro::new(500_000_000),
+                },
+                QueueEvent::ExecuteBid {
+                    collateral_token: CollateralToken::Bluna,
+                    collateral_amount: Micro::new(25_000_000),
+                    repay_amount: Micro::new(990_000_000),
+                    bid_fee: Micro::new(5_000_000),
+                    liquidator_fee: Micro::new(5_000_000),
+                },
+            ],
+            block.queue
+        );
+        assert!(block.events.is_empty());
     }
 }
@@ -39,6 +39,8 @@ pub struct EventData {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct EventDataSlim {
+    #[serde(default)]
+    pub block: BlockSlim,
     #[serde(default)]
     pub txs: Vec<TxSlim>,
 }
@@ -51,6 +53,12 @@ pub struct Block {
     pub last_commit: LastCommit,
 }
 
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct BlockSlim {
+    #[serde(default)]
+    pub header: HeaderSlim,
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Header {
     pub version: Version,
@@ -69,6 +77,12 @@ pub struct Header {
     pub proposer_address: Option<Address>,
 }
 
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct HeaderSlim {
+    pub height: Option<String>,
+    pub time: Option<String>,
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Version {
     pub block: Option<String>,
@@ -188,6 +202,8 @@ pub struct Tx {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TxSlim {
+    #[serde(default)]
+    pub txhash: Hash,
     pub logs: Vec<LogSlim>,
 }
 
@@ -738,6 +754,15 @@ mod tests {
         serde_json::to_string(&de).expect("Could not serialize JSON");
     }
 
+    #[test]
+    fn can_deserialize_slim_new_block_4739729() {
+        let json = read_file("tests/fixtures/new_block_4739729.json")
+            .expect("Could not read JSON fixture");
+        let EventTypeSlim::NewBlock { data, .. } =
+            serde_json::from_str(&json).expect("Could not deserialize JSON fixture");
+        assert_eq!(Some("4739729".to_string()), data.block.header.height);
+    }
+
     #[test]
     fn can_deserialize_serialize_new_block_4739954() {
         let json = read_file("tests/fixtures/new_block_4739954.json")
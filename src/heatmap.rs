@@ -0,0 +1,273 @@
+use anyhow::{anyhow, Error, Result};
+use cached::proc_macro::cached;
+use cached::TimedCache;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::anchor::{self, LiquidationPrice};
+use crate::cache::{Loan, LoanBook};
+use crate::types::{CollateralToken, Micro};
+use crate::whitelist::MaxLtvs;
+
+/// Max number of prices along each axis of a heatmap
+pub const MAX_HEATMAP_PRICES: usize = 1_000;
+
+/// Prices from `min` to `max` (included) every `step`, in UST
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PriceRange {
+    pub min: Decimal,
+    pub max: Decimal,
+    pub step: Decimal,
+}
+
+impl PriceRange {
+    pub fn new(min: Decimal, max: Decimal, step: Decimal) -> Result<Self> {
+        if min.is_sign_negative() || max < min {
+            return Err(anyhow!("Invalid price range {} to {}", min, max));
+        }
+        if step.is_sign_negative() || step.is_zero() {
+            return Err(anyhow!("The step must be positive"));
+        }
+        let range = PriceRange { min, max, step };
+        if range.len() > MAX_HEATMAP_PRICES {
+            return Err(anyhow!(
+                "Too many prices from {} to {} every {}, at most {} are allowed",
+                min,
+                max,
+                step,
+                MAX_HEATMAP_PRICES
+            ));
+        }
+        Ok(range)
+    }
+
+    pub fn len(&self) -> usize {
+        (self.max - self.min)
+            .checked_div(self.step)
+            .and_then(|n| n.floor().to_usize())
+            .map_or(usize::MAX, |n| n.saturating_add(1))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn prices(&self) -> Vec<Decimal> {
+        (0..self.len())
+            .map(|i| self.min + self.step * Decimal::from(i))
+            .collect()
+    }
+
+    /// The number of prices of the range strictly below `price`
+    fn count_below(&self, price: &Decimal) -> Result<usize> {
+        if *price <= self.min {
+            return Ok(0);
+        }
+        let steps = (price - self.min)
+            .checked_div(self.step)
+            .ok_or_else(|| anyhow!("Price {} overflows the range", price))?;
+        Ok(steps
+            .ceil()
+            .to_usize()
+            .map_or(self.len(), |n| n.min(self.len())))
+    }
+}
+
+/// What gets liquidated at a pair of prices
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct HeatmapCell {
+    pub debt: Micro,
+    pub bluna_vol: Micro,
+    pub beth_vol: Micro,
+    pub borrowers: u64,
+}
+
+impl HeatmapCell {
+    fn of(loan: &Loan) -> Self {
+        let amount = |token| loan.collaterals.get(&token).copied().unwrap_or_default();
+        HeatmapCell {
+            debt: loan.amount,
+            bluna_vol: amount(CollateralToken::Bluna),
+            beth_vol: amount(CollateralToken::Beth),
+            borrowers: 1,
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.debt += other.debt;
+        self.bluna_vol += other.bluna_vol;
+        self.beth_vol += other.beth_vol;
+        self.borrowers += other.borrowers;
+    }
+}
+
+/// Liquidated loans over a grid of (bLUNA price, bETH price) pairs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Heatmap {
+    pub bluna_prices: Vec<Decimal>,
+    pub beth_prices: Vec<Decimal>,
+    /// One row per bETH price, with one cell per bLUNA price
+    pub cells: Vec<Vec<HeatmapCell>>,
+}
+
+/// The loans liquidated at each pair of prices of the `bluna` and `beth` ranges.
+///
+/// At a given bETH price, a loan is liquidated at every bLUNA price below its liquidation price,
+/// so each loan is only solved once per row, added to the cell of the highest of those prices,
+/// and the cells are summed from the highest price down rather than checked one by one.
+pub fn heatmap(
+    borrowers: &LoanBook,
+    bluna: &PriceRange,
+    beth: &PriceRange,
+    max_ltvs: &MaxLtvs,
+) -> Heatmap {
+    let bluna_ltv = max_ltvs.get(CollateralToken::Bluna);
+    let beth_ltv = max_ltvs.get(CollateralToken::Beth);
+    let beth_prices = beth.prices();
+    let mut cells = vec![vec![HeatmapCell::default(); bluna.len()]; beth_prices.len()];
+    for (address, loan) in borrowers {
+        let cell = HeatmapCell::of(loan);
+        for (row, beth_price) in cells.iter_mut().zip(&beth_prices) {
+            let others = [anchor::Collateral {
+                amount: cell.beth_vol.units(),
+                price: *beth_price,
+                max_ltv: beth_ltv,
+            }];
+            let liquidated = match anchor::solve_liquidation_price(
+                &loan.amount.units(),
+                &cell.bluna_vol.units(),
+                &bluna_ltv,
+                &others,
+            ) {
+                LiquidationPrice::Price(price) => match bluna.count_below(&price) {
+                    Ok(liquidated) => liquidated,
+                    Err(e) => {
+                        warn!("Error placing {} on the heatmap: {}", address, e);
+                        0
+                    }
+                },
+                LiquidationPrice::AlreadyLiquidatable => bluna.len(),
+                LiquidationPrice::NeverLiquidatable | LiquidationPrice::Overflow => 0,
+            };
+            if liquidated > 0 {
+                row[liquidated - 1].add(&cell);
+            }
+        }
+    }
+    for row in cells.iter_mut() {
+        for i in (1..row.len()).rev() {
+            let higher = row[i];
+            row[i - 1].add(&higher);
+        }
+    }
+    Heatmap {
+        bluna_prices: bluna.prices(),
+        beth_prices,
+        cells,
+    }
+}
+
+#[cached(
+    result = true,
+    type = "TimedCache<String, String>",
+    create = "{ TimedCache::with_lifespan_and_capacity(10, 1000) }",
+    convert = r#"{
+        format!(
+            "{:?}/{:?}@{:?}/{}/{}",
+            bluna,
+            beth,
+            _height,
+            max_ltvs.get(CollateralToken::Bluna),
+            max_ltvs.get(CollateralToken::Beth)
+        )
+    }"#
+)]
+/// TLRU cache of the serialized heatmap, either current or as of `_height`
+pub fn cached_heatmap(
+    borrowers: &LoanBook,
+    bluna: &PriceRange,
+    beth: &PriceRange,
+    max_ltvs: &MaxLtvs,
+    _height: Option<u64>,
+) -> Result<String> {
+    serde_json::to_string(&heatmap(borrowers, bluna, beth, max_ltvs)).map_err(Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{loan, loan_book};
+
+    #[test]
+    fn matches_liquidations_of_every_cell() {
+        let loan = |amount, bluna, beth| {
+            loan(
+                amount,
+                &[
+                    (CollateralToken::Bluna, bluna),
+                    (CollateralToken::Beth, beth),
+                ],
+            )
+        };
+        let borrowers = loan_book(vec![
+            loan(1_500_000_000, 100_000_000, 1_000_000),
+            loan(2_000_000_000, 0, 2_000_000),
+            loan(600_000_000, 100_000_000, 0),
+            loan(1_200_000_000, 0, 0),
+            loan(0, 100_000_000, 0),
+        ]);
+        let bluna =
+            PriceRange::new(Decimal::ZERO, Decimal::new(30, 0), Decimal::new(5, 0)).unwrap();
+        let beth = PriceRange::new(
+            Decimal::new(500, 0),
+            Decimal::new(2_000, 0),
+            Decimal::new(250, 0),
+        )
+        .unwrap();
+        let max_ltvs = MaxLtvs::default();
+        let heatmap = heatmap(&borrowers, &bluna, &beth, &max_ltvs);
+        assert_eq!(7, heatmap.bluna_prices.len());
+        assert_eq!(7, heatmap.beth_prices.len());
+
+        for (row, beth_price) in heatmap.cells.iter().zip(&heatmap.beth_prices) {
+            for (cell, bluna_price) in row.iter().zip(&heatmap.bluna_prices) {
+                let mut expected = HeatmapCell::default();
+                for loan in borrowers.values() {
+                    let limit = anchor::checked_borrow_limit(loan.collaterals.iter().map(
+                        |(token, amount)| {
+                            let price = match token {
+                                CollateralToken::Bluna => bluna_price,
+                                CollateralToken::Beth => beth_price,
+                            };
+                            (amount.units() * price, max_ltvs.get(*token))
+                        },
+                    ))
+                    .unwrap();
+                    if limit < loan.amount.units() {
+                        expected.add(&HeatmapCell::of(loan));
+                    }
+                }
+                assert_eq!(expected, *cell, "at {} / {}", bluna_price, beth_price);
+            }
+        }
+        // bLUNA at $20 and bETH at $1,000: only the loans backed by 2 bETH and by nothing are liquidated
+        assert_eq!(2, heatmap.cells[2][4].borrowers);
+        assert_eq!(Micro::new(3_200_000_000), heatmap.cells[2][4].debt);
+
+        assert!(PriceRange::new(Decimal::ZERO, Decimal::ONE, Decimal::ZERO).is_err());
+        assert!(
+            PriceRange::new(Decimal::ZERO, Decimal::new(10_000, 0), Decimal::new(1, 2)).is_err()
+        );
+    }
+
+    #[test]
+    fn fails_to_place_prices_overflowing_the_range() {
+        let range = PriceRange::new(Decimal::ZERO, Decimal::new(5, 1), Decimal::new(1, 3)).unwrap();
+        assert_eq!(250, range.count_below(&Decimal::new(25, 2)).unwrap());
+        assert_eq!(0, range.count_below(&Decimal::ZERO).unwrap());
+        assert_eq!(range.len(), range.count_below(&Decimal::TEN).unwrap());
+        assert!(range.count_below(&Decimal::MAX).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+use crate::mantle::client::{query_contract_store, MANTLE_HOST};
+use crate::mantle::queries::{
+    FromJson, InterestModelBorrowRate, InterestModelBorrowRateQuery, MarketEpochState,
+    MarketEpochStateQuery, MarketState, MarketStateQuery,
+};
+use crate::mantle::Contracts;
+use crate::risk::LoanRisk;
+use crate::types::Address;
+
+/// Blocks per year assumed by the Anchor market to accrue interest
+pub const BLOCKS_PER_YEAR: u64 = 4_656_810;
+pub const BLOCKS_PER_DAY: u64 = BLOCKS_PER_YEAR / 365;
+/// Default delay between two borrow rate updates
+pub const RATE_INTERVAL: Duration = Duration::from_secs(60);
+/// Max number of days to project liquidations over
+pub const MAX_PROJECTION_DAYS: u32 = 365;
+
+const SECONDS_PER_DAY: f64 = 86_400.0;
+
+pub type SharedBorrowRate = Arc<RwLock<BorrowRate>>;
+
+/// The interest rate debts accrue at, as set by the interest model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct BorrowRate {
+    pub per_block: Decimal,
+    /// Height the rate was read at, 0 until the first update
+    pub height: u64,
+}
+
+impl BorrowRate {
+    /// Reads the rate the interest model sets given the current state of the market
+    pub async fn fetch(host: &str) -> Result<Self> {
+        let (height, state) =
+            query_contract_store(host, Contracts::MARKET, &MarketStateQuery::default()).await?;
+        let state = MarketState::from_json(&state)?;
+        let (_, epoch_state) =
+            query_contract_store(host, Contracts::MARKET, &MarketEpochStateQuery::default())
+                .await?;
+        let balance = market_balance(&state, &MarketEpochState::from_json(&epoch_state)?)?;
+        let query = InterestModelBorrowRateQuery::new(
+            balance.to_string(),
+            state.total_liabilities,
+            state.total_reserves,
+        );
+        let (_, result) = query_contract_store(host, Contracts::INTEREST_MODEL, &query).await?;
+        let rate = InterestModelBorrowRate::from_json(&result)?;
+        Ok(BorrowRate {
+            per_block: parse_decimal("borrow rate", &rate.rate)?,
+            height,
+        })
+    }
+
+    pub fn daily(&self) -> Decimal {
+        self.per_block * Decimal::from(BLOCKS_PER_DAY)
+    }
+
+    pub fn yearly(&self) -> Decimal {
+        self.per_block * Decimal::from(BLOCKS_PER_YEAR)
+    }
+}
+
+fn parse_decimal(name: &str, s: &str) -> Result<Decimal> {
+    Decimal::from_str(s).map_err(|e| anyhow!("Invalid {} '{}': {}", name, s, e))
+}
+
+/// The UST held by the market, in uusd, which isn't part of its state:
+/// the aUST exchange rate is `(balance + liabilities - reserves) / supply`
+pub fn market_balance(state: &MarketState, epoch_state: &MarketEpochState) -> Result<Decimal> {
+    let supply = parse_decimal("aUST supply", &epoch_state.aterra_supply)?;
+    let exchange_rate = parse_decimal("exchange rate", &epoch_state.exchange_rate)?;
+    let liabilities = parse_decimal("total liabilities", &state.total_liabilities)?;
+    let reserves = parse_decimal("total reserves", &state.total_reserves)?;
+    let balance = (supply * exchange_rate - liabilities + reserves).floor();
+    Ok(balance.max(Decimal::ZERO))
+}
+
+/// Periodically reads the borrow rate
+pub struct BorrowRateFeed {
+    host: String,
+    rate: SharedBorrowRate,
+}
+
+impl Default for BorrowRateFeed {
+    fn default() -> Self {
+        Self::new(MANTLE_HOST)
+    }
+}
+
+impl BorrowRateFeed {
+    pub fn new<T: ToString>(host: T) -> Self {
+        BorrowRateFeed {
+            host: host.to_string(),
+            rate: Arc::new(RwLock::new(BorrowRate::default())),
+        }
+    }
+
+    pub fn rate(&self) -> SharedBorrowRate {
+        self.rate.clone()
+    }
+
+    /// Updates the rate every `interval`, in the background
+    pub fn spawn(self, interval: Duration) {
+        info!("Reading the borrow rate every {:?}", interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match BorrowRate::fetch(&self.host).await {
+                    Ok(rate) => *self.rate.write().await = rate,
+                    Err(e) => error!("Error reading the borrow rate: {}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Days until interest makes a loan of `health_factor` liquidatable, compounded at `daily_rate`.
+/// 0 once it is liquidatable, `None` if its debt doesn't grow.
+pub fn days_to_liquidation(health_factor: &Decimal, daily_rate: &Decimal) -> Option<f64> {
+    if *health_factor < Decimal::ONE {
+        return Some(0.0);
+    }
+    if daily_rate.is_sign_negative() || daily_rate.is_zero() {
+        return None;
+    }
+    // debt * (1 + daily_rate) ^ days = borrow limit
+    let days = health_factor.to_f64()?.ln() / (1.0 + daily_rate.to_f64()?).ln();
+    Some(days).filter(|days| days.is_finite())
+}
+
+/// When interest makes a loan liquidatable, prices staying the same
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoanProjection {
+    pub address: Address,
+    pub health_factor: Decimal,
+    pub days: f64,
+    pub date: DateTime<Utc>,
+}
+
+/// The loans which become liquidatable on a given day
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DailyLiquidations {
+    /// Days from now, 0 for the loans already liquidatable
+    pub day: u32,
+    /// Their debt by then, in UST: the borrow limit it reaches, or the current debt on day 0
+    pub debt: Decimal,
+    pub borrowers: u64,
+}
+
+/// Liquidations caused by interest alone over the next days
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccrualProjection {
+    pub rate: BorrowRate,
+    pub daily_rate: Decimal,
+    pub yearly_rate: Decimal,
+    /// One entry per day, from today (0) to the last day projected
+    pub schedule: Vec<DailyLiquidations>,
+    /// The loans liquidatable by the last day, soonest first
+    pub loans: Vec<LoanProjection>,
+}
+
+/// Projects the loans `ranked` by health factor (see `RiskIndex`) over `days` days from `now`
+pub fn project(
+    ranked: &[LoanRisk],
+    rate: &BorrowRate,
+    now: DateTime<Utc>,
+    days: u32,
+) -> AccrualProjection {
+    let daily_rate = rate.daily();
+    let mut schedule: Vec<DailyLiquidations> = (0..=days)
+        .map(|day| DailyLiquidations {
+            day,
+            ..DailyLiquidations::default()
+        })
+        .collect();
+    let mut loans = vec![];
+    for risk in ranked {
+        let liquidatable = risk.health_factor < Decimal::ONE;
+        let projected = match days_to_liquidation(&risk.health_factor, &daily_rate) {
+            Some(projected) => projected,
+            // the later loans are even safer
+            None => break,
+        };
+        // a loan right at its limit is liquidatable as soon as any interest accrues
+        let day = if liquidatable {
+            0
+        } else {
+            projected.ceil().max(1.0) as u32
+        };
+        if day > days {
+            break;
+        }
+        let entry = &mut schedule[day as usize];
+        entry.debt += if liquidatable {
+            risk.debt.units()
+        } else {
+            risk.borrow_limit
+        };
+        entry.borrowers += 1;
+        loans.push(LoanProjection {
+            address: risk.address.clone(),
+            health_factor: risk.health_factor,
+            days: projected,
+            date: now + chrono::Duration::seconds((projected * SECONDS_PER_DAY) as i64),
+        });
+    }
+    AccrualProjection {
+        rate: *rate,
+        daily_rate,
+        yearly_rate: rate.yearly(),
+        schedule,
+        loans,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{loan, loan_book};
+    use crate::oracle::Prices;
+    use crate::risk::RiskIndex;
+    use crate::types::CollateralToken;
+    use crate::whitelist::MaxLtvs;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn derives_market_balance() {
+        let state = MarketState::from_json(&r#"{"total_liabilities":"1500000000000000.123456789012345678","total_reserves":"1000000000.5","last_interest_updated":5000000,"global_interest_index":"1.1"}"#).unwrap();
+        let epoch_state = MarketEpochState::from_json(
+            &r#"{"exchange_rate":"1.2","aterra_supply":"2000000000000000"}"#,
+        )
+        .unwrap();
+        // 2.4e15 - 1.5e15 + 1e9
+        assert_eq!(
+            Decimal::new(900_001_000_000_000, 0),
+            market_balance(&state, &epoch_state).unwrap()
+        );
+    }
+
+    #[test]
+    fn projects_liquidations_from_interest() {
+        let loan = |amount| loan(amount, &[(CollateralToken::Bluna, 100_000_000)]);
+        // limit 100 * 40 * 0.6 = 2,400
+        let borrowers = loan_book(vec![
+            loan(2_500_000_000),
+            loan(2_400_000_000),
+            loan(2_390_000_000),
+            loan(1_200_000_000),
+        ]);
+        let mut prices = Prices {
+            height: 42,
+            prices: BTreeMap::new(),
+        };
+        prices
+            .prices
+            .insert(CollateralToken::Bluna, Decimal::new(40, 0));
+        let index = RiskIndex::build(42, &borrowers, &prices, &MaxLtvs::default());
+
+        // 0.1% a day
+        let rate = BorrowRate {
+            per_block: Decimal::new(1, 3) / Decimal::from(BLOCKS_PER_DAY),
+            height: 42,
+        };
+        let projection = project(index.most_at_risk(usize::MAX), &rate, Utc::now(), 30);
+        assert_eq!(31, projection.schedule.len());
+        assert_eq!(Decimal::new(2_500, 0), projection.schedule[0].debt);
+        assert_eq!(1, projection.schedule[1].borrowers);
+        // ln(2400 / 2390) / ln(1.001) = 4.18 days
+        assert_eq!(1, projection.schedule[5].borrowers);
+        assert_eq!(Decimal::new(2_400, 0), projection.schedule[5].debt);
+        // doubling the debt takes 694 days
+        assert_eq!(3, projection.loans.len());
+        assert_eq!(
+            3,
+            projection
+                .schedule
+                .iter()
+                .map(|day| day.borrowers)
+                .sum::<u64>()
+        );
+
+        assert_eq!(
+            None,
+            days_to_liquidation(&Decimal::new(2, 0), &Decimal::ZERO)
+        );
+        let days = days_to_liquidation(&Decimal::new(2, 0), &Decimal::new(1, 3)).unwrap();
+        assert_eq!(694, days.ceil() as u32);
+    }
+}
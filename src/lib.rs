@@ -1,5 +1,19 @@
 pub mod anchor;
 pub mod cache;
+pub mod cascade;
+pub mod depth;
 pub mod event;
+pub mod heatmap;
+pub mod interest;
 pub mod mantle;
 pub mod observer;
+pub mod oracle;
+pub mod persistence;
+pub mod queue;
+pub mod reconcile;
+pub mod risk;
+pub mod scenario;
+pub mod snapshot;
+pub mod storage;
+pub mod types;
+pub mod whitelist;
@@ -1,52 +1,649 @@
 #[macro_use]
 extern crate rocket;
 
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
 use liquidation_monitor::{
     cache,
-    cache::{AnchorCache, Borrowers},
+    cache::{
+        AnchorCache, Borrowers, BucketScale, Bucketing, Loan, LoanHistory, LoanMetrics,
+        DEFAULT_BETH_PRICE,
+    },
+    cascade::{simulate_cascade, Cascade, Pool, MAX_CASCADE_ROUNDS},
+    depth::cached_depth_curve,
     event::handler,
+    heatmap::{cached_heatmap, PriceRange},
+    interest,
+    interest::{
+        AccrualProjection, BorrowRateFeed, SharedBorrowRate, MAX_PROJECTION_DAYS, RATE_INTERVAL,
+    },
+    mantle::{client::MANTLE_HOST, crawler::SeedCrawler},
     observer::client::ObserverClient,
+    oracle::{PriceFeed, Prices, SharedPrices, PRICE_INTERVAL},
+    persistence::{Persistence, SNAPSHOT_INTERVAL},
+    queue::{liquidated_along, LiquidationQueue, SharedQueue, Simulation, QUEUE_INTERVAL},
+    reconcile::{DriftStats, Reconciler, SharedDriftStats, RECONCILE_INTERVAL},
+    risk::{LoanRisk, RiskIndex, RiskMonitor, SharedRiskIndex, RISK_INTERVAL},
+    scenario,
+    scenario::{Scenario, ScenarioResult},
+    snapshot::SnapshotFormat,
+    storage::{LoanStore, MemoryStore, RedisStore, SqliteStore},
+    types::{Address, CollateralToken, Micro},
+    whitelist::{MaxLtvs, SharedMaxLtvs},
 };
+use rocket::response::Debug;
+use rocket::serde::json::Json;
 use rocket::State;
 use rust_decimal::Decimal;
-use tokio::sync::mpsc;
-use tracing::{error, info};
+use serde::Serialize;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{error, info, warn};
 use tungstenite::error::{Error::Protocol, ProtocolError::ResetWithoutClosingHandshake};
 
-#[get("/borrowers")]
-async fn borrowers(borrowers: &State<Borrowers>) -> String {
-    cache::cached_borrowers(borrowers.read().await.clone()).unwrap()
+const PERSISTENCE_DIR: &str = "data";
+const SEED_FILE: &str = "borrowers_seed.json";
+/// Default heatmap ranges (min, max, step), in uusd
+const DEFAULT_BLUNA_RANGE: (i64, i64, i64) = (1_000_000, 100_000_000, 1_000_000);
+const DEFAULT_BETH_RANGE: (i64, i64, i64) = (500_000_000, 5_000_000_000, 100_000_000);
+/// Default number of loans returned by `/api/risk`
+const DEFAULT_RISK_LIMIT: usize = 20;
+/// Default number of days projected by `/api/projection`
+const DEFAULT_PROJECTION_DAYS: u32 = 30;
+
+type Store = Arc<dyn LoanStore>;
+
+/// A 400 for query parameters out of range, a 500 for any other error
+#[derive(Responder)]
+enum ApiError {
+    #[response(status = 400)]
+    BadRequest(String),
+    Internal(Debug<anyhow::Error>),
+}
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        ApiError::Internal(Debug(e.into()))
+    }
+}
+
+/// `height` if it fits in the `i64` heights of the stores
+fn height_param(height: Option<u64>) -> Result<Option<u64>, ApiError> {
+    match height {
+        Some(height) if i64::try_from(height).is_err() => Err(ApiError::BadRequest(format!(
+            "Height {} out of range",
+            height
+        ))),
+        _ => Ok(height),
+    }
+}
+
+/// The amount of `micro` micro-units in whole units, if it fits in an `i64`
+fn micro_param<T>(name: &str, micro: T) -> Result<Decimal, ApiError>
+where
+    T: Copy + fmt::Display,
+    i64: TryFrom<T>,
+{
+    i64::try_from(micro)
+        .map(|micro| Micro::new(micro).units())
+        .map_err(|_| ApiError::BadRequest(format!("{} {} out of range", name, micro)))
+}
+
+/// The whole loan book, either current or as of `height` (`None` if it isn't retained)
+async fn loan_book(store: &Store, height: Option<u64>) -> Result<Option<Borrowers>, ApiError> {
+    match height_param(height)? {
+        Some(height) => Ok(store.all_at(height).await?),
+        None => Ok(store.all().await.map(Some)?),
+    }
+}
+
+#[get("/borrowers?<height>")]
+async fn borrowers(store: &State<Store>, height: Option<u64>) -> Result<Option<String>, ApiError> {
+    match loan_book(store, height).await? {
+        Some(borrowers) => Ok(Some(cache::cached_borrowers(&borrowers, height)?)),
+        None => Ok(None),
+    }
+}
+
+#[get("/borrowers/<address>?<height>")]
+async fn borrower(
+    store: &State<Store>,
+    address: Address,
+    height: Option<u64>,
+) -> Result<Option<Json<Loan>>, ApiError> {
+    let loan = match height_param(height)? {
+        Some(height) => store.loan_at(&address, height).await?,
+        None => store.get(&address).await?,
+    };
+    Ok(loan.map(Json))
+}
+
+#[get("/borrowers/<address>/history")]
+async fn borrower_history(
+    store: &State<Store>,
+    address: Address,
+) -> Result<Option<Json<LoanHistory>>, Debug<anyhow::Error>> {
+    Ok(store.history(&address).await?.map(Json))
+}
+
+#[get("/borrowers/<address>/metrics")]
+async fn borrower_metrics(
+    store: &State<Store>,
+    prices: &State<SharedPrices>,
+    max_ltvs: &State<SharedMaxLtvs>,
+    address: Address,
+) -> Result<Option<Json<LoanMetrics>>, Debug<anyhow::Error>> {
+    let loan = match store.get(&address).await? {
+        Some(loan) => loan,
+        None => return Ok(None),
+    };
+    let max_ltvs = max_ltvs.read().await;
+    match loan.metrics(&*prices.read().await, &max_ltvs)? {
+        Some(metrics) => Ok(Some(Json(metrics))),
+        None => Err(anyhow::anyhow!("Collateral prices aren't available yet").into()),
+    }
+}
+
+/// Liquidation levels along the price of `collateral` (bLUNA by default), in buckets of `step`,
+/// `log` percent wider each, or `percent` of the current oracle price (`step` by default).
+/// Prices and steps are in micro-units, and `empty` includes the buckets without any loan.
+/// The other collateral is valued at its given price,
+/// else at `DEFAULT_BETH_PRICE` for bETH and at the latest oracle price for bLUNA.
+#[get("/liqs?<collateral>&<beth_price>&<bluna_price>&<step>&<log>&<percent>&<min>&<max>&<empty>&<height>")]
+#[allow(clippy::too_many_arguments)]
+async fn liqs(
+    store: &State<Store>,
+    prices: &State<SharedPrices>,
+    max_ltvs: &State<SharedMaxLtvs>,
+    collateral: Option<CollateralToken>,
+    beth_price: Option<usize>,
+    bluna_price: Option<usize>,
+    step: Option<usize>,
+    log: Option<&str>,
+    percent: Option<&str>,
+    min: Option<usize>,
+    max: Option<usize>,
+    empty: Option<bool>,
+    height: Option<u64>,
+) -> Result<Option<String>, ApiError> {
+    let collateral = collateral.unwrap_or(CollateralToken::Bluna);
+    let mut bucketing = match (step, log, percent) {
+        (Some(step), None, None) => Bucketing::linear(micro_param("Step", step)?),
+        (None, None, None) => Bucketing::linear(cache::default_liquidation_step(collateral)),
+        (None, Some(log), None) => Bucketing {
+            scale: BucketScale::Log(Decimal::from_str(log).map_err(anyhow::Error::from)?),
+            ..Bucketing::linear(Decimal::ONE)
+        },
+        (None, None, Some(percent)) => {
+            let price =
+                prices.read().await.get(collateral).ok_or_else(|| {
+                    anyhow::anyhow!("The {} price isn't available yet", collateral)
+                })?;
+            Bucketing::percent_of(
+                &price,
+                &Decimal::from_str(percent).map_err(anyhow::Error::from)?,
+            )?
+        }
+        _ => return Err(anyhow::anyhow!("Only one of step, log and percent can be given").into()),
+    };
+    bucketing.min = min.map(|min| micro_param("Min", min)).transpose()?;
+    bucketing.max = max.map(|max| micro_param("Max", max)).transpose()?;
+    bucketing.include_empty = empty.unwrap_or(false);
+    let other_price = match collateral {
+        // TODO: Fetch current bETH price if none is provided?
+        CollateralToken::Bluna => Some(match beth_price {
+            Some(price) => micro_param("Price", price)?,
+            None => Micro::new(DEFAULT_BETH_PRICE).units(),
+        }),
+        CollateralToken::Beth => bluna_price
+            .map(|price| micro_param("Price", price))
+            .transpose()?,
+    };
+    let mut fixed = Prices::default();
+    for token in CollateralToken::ALL.iter().copied() {
+        if token == collateral {
+            continue;
+        }
+        let price = match other_price {
+            Some(price) => price,
+            None => prices.read().await.get(token).ok_or_else(|| {
+                anyhow::anyhow!("The {} price isn't available yet, please provide it", token)
+            })?,
+        };
+        fixed.prices.insert(token, price);
+    }
+    match loan_book(store, height).await? {
+        Some(borrowers) => Ok(Some(cache::cached_liquidations(
+            &borrowers,
+            collateral,
+            &fixed,
+            &bucketing,
+            &*max_ltvs.read().await,
+            height,
+        )?)),
+        None => Ok(None),
+    }
+}
+
+/// Cumulative liquidations as the oracle price of `collateral` (bLUNA by default) drops,
+/// in buckets of `step` or `log` percent wider each, down to `min`. Prices are in micro-units.
+#[get("/depth?<collateral>&<step>&<log>&<min>&<empty>&<height>")]
+#[allow(clippy::too_many_arguments)]
+async fn depth(
+    store: &State<Store>,
+    prices: &State<SharedPrices>,
+    max_ltvs: &State<SharedMaxLtvs>,
+    collateral: Option<CollateralToken>,
+    step: Option<usize>,
+    log: Option<&str>,
+    min: Option<usize>,
+    empty: Option<bool>,
+    height: Option<u64>,
+) -> Result<Option<String>, ApiError> {
+    let collateral = collateral.unwrap_or(CollateralToken::Bluna);
+    let mut bucketing = match (step, log) {
+        (Some(step), None) => Bucketing::linear(micro_param("Step", step)?),
+        (None, None) => Bucketing::linear(cache::default_liquidation_step(collateral)),
+        (None, Some(log)) => Bucketing {
+            scale: BucketScale::Log(Decimal::from_str(log).map_err(anyhow::Error::from)?),
+            ..Bucketing::linear(Decimal::ONE)
+        },
+        _ => return Err(anyhow::anyhow!("Only one of step and log can be given").into()),
+    };
+    bucketing.min = min.map(|min| micro_param("Min", min)).transpose()?;
+    bucketing.include_empty = empty.unwrap_or(false);
+    let prices = prices.read().await.clone();
+    match loan_book(store, height).await? {
+        Some(borrowers) => Ok(Some(cached_depth_curve(
+            &borrowers,
+            collateral,
+            &prices,
+            &bucketing,
+            &*max_ltvs.read().await,
+            height,
+        )?)),
+        None => Ok(None),
+    }
+}
+
+/// Liquidations over a grid of bLUNA and bETH prices, in micro-units.
+/// Ranges default to `DEFAULT_BLUNA_RANGE` and `DEFAULT_BETH_RANGE`.
+#[get("/heatmap?<bluna_min>&<bluna_max>&<bluna_step>&<beth_min>&<beth_max>&<beth_step>&<height>")]
+#[allow(clippy::too_many_arguments)]
+async fn heatmap(
+    store: &State<Store>,
+    max_ltvs: &State<SharedMaxLtvs>,
+    bluna_min: Option<usize>,
+    bluna_max: Option<usize>,
+    bluna_step: Option<usize>,
+    beth_min: Option<usize>,
+    beth_max: Option<usize>,
+    beth_step: Option<usize>,
+    height: Option<u64>,
+) -> Result<Option<String>, ApiError> {
+    let range = |min: Option<usize>, max, step, default: (i64, i64, i64)| {
+        let units = |name, price: Option<usize>, default| match price {
+            Some(price) => micro_param(name, price),
+            None => Ok(Micro::new(default).units()),
+        };
+        Ok::<_, ApiError>(PriceRange::new(
+            units("Min", min, default.0)?,
+            units("Max", max, default.1)?,
+            units("Step", step, default.2)?,
+        )?)
+    };
+    let bluna = range(bluna_min, bluna_max, bluna_step, DEFAULT_BLUNA_RANGE)?;
+    let beth = range(beth_min, beth_max, beth_step, DEFAULT_BETH_RANGE)?;
+    match loan_book(store, height).await? {
+        Some(borrowers) => Ok(Some(cached_heatmap(
+            &borrowers,
+            &bluna,
+            &beth,
+            &*max_ltvs.read().await,
+            height,
+        )?)),
+        None => Ok(None),
+    }
+}
+
+/// Loans ranked from the most to the least at risk, at the latest oracle prices
+#[derive(Serialize)]
+struct RiskReport<'a> {
+    height: u64,
+    price_height: u64,
+    loans: &'a [LoanRisk],
+}
+
+impl<'a> RiskReport<'a> {
+    fn new(index: &RiskIndex, loans: &'a [LoanRisk]) -> Self {
+        RiskReport {
+            height: index.height,
+            price_height: index.price_height,
+            loans,
+        }
+    }
+}
+
+/// The `limit` loans with the lowest health factor
+#[get("/risk?<limit>")]
+async fn risk(
+    index: &State<SharedRiskIndex>,
+    limit: Option<usize>,
+) -> Result<String, Debug<anyhow::Error>> {
+    let index = index.read().await;
+    if !index.is_ready() {
+        return Err(anyhow::anyhow!("Collateral prices aren't available yet").into());
+    }
+    let loans = index.most_at_risk(limit.unwrap_or(DEFAULT_RISK_LIMIT));
+    Ok(serde_json::to_string(&RiskReport::new(&index, loans)).map_err(anyhow::Error::from)?)
 }
 
-#[get("/liqs?<beth_price>")]
-async fn liqs(borrowers: &State<Borrowers>, beth_price: Option<usize>) -> String {
-    let beth_price = Decimal::new(beth_price.unwrap_or(2_800_000_000) as i64, 6); // TODO: Fetch current bETH price if none is provided?
-    cache::cached_liquidations(borrowers.read().await.clone(), &beth_price).unwrap()
+/// The loans which a drop of every collateral price by `percent` makes liquidatable
+#[get("/risk/within?<percent>")]
+async fn risk_within(
+    index: &State<SharedRiskIndex>,
+    percent: &str,
+) -> Result<String, Debug<anyhow::Error>> {
+    let percent = Decimal::from_str(percent)
+        .map_err(|e| anyhow::anyhow!("Invalid percentage '{}': {}", percent, e))?;
+    let index = index.read().await;
+    if !index.is_ready() {
+        return Err(anyhow::anyhow!("Collateral prices aren't available yet").into());
+    }
+    let loans = index.within(&percent);
+    Ok(serde_json::to_string(&RiskReport::new(&index, loans)).map_err(anyhow::Error::from)?)
+}
+
+/// The loans which interest alone makes liquidatable over the next `days` days, at the latest oracle prices
+#[get("/projection?<days>")]
+async fn projection(
+    index: &State<SharedRiskIndex>,
+    rate: &State<SharedBorrowRate>,
+    days: Option<u32>,
+) -> Result<Json<AccrualProjection>, Debug<anyhow::Error>> {
+    let days = days.unwrap_or(DEFAULT_PROJECTION_DAYS);
+    if days > MAX_PROJECTION_DAYS {
+        return Err(
+            anyhow::anyhow!("At most {} days can be projected", MAX_PROJECTION_DAYS).into(),
+        );
+    }
+    let rate = *rate.read().await;
+    if rate.height == 0 {
+        return Err(anyhow::anyhow!("The borrow rate isn't available yet").into());
+    }
+    let index = index.read().await;
+    if !index.is_ready() {
+        return Err(anyhow::anyhow!("Collateral prices aren't available yet").into());
+    }
+    Ok(Json(interest::project(
+        index.most_at_risk(usize::MAX),
+        &rate,
+        Utc::now(),
+        days,
+    )))
+}
+
+/// The bid pools of the liquidation queue
+#[get("/queue")]
+async fn queue(queue: &State<SharedQueue>) -> Json<LiquidationQueue> {
+    Json(queue.read().await.clone())
+}
+
+/// How the liquidation queue would absorb the loans of `collateral` (bLUNA by default) liquidated
+/// as its price goes through `path`, comma separated prices in micro-units.
+/// The other collaterals are valued at the latest oracle prices.
+#[get("/queue/simulate?<collateral>&<path>")]
+async fn simulate_queue(
+    store: &State<Store>,
+    queue: &State<SharedQueue>,
+    prices: &State<SharedPrices>,
+    max_ltvs: &State<SharedMaxLtvs>,
+    collateral: Option<CollateralToken>,
+    path: &str,
+) -> Result<Json<Simulation>, Debug<anyhow::Error>> {
+    let collateral = collateral.unwrap_or(CollateralToken::Bluna);
+    let path = path
+        .split(',')
+        .map(|price| {
+            Micro::parse_micro(price.trim())
+                .map(Micro::units)
+                .map_err(|e| anyhow::anyhow!("Invalid price '{}' in path: {}", price, e))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let borrowers = store.all().await?;
+    let liquidated = liquidated_along(
+        &borrowers,
+        collateral,
+        &*prices.read().await,
+        &*max_ltvs.read().await,
+        &path,
+    );
+    Ok(Json(queue.read().await.simulate(collateral, &liquidated)))
+}
+
+/// Liquidations of `collateral` (bLUNA by default) cascading through a constant-product pool
+/// of the given reserves (in micro-units) after its price drops by `shock` percent.
+/// The other collaterals are valued at the latest oracle prices.
+#[get("/cascade?<collateral>&<shock>&<collateral_reserve>&<ust_reserve>&<max_rounds>")]
+#[allow(clippy::too_many_arguments)]
+async fn cascade(
+    store: &State<Store>,
+    prices: &State<SharedPrices>,
+    max_ltvs: &State<SharedMaxLtvs>,
+    collateral: Option<CollateralToken>,
+    shock: &str,
+    collateral_reserve: u64,
+    ust_reserve: u64,
+    max_rounds: Option<usize>,
+) -> Result<Json<Cascade>, ApiError> {
+    let collateral = collateral.unwrap_or(CollateralToken::Bluna);
+    let shock = Decimal::from_str(shock)
+        .map_err(|e| anyhow::anyhow!("Invalid shock '{}': {}", shock, e))?
+        / Decimal::ONE_HUNDRED;
+    let pool = Pool::new(
+        micro_param("Collateral reserve", collateral_reserve)?,
+        micro_param("UST reserve", ust_reserve)?,
+    )?;
+    let prices = prices.read().await.clone();
+    if CollateralToken::ALL
+        .iter()
+        .any(|token| *token != collateral && prices.get(*token).is_none())
+    {
+        return Err(anyhow::anyhow!("Collateral prices aren't available yet").into());
+    }
+    let borrowers = store.all().await?;
+    Ok(Json(simulate_cascade(
+        &borrowers,
+        collateral,
+        &prices,
+        &*max_ltvs.read().await,
+        pool,
+        &shock,
+        max_rounds.unwrap_or(MAX_CASCADE_ROUNDS),
+    )?))
+}
+
+/// What the shocks of `scenario` liquidate, starting from the latest oracle prices,
+/// over the loan book either current or as of `height`
+#[post("/scenario?<height>", format = "json", data = "<scenario>")]
+async fn run_scenario(
+    store: &State<Store>,
+    prices: &State<SharedPrices>,
+    max_ltvs: &State<SharedMaxLtvs>,
+    scenario: Json<Scenario>,
+    height: Option<u64>,
+) -> Result<Option<Json<ScenarioResult>>, ApiError> {
+    match loan_book(store, height).await? {
+        Some(borrowers) => Ok(Some(Json(scenario::run(
+            &borrowers,
+            &*prices.read().await,
+            &*max_ltvs.read().await,
+            &scenario,
+        )?))),
+        None => Ok(None),
+    }
+}
+
+#[get("/drift")]
+async fn drift(stats: &State<SharedDriftStats>) -> Json<DriftStats> {
+    Json(stats.read().await.clone())
+}
+
+/// `MANTLE_URL` if set, the public Mantle otherwise
+fn mantle_host() -> String {
+    env::var("MANTLE_URL").unwrap_or_else(|_| MANTLE_HOST.to_string())
+}
+
+fn crawler() -> SeedCrawler {
+    SeedCrawler::new(mantle_host())
 }
 
 #[rocket::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
+    // `seed [path]` regenerates the seed from the chain, instead of running the monitor
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("seed") {
+        let path = args.get(1).map_or(SEED_FILE, String::as_str);
+        let seed = crawler()
+            .crawl()
+            .await
+            .expect("Error crawling borrowers data");
+        seed.write(path, SnapshotFormat::from_path(path))
+            .expect("Error writing seed");
+        info!(
+            "Wrote {} borrowers at height {} to {}",
+            seed.borrowers.len(),
+            seed.height,
+            path
+        );
+        return;
+    }
+
     info!("Starting the liquidation-monitor...");
-    let mut client = ObserverClient::default();
     let (tx, rx) = mpsc::channel(1000);
-    let cache = AnchorCache::new();
-    cache
-        .seed_borrowers("borrowers_seed.json")
-        .await
-        .expect("Error seeding borrowers data");
-    cache.init_listener(rx);
+    let store: Store = match (env::var("REDIS_URL"), env::var("SQLITE_PATH")) {
+        (Ok(url), _) => {
+            info!("Using Redis storage at {}", url);
+            Arc::new(
+                RedisStore::connect(url)
+                    .await
+                    .expect("Error connecting to Redis"),
+            )
+        }
+        (_, Ok(path)) => {
+            info!("Using SQLite storage at {}", path);
+            Arc::new(SqliteStore::open(path).expect("Error opening SQLite database"))
+        }
+        _ => Arc::new(MemoryStore::new()),
+    };
+    let cache = AnchorCache::new(store);
+    let mut persistence =
+        Persistence::open(PERSISTENCE_DIR, SNAPSHOT_INTERVAL).expect("Error opening persistence");
+    match cache.store.height().await.expect("Error reading height") {
+        0 => match cache
+            .restore(&persistence)
+            .await
+            .expect("Error restoring persisted borrowers data")
+        {
+            Some(height) => info!("Restored borrowers data at height {}", height),
+            None if Path::new(SEED_FILE).exists() => {
+                let height = cache
+                    .seed_borrowers(SEED_FILE)
+                    .await
+                    .expect("Error seeding borrowers data");
+                info!("Seeded borrowers data at height {}", height)
+            }
+            None => {
+                info!("No seed found, bootstrapping borrowers data from the chain...");
+                let height = cache
+                    .bootstrap(&crawler())
+                    .await
+                    .expect("Error bootstrapping borrowers data");
+                // a restart shouldn't crawl again
+                let borrowers = cache.store.all().await.expect("Error reading borrowers");
+                persistence
+                    .snapshot(height, &borrowers)
+                    .expect("Error persisting bootstrapped borrowers data");
+                info!("Bootstrapped borrowers data at height {}", height)
+            }
+        },
+        height => info!("Resuming shared borrowers data at height {}", height),
+    }
+    match MaxLtvs::fetch(&mantle_host()).await {
+        Ok(max_ltvs) => *cache.max_ltvs.write().await = max_ltvs,
+        Err(e) => warn!(
+            "Error fetching the collateral whitelist, assuming the default max LTVs: {}",
+            e
+        ),
+    }
+    let persistence = Arc::new(Mutex::new(persistence));
+    cache.init_listener(rx, Some(persistence.clone()));
+    LiquidationQueue::spawn_sync(cache.queue.clone(), mantle_host(), QUEUE_INTERVAL);
+
+    let reconciler = Reconciler::new(cache.store.clone()).with_persistence(persistence);
+    let drift_stats = reconciler.stats();
+    let reconcile_interval = env::var("RECONCILE_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map_or(RECONCILE_INTERVAL, Duration::from_secs);
+    reconciler.spawn(reconcile_interval);
+
+    let price_feed = PriceFeed::new(mantle_host());
+    let prices = price_feed.prices();
+    price_feed.spawn(PRICE_INTERVAL);
+
+    let risk_monitor =
+        RiskMonitor::new(cache.store.clone(), prices.clone(), cache.max_ltvs.clone());
+    let risk_index = risk_monitor.index();
+    risk_monitor.spawn(RISK_INTERVAL);
+
+    let rate_feed = BorrowRateFeed::new(mantle_host());
+    let borrow_rate = rate_feed.rate();
+    rate_feed.spawn(RATE_INTERVAL);
 
     info!("Launching API server...");
     tokio::spawn(
         rocket::build()
-            .mount("/api", routes![borrowers, liqs])
-            .manage(cache.borrowers)
+            .mount(
+                "/api",
+                routes![
+                    borrowers,
+                    borrower,
+                    borrower_history,
+                    borrower_metrics,
+                    liqs,
+                    depth,
+                    heatmap,
+                    risk,
+                    risk_within,
+                    projection,
+                    queue,
+                    simulate_queue,
+                    cascade,
+                    run_scenario,
+                    drift
+                ],
+            )
+            .manage(cache.store)
+            .manage(drift_stats)
+            .manage(prices)
+            .manage(cache.max_ltvs)
+            .manage(cache.queue)
+            .manage(risk_index)
+            .manage(borrow_rate)
             .launch(),
     );
 
     info!("Listening to WebSocket...");
+    let mut client = ObserverClient::default();
     loop {
         let res = client.socket.read_message();
         let tx = tx.clone();
@@ -3,31 +3,39 @@ use std::fmt::Display;
 
 use anyhow::{anyhow, Error, Result};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use cynic::http::SurfExt;
 use rust_decimal::prelude::*;
+use serde::Serialize;
 use surf::RequestBuilder;
 use tracing::{debug, error};
 
-use crate::anchor;
-use crate::cache::Loan;
-use crate::mantle::{
-    queries::{BorrowLiquidationPriceQuery, FromJson, MarketBorrowerInfo, OverseerCollaterals},
-    Contracts,
+use crate::anchor::{self, LiquidationPrice};
+use crate::cache::{BlockEvents, Loan, Rewards};
+use crate::event::handler::parse_block;
+use crate::mantle::queries::{
+    BlockTxsQuery, BlocksTxs, BorrowLiquidationPriceQuery, ContractStoreQuery, FromJson,
+    GetWasmContractsContractAddressStorePayload, MarketBorrowerInfo, OverseerCollaterals,
+    OverseerWhitelist, ToJson,
 };
+use crate::types::{Address, CollateralToken, Micro};
+use crate::whitelist::MaxLtvs;
 
 pub type MantleClient = RequestBuilder;
 
-const MANTLE_HOST: &str = "https://mantle.anchorprotocol.com";
+pub const MANTLE_HOST: &str = "https://mantle.anchorprotocol.com";
 
 #[async_trait]
 pub trait MantleExt {
     fn default() -> MantleClient;
-    async fn query_loan<T: AsRef<str> + ToString + Display + Send + Sync>(
-        borrower: T,
-    ) -> Result<Loan>;
-    async fn query_liquidation_price<T: AsRef<str> + ToString + Display + Send + Sync>(
-        borrower: T,
-    ) -> Result<Option<Decimal>>;
+    /// The on-chain state of the loan of `borrower`, along with the height it was read at
+    async fn query_loan<T>(borrower: T) -> Result<(u64, Loan)>
+    where
+        T: AsRef<str> + ToString + Display + Send + Sync;
+    async fn query_liquidation_price<T>(borrower: T) -> Result<Option<LiquidationPrice>>
+    where
+        T: AsRef<str> + ToString + Display + Send + Sync;
+    async fn query_block_events(height: u64) -> Result<BlockEvents>;
 }
 
 #[async_trait]
@@ -36,12 +44,11 @@ impl MantleExt for MantleClient {
         surf::post(MANTLE_HOST)
     }
 
-    async fn query_loan<T: AsRef<str> + ToString + Display + Send + Sync>(
-        borrower: T,
-    ) -> Result<Loan> {
-        if !borrower.as_ref().starts_with("terra") {
-            return Err(anyhow!("'borrower' must be a valid Terra address"));
-        }
+    async fn query_loan<T>(borrower: T) -> Result<(u64, Loan)>
+    where
+        T: AsRef<str> + ToString + Display + Send + Sync,
+    {
+        let borrower: Address = borrower.as_ref().parse()?;
 
         let data = surf::post(MANTLE_HOST)
             .run_graphql(BorrowLiquidationPriceQuery::build_query(&borrower))
@@ -50,7 +57,7 @@ impl MantleExt for MantleClient {
             .map(|res| res.data);
 
         match data {
-            Ok(Some(q)) => parse_loan(q),
+            Ok(Some(q)) => Ok((parse_loan_height(&q)?, parse_loan(q)?)),
             Ok(None) => Err(anyhow!(
                 "Couldn't fetch loan info of borrower: {}",
                 &borrower
@@ -59,12 +66,11 @@ impl MantleExt for MantleClient {
         }
     }
 
-    async fn query_liquidation_price<T: AsRef<str> + ToString + Display + Send + Sync>(
-        borrower: T,
-    ) -> Result<Option<Decimal>> {
-        if !borrower.as_ref().starts_with("terra") {
-            return Err(anyhow!("'borrower' must be a valid Terra address"));
-        }
+    async fn query_liquidation_price<T>(borrower: T) -> Result<Option<LiquidationPrice>>
+    where
+        T: AsRef<str> + ToString + Display + Send + Sync,
+    {
+        let borrower: Address = borrower.as_ref().parse()?;
 
         // let data = self.run_graphql
         let data = surf::post(MANTLE_HOST)
@@ -80,31 +86,153 @@ impl MantleExt for MantleClient {
             Err(e) => Err(e),
         }
     }
+
+    async fn query_block_events(height: u64) -> Result<BlockEvents> {
+        let data = surf::post(MANTLE_HOST)
+            .run_graphql(BlockTxsQuery::build_query(height as i32))
+            .await
+            .map_err(|e| e.into_inner())
+            .map(|res| res.data);
+
+        match data {
+            Ok(Some(q)) => Ok(parse_block_events(height, q)),
+            Ok(None) => Err(anyhow!("Couldn't fetch block: {}", height)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Runs a smart query of `contract_address` on the Mantle at `host`,
+/// returning its result along with the height it was answered at
+pub async fn query_contract_store<Q>(
+    host: &str,
+    contract_address: &str,
+    query_msg: &Q,
+) -> Result<(u64, String)>
+where
+    Q: ToJson + Serialize,
+{
+    let payload = surf::post(host)
+        .run_graphql(ContractStoreQuery::build_query(contract_address, query_msg))
+        .await
+        .map_err(|e| e.into_inner())?
+        .data
+        .and_then(|q| q.contract_store)
+        .ok_or_else(|| anyhow!("Couldn't query contract {}", contract_address))?;
+
+    let height = payload
+        .height
+        .ok_or_else(|| anyhow!("Missing height of contract {} query", contract_address))?
+        .parse()?;
+    let result = payload
+        .result
+        .ok_or_else(|| anyhow!("Missing result of contract {} query", contract_address))?;
+    Ok((height, result))
+}
+
+/// The ANC rewards of a borrower as reported by the market at `height`,
+/// which counts them in fractional micro-units
+pub fn parse_rewards(info: &MarketBorrowerInfo, height: u64) -> Result<Rewards> {
+    let pending = Decimal::from_str(&info.pending_rewards)
+        .map_err(|e| anyhow!("Invalid pending rewards '{}': {}", info.pending_rewards, e))?;
+    let reward_index = Decimal::from_str(&info.reward_index)
+        .map_err(|e| anyhow!("Invalid reward index '{}': {}", info.reward_index, e))?;
+    Ok(Rewards {
+        pending: Micro::from_units(pending / Decimal::new(1_000_000, 0)),
+        reward_index,
+        height,
+    })
+}
+
+/// Extracts the `CacheEvent`s and whitelist updates of a block, the same way `handler::handle_new_block` does
+pub fn parse_block_events(height: u64, q: BlockTxsQuery) -> BlockEvents {
+    let txs: Vec<BlocksTxs> = q
+        .blocks
+        .into_iter()
+        .flatten()
+        .flatten()
+        .flat_map(|block| block.txs.into_iter().flatten().flatten())
+        .collect();
+    let timestamp = txs
+        .iter()
+        .find_map(|tx| tx.timestamp.as_ref())
+        .and_then(|t| t.parse::<DateTime<Utc>>().ok());
+
+    let from_contract = txs
+        .into_iter()
+        .flat_map(|tx| {
+            let txhash = tx.tx_hash.unwrap_or_default();
+            tx.logs
+                .into_iter()
+                .flatten()
+                .flatten()
+                .flat_map(|log| log.events.into_iter().flatten().flatten())
+                .filter(|event| event.event_type.as_deref() == Some("from_contract"))
+                .map(move |event| {
+                    let attrs = event
+                        .attributes
+                        .into_iter()
+                        .flatten()
+                        .flatten()
+                        .filter_map(|attr| attr.key.zip(attr.value))
+                        .collect();
+                    (txhash.clone(), attrs)
+                })
+        })
+        .collect();
+
+    parse_block(height, timestamp, from_contract)
 }
 
-fn parse_loan_amount(q: &BorrowLiquidationPriceQuery) -> Result<Option<Decimal>, Error> {
+fn parse_loan_amount(q: &BorrowLiquidationPriceQuery) -> Result<Option<Micro>, Error> {
     q.market_borrower_info
         .as_ref()
         .and_then(|p| p.result.as_ref())
         .map(|res| MarketBorrowerInfo::from_json(&res))
+        .transpose()?
+        .map(|info| Micro::parse_micro(info.loan_amount.as_ref()))
         .transpose()
-        .expect("JSON parsing error")
-        .map(|info| {
-            i64::from_str(info.loan_amount.as_ref())
-                .map_err(|e| anyhow::Error::from(e))
-                .map(|i| Decimal::new(i, 6))
-        })
-        .transpose()
+}
+
+fn parse_loan_rewards(q: &BorrowLiquidationPriceQuery) -> Result<Option<Rewards>> {
+    match q.market_borrower_info.as_ref() {
+        Some(payload) => match (&payload.result, &payload.height) {
+            (Some(result), Some(height)) => Ok(Some(parse_rewards(
+                &MarketBorrowerInfo::from_json(result)?,
+                height.parse()?,
+            )?)),
+            _ => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The height the loan was read at, which the debt and the collaterals must agree on
+fn parse_loan_height(q: &BorrowLiquidationPriceQuery) -> Result<u64> {
+    let height = |payload: &Option<GetWasmContractsContractAddressStorePayload>| -> Result<u64> {
+        payload
+            .as_ref()
+            .and_then(|p| p.height.as_ref())
+            .ok_or_else(|| anyhow!("Missing height of the loan query"))?
+            .parse()
+            .map_err(Error::from)
+    };
+    let market = height(&q.market_borrower_info)?;
+    let overseer = height(&q.overseer_collaterals)?;
+    if market != overseer {
+        return Err(anyhow!("Loan read at heights {} and {}", market, overseer));
+    }
+    Ok(market)
 }
 
 fn parse_collateral(
     q: &BorrowLiquidationPriceQuery,
-    collateral_addr: &str,
-) -> Result<Option<Decimal>, Error> {
+    token: CollateralToken,
+) -> Result<Option<Micro>, Error> {
     q.overseer_collaterals
         .as_ref()
         .and_then(|p| p.result.as_ref())
-        .map(|result| OverseerCollaterals::from_json(&result))
+        .map(OverseerCollaterals::from_json)
         .transpose()
         .and_then(|c| {
             if c.is_none() {
@@ -130,53 +258,76 @@ fn parse_collateral(
                     });
 
             collaterals
-                .get(collateral_addr)
-                .map(|s| {
-                    Decimal::from_str(&s)
-                        .map_err(|e| anyhow::Error::from(e))
-                        .map(|mut d| {
-                            d.set_scale(6).unwrap();
-                            d
-                        })
-                })
+                .get(token.contract_address())
+                .map(|s| Micro::parse_micro(s))
                 .transpose()
         })
 }
 
+/// The loan and all its collaterals, whichever they are.
+/// Fails if the debt is missing or any part of the query is malformed.
 pub fn parse_loan(q: BorrowLiquidationPriceQuery) -> Result<Loan> {
-    let loan_amount = parse_loan_amount(&q);
-    let beth_collateral = parse_collateral(&q, Contracts::BETH);
-    let bluna_collateral = parse_collateral(&q, Contracts::BLUNA);
-
-    match (&loan_amount, &beth_collateral, &bluna_collateral) {
-        (Ok(Some(amount)), Ok(beth), Ok(Some(bluna))) => {
-            let mut collaterals = HashMap::new();
-            collaterals.insert(Contracts::BLUNA.to_string(), bluna.clone());
-            if let Some(beth) = beth {
-                collaterals.insert(Contracts::BETH.to_string(), beth.clone());
-            }
-            Ok(Loan {
-                amount: *amount,
-                collaterals,
-            })
-        }
-        (_, _, _) => {
-            debug!(
-                "Loan amount result: {:?}\nCollateral amount result: {:?}\n",
-                loan_amount, bluna_collateral
-            );
-            Err(anyhow!("Liquidation error"))
+    let amount = parse_loan_amount(&q)?.ok_or_else(|| anyhow!("Missing loan amount"))?;
+    let mut collaterals = HashMap::new();
+    for token in CollateralToken::ALL.iter().copied() {
+        if let Some(collateral) = parse_collateral(&q, token)? {
+            collaterals.insert(token, collateral);
         }
     }
+    // the loan is still useful without its rewards
+    let rewards = parse_loan_rewards(&q).unwrap_or_else(|e| {
+        debug!("Couldn't parse the rewards of the loan: {}", e);
+        None
+    });
+    Ok(Loan {
+        amount,
+        collaterals,
+        last_updated: None,
+        rewards,
+    })
 }
 
-pub fn liquidation_price(q: BorrowLiquidationPriceQuery) -> Result<Option<Decimal>> {
+/// The max LTV of the collaterals, from the whitelist queried along with the loan
+fn parse_max_ltvs(q: &BorrowLiquidationPriceQuery) -> Result<MaxLtvs> {
+    let payload = q
+        .overseer_whitelist
+        .as_ref()
+        .ok_or_else(|| anyhow!("Missing overseer whitelist"))?;
+    let whitelist = payload
+        .result
+        .as_ref()
+        .ok_or_else(|| anyhow!("Missing overseer whitelist"))?;
+    let height = payload
+        .height
+        .as_ref()
+        .map(|h| h.parse())
+        .transpose()?
+        .unwrap_or(0);
+    MaxLtvs::from_whitelist(height, &OverseerWhitelist::from_json(whitelist)?)
+}
+
+/// Turns a liquidation price which doesn't fit in a `Decimal` into an error
+fn checked(price: LiquidationPrice) -> Result<LiquidationPrice> {
+    match price {
+        LiquidationPrice::Overflow => Err(anyhow!("Liquidation price overflow")),
+        LiquidationPrice::Price(_)
+        | LiquidationPrice::NeverLiquidatable
+        | LiquidationPrice::AlreadyLiquidatable => Ok(price),
+    }
+}
+
+pub fn liquidation_price(q: BorrowLiquidationPriceQuery) -> Result<Option<LiquidationPrice>> {
     let loan_amount = parse_loan_amount(&q);
-    let bluna_collateral = parse_collateral(&q, Contracts::BLUNA);
-    let max_ltv = Decimal::new(6, 1);
+    let bluna_collateral = parse_collateral(&q, CollateralToken::Bluna);
+    let max_ltvs = parse_max_ltvs(&q)?;
 
     match (&loan_amount, &bluna_collateral) {
-        (Ok(Some(a)), Ok(Some(c))) => Ok(Some(anchor::liquidation_price(a, c, &max_ltv))),
+        (Ok(Some(a)), Ok(Some(c))) => checked(anchor::liquidation_price(
+            &a.units(),
+            &c.units(),
+            &max_ltvs.get(CollateralToken::Bluna),
+        ))
+        .map(Some),
         (_, _) => {
             debug!(
                 "Loan amount result: {:?}\nCollateral amount result: {:?}\n",
@@ -190,20 +341,32 @@ pub fn liquidation_price(q: BorrowLiquidationPriceQuery) -> Result<Option<Decima
 pub fn liquidation_price_multi(
     q: BorrowLiquidationPriceQuery,
     beth_price: Decimal,
-) -> Result<Option<Decimal>> {
+) -> Result<Option<LiquidationPrice>> {
     let loan_amount = parse_loan_amount(&q);
-    let beth_collateral = parse_collateral(&q, Contracts::BETH);
-    let bluna_collateral = parse_collateral(&q, Contracts::BLUNA);
-    let max_ltv = Decimal::new(6, 1);
+    let beth_collateral = parse_collateral(&q, CollateralToken::Beth);
+    let bluna_collateral = parse_collateral(&q, CollateralToken::Bluna);
+    let max_ltvs = parse_max_ltvs(&q)?;
+    let bluna_max_ltv = max_ltvs.get(CollateralToken::Bluna);
 
     match (&loan_amount, &beth_collateral, &bluna_collateral) {
-        (Ok(Some(amount)), Ok(Some(beth)), Ok(Some(bluna))) => Ok(Some(
-            anchor::liquidation_price_multi(amount, beth, &beth_price, bluna, &max_ltv),
-        )),
-        // fallback to default
-        (Ok(Some(amount)), Ok(None), Ok(Some(bluna))) => {
-            Ok(Some(anchor::liquidation_price(amount, bluna, &max_ltv)))
+        (Ok(Some(amount)), Ok(Some(beth)), Ok(Some(bluna))) => {
+            checked(anchor::liquidation_price_multi(
+                &amount.units(),
+                &beth.units(),
+                &beth_price,
+                &max_ltvs.get(CollateralToken::Beth),
+                &bluna.units(),
+                &bluna_max_ltv,
+            ))
+            .map(Some)
         }
+        // fallback to default
+        (Ok(Some(amount)), Ok(None), Ok(Some(bluna))) => checked(anchor::liquidation_price(
+            &amount.units(),
+            &bluna.units(),
+            &bluna_max_ltv,
+        ))
+        .map(Some),
         (_, _, _) => {
             debug!(
                 "Loan amount result: {:?}\nCollateral amount result: {:?}\n",
@@ -221,6 +384,56 @@ mod tests {
     #[tokio::test]
     async fn throws_error_on_bad_address() {
         let price = MantleClient::query_liquidation_price("abcd").await;
-        assert!(!price.is_err());
+        assert!(price.is_err());
+    }
+
+    /// A loan query answered at height 42
+    fn loan_query(borrower_info: &str, collaterals: &str) -> BorrowLiquidationPriceQuery {
+        let payload = |result: &str| {
+            Some(GetWasmContractsContractAddressStorePayload {
+                height: Some("42".to_string()),
+                result: Some(result.to_string()),
+            })
+        };
+        BorrowLiquidationPriceQuery {
+            market_borrower_info: payload(borrower_info),
+            overseer_borrow_limit: None,
+            overseer_collaterals: payload(collaterals),
+            overseer_whitelist: None,
+            oracle_price_info: None,
+        }
+    }
+
+    #[test]
+    fn parses_loans_without_bluna() {
+        let borrower_info = r#"{"borrower":"terra1","interest_index":"1","loan_amount":"1000000","pending_rewards":"0","reward_index":"0"}"#;
+        let beth = CollateralToken::Beth.contract_address();
+        let loan = parse_loan(loan_query(
+            borrower_info,
+            &format!(
+                r#"{{"borrower":"terra1","collaterals":[["{}","2000000"]]}}"#,
+                beth
+            ),
+        ))
+        .unwrap();
+        assert_eq!(Micro::new(1_000_000), loan.amount);
+        assert_eq!(
+            vec![(CollateralToken::Beth, Micro::new(2_000_000))],
+            loan.collaterals.into_iter().collect::<Vec<_>>()
+        );
+
+        let loan = parse_loan(loan_query(
+            borrower_info,
+            r#"{"borrower":"terra1","collaterals":[]}"#,
+        ))
+        .unwrap();
+        assert!(loan.collaterals.is_empty());
+    }
+
+    #[test]
+    fn fails_on_malformed_borrower_info() {
+        let query = loan_query("{}", r#"{"borrower":"terra1","collaterals":[]}"#);
+        assert!(parse_loan_amount(&query).is_err());
+        assert!(parse_loan(query).is_err());
     }
 }
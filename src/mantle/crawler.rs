@@ -0,0 +1,498 @@
+use anyhow::{anyhow, Result};
+use cynic::http::SurfExt;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::cache::{apply_tx_event, BlockEvents, CacheEvent, Loan, LoanBook};
+use crate::mantle::client::{parse_block_events, parse_rewards, query_contract_store, MANTLE_HOST};
+use crate::mantle::queries::{
+    BlockTxsQuery, FromJson, MarketBorrowerInfos, MarketBorrowerInfosQuery, OverseerAllCollaterals,
+    OverseerAllCollateralsQuery, ToJson,
+};
+use crate::mantle::Contracts;
+use crate::snapshot::Snapshot;
+use crate::types::{Address, CollateralToken, Micro};
+
+/// Default number of entries per page, the maximum accepted by the Anchor contracts
+pub const PAGE_LIMIT: u32 = 30;
+
+/// Crawls the whole loan book from the market and overseer contracts,
+/// to (re)generate the seed loaded by `AnchorCache::seed_borrowers`
+pub struct SeedCrawler {
+    host: String,
+    limit: u32,
+}
+
+impl Default for SeedCrawler {
+    fn default() -> Self {
+        Self::new(MANTLE_HOST)
+    }
+}
+
+impl SeedCrawler {
+    pub fn new<T: ToString>(host: T) -> Self {
+        Self::with_limit(host, PAGE_LIMIT)
+    }
+
+    pub fn with_limit<T: ToString>(host: T, limit: u32) -> Self {
+        SeedCrawler {
+            host: host.to_string(),
+            limit: limit.max(1),
+        }
+    }
+
+    /// Pages through every borrower and collateral, then brings the whole loan book to the
+    /// height of the last page. The pages aren't pinned to a height: each is answered at whatever
+    /// height Mantle is at, so the events since each page are replayed on the loans it returned,
+    /// or would have returned for the borrowers which first appeared since.
+    /// A final listing of every borrower then adds any the crawl still missed.
+    pub async fn crawl(&self) -> Result<Snapshot> {
+        let (mut borrowers, debts, collaterals) = self.crawl_pages().await?;
+        let from = debts.earliest().min(collaterals.earliest());
+        let height = debts.latest().max(collaterals.latest());
+        self.catch_up(&mut borrowers, from, height, |event| {
+            pages_of(event, &debts, &collaterals).height_of(event.address())
+        })
+        .await?;
+
+        let (listed, debts, collaterals) = self.crawl_pages().await?;
+        let missing: LoanBook = listed
+            .into_iter()
+            .filter(|(address, _)| !borrowers.contains_key(address))
+            .collect();
+        if !missing.is_empty() {
+            warn!("Adding {} borrowers missed by the crawl", missing.len());
+        }
+        let latest = height.max(debts.latest()).max(collaterals.latest());
+        borrowers.extend(missing.clone());
+        // the missed borrowers are as of their page, the others as of `height`
+        self.catch_up(&mut borrowers, height, latest, |event| {
+            if missing.contains_key(event.address()) {
+                pages_of(event, &debts, &collaterals).height_of(event.address())
+            } else {
+                height
+            }
+        })
+        .await?;
+
+        info!("Crawled {} borrowers at height {}", borrowers.len(), latest);
+        Ok(Snapshot::new(latest, borrowers))
+    }
+
+    /// Pages through every borrower infos, then every collateral
+    async fn crawl_pages(&self) -> Result<(LoanBook, PageHeights, PageHeights)> {
+        let mut borrowers = LoanBook::new();
+        let debts = self.crawl_debts(&mut borrowers).await?;
+        debug!("Crawled {} borrower infos", borrowers.len());
+        let collaterals = self.crawl_collaterals(&mut borrowers).await?;
+        Ok((borrowers, debts, collaterals))
+    }
+
+    /// Applies the events of the blocks `from + 1..=to` to the loans, skipping those
+    /// mined before the height `crawled_at` returns for the loan they modify
+    async fn catch_up<F>(
+        &self,
+        borrowers: &mut LoanBook,
+        from: u64,
+        to: u64,
+        crawled_at: F,
+    ) -> Result<()>
+    where
+        F: Fn(&CacheEvent) -> u64,
+    {
+        if from < to {
+            info!("Catching up blocks {} to {}...", from + 1, to);
+        }
+        for height in from + 1..=to {
+            let block = self.query_block(height).await?;
+            catch_up(borrowers, &block, &crawled_at);
+        }
+        Ok(())
+    }
+
+    async fn crawl_debts(&self, borrowers: &mut LoanBook) -> Result<PageHeights> {
+        let mut pages = PageHeights::default();
+        let mut start_after = None;
+        loop {
+            let query = MarketBorrowerInfosQuery::new(start_after.take(), self.limit);
+            let (height, result) = self.query(Contracts::MARKET, &query).await?;
+            let page = MarketBorrowerInfos::from_json(&result)?.borrower_infos;
+            for info in &page {
+                let loan = loan_mut(borrowers, info.borrower.parse()?);
+                loan.amount = Micro::parse_micro(&info.loan_amount)?;
+                loan.rewards = Some(parse_rewards(info, height)?);
+            }
+            match page.last() {
+                Some(last) if page.len() as u32 == self.limit => {
+                    pages.push(Some(last.borrower.parse()?), height);
+                    start_after = Some(last.borrower.clone())
+                }
+                _ => {
+                    pages.push(None, height);
+                    return Ok(pages);
+                }
+            }
+        }
+    }
+
+    async fn crawl_collaterals(&self, borrowers: &mut LoanBook) -> Result<PageHeights> {
+        let mut pages = PageHeights::default();
+        let mut start_after = None;
+        loop {
+            let query = OverseerAllCollateralsQuery::new(start_after.take(), self.limit);
+            let (height, result) = self.query(Contracts::OVERSEER, &query).await?;
+            let page = OverseerAllCollaterals::from_json(&result)?.all_collaterals;
+            for entry in &page {
+                let loan = loan_mut(borrowers, entry.borrower.parse()?);
+                for collateral in &entry.collaterals {
+                    let (token, amount) = match collateral.as_slice() {
+                        [token, amount] => (token, amount),
+                        _ => return Err(anyhow!("Invalid collateral of {}", entry.borrower)),
+                    };
+                    match CollateralToken::from_contract_address(token) {
+                        Some(token) => {
+                            loan.collaterals.insert(token, Micro::parse_micro(amount)?);
+                        }
+                        None => debug!("Ignoring unknown collateral token {}", token),
+                    }
+                }
+            }
+            match page.last() {
+                Some(last) if page.len() as u32 == self.limit => {
+                    pages.push(Some(last.borrower.parse()?), height);
+                    start_after = Some(last.borrower.clone())
+                }
+                _ => {
+                    pages.push(None, height);
+                    return Ok(pages);
+                }
+            }
+        }
+    }
+
+    async fn query_block(&self, height: u64) -> Result<BlockEvents> {
+        let q = surf::post(&self.host)
+            .run_graphql(BlockTxsQuery::build_query(height as i32))
+            .await
+            .map_err(|e| e.into_inner())?
+            .data
+            .ok_or_else(|| anyhow!("Couldn't fetch block: {}", height))?;
+        Ok(parse_block_events(height, q))
+    }
+
+    async fn query<Q>(&self, contract_address: &str, query_msg: &Q) -> Result<(u64, String)>
+    where
+        Q: ToJson + Serialize,
+    {
+        query_contract_store(&self.host, contract_address, query_msg).await
+    }
+}
+
+/// The height each page of a crawl was answered at, by the last borrower of the page
+#[derive(Default)]
+struct PageHeights(Vec<(Option<Address>, u64)>);
+
+impl PageHeights {
+    /// Records the next page, `last` being `None` for the final one
+    fn push(&mut self, last: Option<Address>, height: u64) {
+        self.0.push((last, height));
+    }
+
+    /// Height of the page which returned, or would have returned, `address`
+    fn height_of(&self, address: &Address) -> u64 {
+        self.0
+            .iter()
+            .find(|(last, _)| last.as_ref().is_none_or(|last| address <= last))
+            .map_or(0, |(_, height)| *height)
+    }
+
+    fn earliest(&self) -> u64 {
+        self.0.iter().map(|(_, h)| *h).min().unwrap_or(0)
+    }
+
+    fn latest(&self) -> u64 {
+        self.0.iter().map(|(_, h)| *h).max().unwrap_or(0)
+    }
+}
+
+/// The pages which list the balance modified by `event`
+fn pages_of<'a>(
+    event: &CacheEvent,
+    debts: &'a PageHeights,
+    collaterals: &'a PageHeights,
+) -> &'a PageHeights {
+    match event {
+        CacheEvent::BorrowStable { .. } | CacheEvent::RepayStable { .. } => debts,
+        CacheEvent::DepositCollateral { .. } | CacheEvent::WithdrawCollateral { .. } => collaterals,
+    }
+}
+
+/// Applies the events of `block` to the loans crawled before it
+fn catch_up<F>(borrowers: &mut LoanBook, block: &BlockEvents, crawled_at: F)
+where
+    F: Fn(&CacheEvent) -> u64,
+{
+    for tx_event in &block.events {
+        let event = &tx_event.event;
+        if block.height <= crawled_at(event) {
+            continue;
+        }
+        // the borrower first appeared after its page was read
+        if !borrowers.contains_key(event.address()) {
+            let loan = loan_mut(borrowers, event.address().clone());
+            if let Some(token) = event.contract_address() {
+                loan.collaterals.insert(token, Micro::zero());
+            }
+        }
+        apply_tx_event(borrowers, block.height, tx_event);
+    }
+}
+
+fn loan_mut(borrowers: &mut LoanBook, address: Address) -> &mut Loan {
+    borrowers.entry(address).or_insert_with(|| Loan {
+        amount: Micro::zero(),
+        collaterals: Default::default(),
+        last_updated: None,
+        rewards: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{borrower, BORROWERS};
+    use crate::cache::AnchorCache;
+    use crate::snapshot::SnapshotFormat;
+    use crate::storage::MemoryStore;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const HEIGHT: u64 = 4_739_729;
+
+    /// The borrower infos listed at `height`
+    fn borrower_infos(height: u64) -> Vec<Value> {
+        let mut infos = vec![
+            (BORROWERS[1], "0", "0"),
+            (BORROWERS[0], "397843444", "2500000.25"),
+            (BORROWERS[2], "1000000", "0"),
+        ];
+        // only listed by the final listing
+        if height > HEIGHT + 3 {
+            infos.push((BORROWERS[4], "5000000", "0"));
+        }
+        infos
+            .into_iter()
+            .map(|(borrower, loan_amount, pending_rewards)| {
+                json!({
+                    "borrower": borrower,
+                    "interest_index": "1.0",
+                    "loan_amount": loan_amount,
+                    "pending_rewards": pending_rewards,
+                    "reward_index": "0.5",
+                })
+            })
+            .collect()
+    }
+
+    fn all_collaterals() -> Vec<Value> {
+        vec![
+            json!({
+                "borrower": BORROWERS[1],
+                "collaterals": [[Contracts::BLUNA, "2382330"]],
+            }),
+            json!({
+                "borrower": BORROWERS[0],
+                "collaterals": [[Contracts::BLUNA, "40400217"], [Contracts::BETH, "1000"]],
+            }),
+            json!({
+                "borrower": BORROWERS[2],
+                "collaterals": [[Contracts::MARKET, "1000"]],
+            }),
+        ]
+    }
+
+    fn from_contract(attributes: Vec<(&str, &str)>) -> Value {
+        let attributes: Vec<Value> = attributes
+            .into_iter()
+            .map(|(key, value)| json!({ "Key": key, "Value": value }))
+            .collect();
+        json!({ "Type": "from_contract", "Attributes": attributes })
+    }
+
+    /// The blocks mined while crawling, one per page
+    fn block(height: u64) -> Value {
+        let event = match height - HEIGHT {
+            // already returned by the second market page
+            1 => from_contract(vec![
+                ("action", "repay_stable"),
+                ("borrower", BORROWERS[2]),
+                ("repay_amount", "1000000"),
+            ]),
+            // first appears after its page was read
+            5 => from_contract(vec![
+                ("action", "deposit_collateral"),
+                ("borrower", BORROWERS[3]),
+                ("contract_address", Contracts::BLUNA),
+                ("amount", "3000000"),
+            ]),
+            2 => from_contract(vec![
+                ("action", "borrow_stable"),
+                ("borrower", BORROWERS[0]),
+                ("borrow_amount", "2000000"),
+            ]),
+            _ => from_contract(vec![
+                ("action", "deposit_collateral"),
+                ("borrower", BORROWERS[0]),
+                ("contract_address", Contracts::BLUNA),
+                ("amount", "1000000"),
+            ]),
+        };
+        json!({
+            "Blocks": [{
+                "Height": height,
+                "Txs": [{
+                    "TxHash": format!("TXHASH{}", height),
+                    "Timestamp": "2021-10-18T12:00:00Z",
+                    "Logs": [{ "Events": [event] }],
+                }],
+            }]
+        })
+    }
+
+    /// Answers like Mantle, paging through the fixtures and mining a block after every page
+    fn answer(request: &Value, pages: &AtomicU64) -> Value {
+        let variables = request["variables"].as_object().unwrap();
+        if let Some(height) = variables.values().find_map(|v| v.as_u64()) {
+            return json!({ "data": block(height) });
+        }
+
+        let query_msg: Value = variables
+            .values()
+            .filter_map(|v| v.as_str().and_then(|s| serde_json::from_str(s).ok()))
+            .find(|v: &Value| v.is_object())
+            .unwrap();
+        let height = HEIGHT + pages.fetch_add(1, Ordering::SeqCst);
+        let (key, entries) = if query_msg.get("borrower_infos").is_some() {
+            ("borrower_infos", borrower_infos(height))
+        } else {
+            ("all_collaterals", all_collaterals())
+        };
+        let args = &query_msg[key];
+        let page: Vec<Value> = entries
+            .into_iter()
+            .skip_while(|e| match args["start_after"].as_str() {
+                Some(start_after) => e["borrower"] != start_after,
+                None => false,
+            })
+            .skip(args["start_after"].is_string() as usize)
+            .take(args["limit"].as_u64().unwrap() as usize)
+            .collect();
+        json!({
+            "data": {
+                "contract_store": {
+                    "Height": height.to_string(),
+                    "Result": json!({ key: page }).to_string(),
+                }
+            }
+        })
+    }
+
+    async fn respond(mut socket: TcpStream, pages: Arc<AtomicU64>) {
+        let mut request = vec![];
+        let mut chunk = [0u8; 4096];
+        let header_end = loop {
+            let n = socket.read(&mut chunk).await.unwrap();
+            request.extend_from_slice(&chunk[..n]);
+            if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+        let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+        if headers.contains("expect: 100-continue") {
+            socket
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await
+                .unwrap();
+        }
+        let length: usize = headers
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length:"))
+            .map(|l| l.trim().parse().unwrap())
+            .unwrap();
+        while request.len() < header_end + length {
+            let n = socket.read(&mut chunk).await.unwrap();
+            request.extend_from_slice(&chunk[..n]);
+        }
+
+        let request = serde_json::from_slice(&request[header_end..]).unwrap();
+        let body = answer(&request, &pages).to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    /// Starts a mock Mantle server, returning its URL
+    async fn mock_mantle() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let pages = Arc::new(AtomicU64::new(0));
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(respond(socket, pages.clone()));
+            }
+        });
+        url
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn crawls_paged_loan_book() {
+        let crawler = SeedCrawler::with_limit(mock_mantle().await, 2);
+        let seed = crawler.crawl().await.unwrap();
+
+        // 2 pages of borrower infos then 2 pages of collaterals, a block apart,
+        // then the final listing's 3 pages of borrower infos and 2 pages of collaterals
+        assert_eq!(HEIGHT + 8, seed.height);
+        assert_eq!(5, seed.borrowers.len());
+        let loan = &seed.borrowers[&borrower(0)];
+        assert_eq!(Micro::new(399_843_444), loan.amount);
+        assert_eq!(
+            Some(&Micro::new(45_400_217)),
+            loan.collaterals.get(&CollateralToken::Bluna)
+        );
+        assert_eq!(
+            Some(&Micro::new(1_000)),
+            loan.collaterals.get(&CollateralToken::Beth)
+        );
+        let rewards = loan.rewards.as_ref().unwrap();
+        assert_eq!(Micro::new(2_500_000), rewards.pending);
+        assert_eq!(HEIGHT, rewards.height);
+        // unknown collateral tokens are ignored
+        let loan = &seed.borrowers[&borrower(2)];
+        assert!(loan.collaterals.is_empty());
+        assert_eq!(Micro::new(1_000_000), loan.amount);
+
+        let loan = &seed.borrowers[&borrower(3)];
+        assert!(loan.amount.is_zero());
+        assert_eq!(
+            Some(&Micro::new(3_000_000)),
+            loan.collaterals.get(&CollateralToken::Bluna)
+        );
+        assert_eq!(Micro::new(5_000_000), seed.borrowers[&borrower(4)].amount);
+
+        let path = std::env::temp_dir().join("liquidation_monitor_crawled_seed.json");
+        seed.write(&path, SnapshotFormat::Json).unwrap();
+        let cache = AnchorCache::new(Arc::new(MemoryStore::new()));
+        assert_eq!(
+            HEIGHT + 8,
+            cache.seed_borrowers(path.to_str().unwrap()).await.unwrap()
+        );
+        assert_eq!(seed.borrowers, *cache.store.all().await.unwrap());
+    }
+}
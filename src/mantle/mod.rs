@@ -1,4 +1,5 @@
 pub mod client;
+pub mod crawler;
 
 #[non_exhaustive]
 pub struct Contracts;
@@ -8,6 +9,8 @@ impl<'a> Contracts {
     pub const BETH: &'a str = "terra1dzhzukyezv0etz22ud940z7adyv7xgcjkahuun";
     pub const BLUNA: &'a str = "terra1kc87mu460fwkqte29rquh4hc20m54fxwtsx7gp";
     pub const ORACLE: &'a str = "terra1cgg6yef7qcdm070qftghfulaxmllgmvk77nc7t";
+    pub const LIQUIDATION_QUEUE: &'a str = "terra1e25zllgag7j9xsun3me4stnye2pcg66234je3u";
+    pub const INTEREST_MODEL: &'a str = "terra1kq8zzq5hufas9t0kjsjc62t2kucfnx8txf547n";
 }
 
 mod schema {
@@ -63,7 +66,8 @@ pub mod queries {
     #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
     #[serde(rename_all = "snake_case")]
     pub struct Whitelist {
-        pub collateral_token: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub collateral_token: Option<String>,
     }
 
     #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -124,6 +128,44 @@ pub mod queries {
         }
     }
 
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct PageArgs {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub start_after: Option<String>,
+        pub limit: u32,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct MarketBorrowerInfosQuery {
+        pub borrower_infos: PageArgs,
+    }
+
+    impl ToJson for MarketBorrowerInfosQuery {}
+    impl MarketBorrowerInfosQuery {
+        pub fn new(start_after: Option<String>, limit: u32) -> MarketBorrowerInfosQuery {
+            MarketBorrowerInfosQuery {
+                borrower_infos: PageArgs { start_after, limit },
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct OverseerAllCollateralsQuery {
+        pub all_collaterals: PageArgs,
+    }
+
+    impl ToJson for OverseerAllCollateralsQuery {}
+    impl OverseerAllCollateralsQuery {
+        pub fn new(start_after: Option<String>, limit: u32) -> OverseerAllCollateralsQuery {
+            OverseerAllCollateralsQuery {
+                all_collaterals: PageArgs { start_after, limit },
+            }
+        }
+    }
+
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     #[serde(rename_all = "snake_case")]
     pub struct OverseerWhitelistQuery {
@@ -135,10 +177,17 @@ pub mod queries {
         pub fn new<T: AsRef<str> + ToString>(collateral_token: T) -> OverseerWhitelistQuery {
             OverseerWhitelistQuery {
                 whitelist: Whitelist {
-                    collateral_token: collateral_token.to_string(),
+                    collateral_token: Some(collateral_token.to_string()),
                 },
             }
         }
+
+        /// The whole whitelist
+        pub fn all() -> OverseerWhitelistQuery {
+            OverseerWhitelistQuery {
+                whitelist: Whitelist::default(),
+            }
+        }
     }
 
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -161,6 +210,101 @@ pub mod queries {
         }
     }
 
+    /// The arguments of a query which takes none
+    #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct NoArgs {}
+
+    #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct MarketStateQuery {
+        pub state: NoArgs,
+    }
+    impl ToJson for MarketStateQuery {}
+
+    #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct MarketEpochStateQuery {
+        pub epoch_state: NoArgs,
+    }
+    impl ToJson for MarketEpochStateQuery {}
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct BorrowRate {
+        pub market_balance: String,
+        pub total_liabilities: String,
+        pub total_reserves: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct InterestModelBorrowRateQuery {
+        pub borrow_rate: BorrowRate,
+    }
+    impl ToJson for InterestModelBorrowRateQuery {}
+    impl InterestModelBorrowRateQuery {
+        pub fn new<T: AsRef<str> + ToString>(
+            market_balance: T,
+            total_liabilities: T,
+            total_reserves: T,
+        ) -> InterestModelBorrowRateQuery {
+            InterestModelBorrowRateQuery {
+                borrow_rate: BorrowRate {
+                    market_balance: market_balance.to_string(),
+                    total_liabilities: total_liabilities.to_string(),
+                    total_reserves: total_reserves.to_string(),
+                },
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct BidPoolsByCollateral {
+        pub collateral_token: String,
+        pub limit: u8,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct LiquidationQueueBidPoolsQuery {
+        pub bid_pools_by_collateral: BidPoolsByCollateral,
+    }
+    impl ToJson for LiquidationQueueBidPoolsQuery {}
+    impl LiquidationQueueBidPoolsQuery {
+        /// Every premium slot of `collateral_token`
+        pub fn new<T: AsRef<str> + ToString>(collateral_token: T) -> LiquidationQueueBidPoolsQuery {
+            LiquidationQueueBidPoolsQuery {
+                bid_pools_by_collateral: BidPoolsByCollateral {
+                    collateral_token: collateral_token.to_string(),
+                    limit: 31,
+                },
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct BidIdx {
+        pub bid_idx: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub struct LiquidationQueueBidQuery {
+        pub bid: BidIdx,
+    }
+    impl ToJson for LiquidationQueueBidQuery {}
+    impl LiquidationQueueBidQuery {
+        pub fn new<T: AsRef<str> + ToString>(bid_idx: T) -> LiquidationQueueBidQuery {
+            LiquidationQueueBidQuery {
+                bid: BidIdx {
+                    bid_idx: bid_idx.to_string(),
+                },
+            }
+        }
+    }
+
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     #[serde(rename_all = "snake_case")]
     pub struct Elem {
@@ -195,6 +339,18 @@ pub mod queries {
     }
     impl FromJson<OverseerCollaterals> for OverseerCollaterals {}
 
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct MarketBorrowerInfos {
+        pub borrower_infos: Vec<MarketBorrowerInfo>,
+    }
+    impl FromJson<MarketBorrowerInfos> for MarketBorrowerInfos {}
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct OverseerAllCollaterals {
+        pub all_collaterals: Vec<OverseerCollaterals>,
+    }
+    impl FromJson<OverseerAllCollaterals> for OverseerAllCollaterals {}
+
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct OverseerWhitelist {
         pub elems: Vec<Elem>,
@@ -203,12 +359,56 @@ pub mod queries {
 
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct OraclePriceInfo {
-        pub last_updated_base: i64,
-        pub last_updated_quote: i64,
+        pub last_updated_base: u64,
+        pub last_updated_quote: u64,
         pub rate: String,
     }
     impl FromJson<OraclePriceInfo> for OraclePriceInfo {}
 
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct BidPool {
+        pub premium_rate: String,
+        pub total_bid_amount: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct LiquidationQueueBidPools {
+        pub bid_pools: Vec<BidPool>,
+    }
+    impl FromJson<LiquidationQueueBidPools> for LiquidationQueueBidPools {}
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct LiquidationQueueBid {
+        pub idx: String,
+        pub collateral_token: String,
+        pub premium_slot: u8,
+        pub amount: String,
+        /// Until when the bid waits to be activated, `None` once it is
+        pub wait_end: Option<u64>,
+    }
+    impl FromJson<LiquidationQueueBid> for LiquidationQueueBid {}
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct MarketState {
+        pub total_liabilities: String,
+        pub total_reserves: String,
+        pub last_interest_updated: u64,
+    }
+    impl FromJson<MarketState> for MarketState {}
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct MarketEpochState {
+        pub exchange_rate: String,
+        pub aterra_supply: String,
+    }
+    impl FromJson<MarketEpochState> for MarketEpochState {}
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct InterestModelBorrowRate {
+        pub rate: String,
+    }
+    impl FromJson<InterestModelBorrowRate> for InterestModelBorrowRate {}
+
     #[derive(cynic::FragmentArguments, Debug, Clone)]
     pub struct BorrowStableTxHistoryQueryArguments {
         pub height_range: Option<Vec<Option<i32>>>,
@@ -242,6 +442,56 @@ pub mod queries {
         pub address: Option<String>,
     }
 
+    #[derive(cynic::FragmentArguments, Debug, Clone)]
+    pub struct BlockTxsQueryArguments {
+        pub height: Option<i32>,
+    }
+
+    #[derive(cynic::QueryFragment, Debug)]
+    #[cynic(graphql_type = "RootQuery", argument_struct = "BlockTxsQueryArguments")]
+    pub struct BlockTxsQuery {
+        #[arguments(height = args.height)]
+        pub blocks: Option<Vec<Option<Blocks>>>,
+    }
+    impl BlockTxsQuery {
+        pub fn build_query(height: i32) -> Operation<'static, BlockTxsQuery> {
+            BlockTxsQuery::build(&BlockTxsQueryArguments {
+                height: Some(height),
+            })
+        }
+    }
+
+    #[derive(cynic::QueryFragment, Debug)]
+    pub struct Blocks {
+        pub height: Option<i32>,
+        pub txs: Option<Vec<Option<BlocksTxs>>>,
+    }
+
+    #[derive(cynic::QueryFragment, Debug)]
+    pub struct BlocksTxs {
+        pub tx_hash: Option<String>,
+        pub timestamp: Option<String>,
+        pub logs: Option<Vec<Option<BlocksTxsLogs>>>,
+    }
+
+    #[derive(cynic::QueryFragment, Debug)]
+    pub struct BlocksTxsLogs {
+        pub events: Option<Vec<Option<BlocksTxsLogsEvents>>>,
+    }
+
+    #[derive(cynic::QueryFragment, Debug)]
+    pub struct BlocksTxsLogsEvents {
+        #[cynic(rename = "Type")]
+        pub event_type: Option<String>,
+        pub attributes: Option<Vec<Option<BlocksTxsLogsEventsAttributes>>>,
+    }
+
+    #[derive(cynic::QueryFragment, Debug)]
+    pub struct BlocksTxsLogsEventsAttributes {
+        pub key: Option<String>,
+        pub value: Option<String>,
+    }
+
     #[derive(cynic::QueryFragment, Debug, Clone, PartialEq, Serialize, Deserialize)]
     #[cynic(
         graphql_type = "RootQuery",
@@ -279,13 +529,45 @@ pub mod queries {
                 overseer_contract: Contracts::OVERSEER.to_string(),
                 overseer_borrowlimit_query: OverseerBorrowLimitQuery::new(&borrower).to_json(),
                 overseer_collaterals_query: OverseerCollateralsQuery::new(&borrower).to_json(),
-                overseer_whitelist_query: OverseerWhitelistQuery::new(Contracts::BLUNA).to_json(),
+                overseer_whitelist_query: OverseerWhitelistQuery::all().to_json(),
                 oracle_contract: Contracts::ORACLE.to_string(),
                 oracle_price_query: OraclePriceQuery::new(Contracts::BLUNA, "uusd").to_json(),
             })
         }
     }
 
+    #[derive(cynic::FragmentArguments, Debug)]
+    pub struct ContractStoreQueryArguments {
+        pub contract_address: String,
+        pub query_msg: String,
+    }
+
+    /// A single smart query of a contract, answered at the latest height known to Mantle
+    #[derive(cynic::QueryFragment, Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[cynic(
+        graphql_type = "RootQuery",
+        argument_struct = "ContractStoreQueryArguments"
+    )]
+    pub struct ContractStoreQuery {
+        #[arguments(contract_address = &args.contract_address, query_msg = &args.query_msg)]
+        #[cynic(rename = "WasmContractsContractAddressStore", alias)]
+        pub contract_store: Option<GetWasmContractsContractAddressStorePayload>,
+    }
+    impl ContractStoreQuery {
+        pub fn build_query<Q>(
+            contract_address: &str,
+            query_msg: &Q,
+        ) -> Operation<'static, ContractStoreQuery>
+        where
+            Q: ToJson + Serialize,
+        {
+            ContractStoreQuery::build(&ContractStoreQueryArguments {
+                contract_address: contract_address.to_string(),
+                query_msg: query_msg.to_json(),
+            })
+        }
+    }
+
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     #[serde(rename_all = "snake_case", untagged)]
     pub enum WasmContractResult {
@@ -316,6 +598,7 @@ pub mod queries {
 
     #[derive(cynic::QueryFragment, Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct GetWasmContractsContractAddressStorePayload {
+        pub height: Option<String>,
         pub result: Option<String>,
     }
 
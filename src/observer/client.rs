@@ -27,6 +27,7 @@ pub struct ObserverClient {
 const TERRA_OBSERVER: &str = "wss://observer.terra.dev";
 
 impl ObserverClient {
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> ObserverClient {
         let mut socket = connect(Url::parse(TERRA_OBSERVER).unwrap())
             .expect("Can't connect to Terra Observer")
@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+use crate::mantle::client::{query_contract_store, MANTLE_HOST};
+use crate::mantle::queries::{FromJson, OraclePriceInfo, OraclePriceQuery};
+use crate::mantle::Contracts;
+use crate::types::CollateralToken;
+
+/// Default delay between two price updates, about two blocks
+pub const PRICE_INTERVAL: Duration = Duration::from_secs(12);
+/// Prices are quoted in UST
+const QUOTE: &str = "uusd";
+
+pub type SharedPrices = Arc<RwLock<Prices>>;
+
+/// The latest oracle price of each collateral, in UST
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Prices {
+    /// Height the last price was read at, 0 until the first update
+    pub height: u64,
+    pub prices: BTreeMap<CollateralToken, Decimal>,
+}
+
+impl Prices {
+    pub fn get(&self, token: CollateralToken) -> Option<Decimal> {
+        self.prices.get(&token).copied()
+    }
+
+    /// Records the price of `token` from an oracle query `result` read at `height`,
+    /// keeping the previous one and the height if it's invalid
+    fn refresh(&mut self, token: CollateralToken, height: u64, result: &str) -> Result<()> {
+        let price = parse_price(result)?;
+        self.height = self.height.max(height);
+        self.prices.insert(token, price);
+        Ok(())
+    }
+}
+
+/// Periodically reads the price of every collateral from the Anchor oracle
+pub struct PriceFeed {
+    host: String,
+    prices: SharedPrices,
+}
+
+impl Default for PriceFeed {
+    fn default() -> Self {
+        Self::new(MANTLE_HOST)
+    }
+}
+
+impl PriceFeed {
+    pub fn new<T: ToString>(host: T) -> Self {
+        PriceFeed {
+            host: host.to_string(),
+            prices: Arc::new(RwLock::new(Prices::default())),
+        }
+    }
+
+    pub fn prices(&self) -> SharedPrices {
+        self.prices.clone()
+    }
+
+    /// Updates the prices every `interval`, in the background
+    pub fn spawn(self, interval: Duration) {
+        info!("Reading oracle prices every {:?}", interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.update().await {
+                    error!("Error reading oracle prices: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Reads the price of every collateral, keeping the previous one of those which fail
+    pub async fn update(&self) -> Result<()> {
+        let mut errors = vec![];
+        for token in CollateralToken::ALL.iter().copied() {
+            let query = OraclePriceQuery::new(token.contract_address(), QUOTE);
+            let refreshed = match query_contract_store(&self.host, Contracts::ORACLE, &query).await
+            {
+                Ok((height, result)) => self.prices.write().await.refresh(token, height, &result),
+                Err(e) => Err(e),
+            };
+            // the other tokens are still refreshed
+            if let Err(e) = refreshed {
+                warn!("Error reading the price of {}: {}", token, e);
+                errors.push(format!("{}: {}", token, e));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("Missing prices of {}", errors.join(", ")))
+        }
+    }
+}
+
+/// The rate of an oracle price query result
+pub fn parse_price(result: &str) -> Result<Decimal> {
+    let info = OraclePriceInfo::from_json(&result)?;
+    Decimal::from_str(&info.rate).map_err(|e| anyhow!("Invalid price '{}': {}", info.rate, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_oracle_price() {
+        let result = r#"{"rate":"36.482650000000000000","last_updated_base":1636104315,"last_updated_quote":18446744073709551615}"#;
+        assert_eq!(Decimal::new(3648265, 5), parse_price(result).unwrap());
+        assert!(
+            parse_price(r#"{"rate":"NaN","last_updated_base":0,"last_updated_quote":0}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn keeps_the_previous_price_when_invalid() {
+        let mut prices = Prices::default();
+        prices
+            .refresh(
+                CollateralToken::Bluna,
+                42,
+                r#"{"rate":"36.5","last_updated_base":0,"last_updated_quote":0}"#,
+            )
+            .unwrap();
+        assert!(prices
+            .refresh(
+                CollateralToken::Bluna,
+                43,
+                r#"{"rate":"NaN","last_updated_base":0,"last_updated_quote":0}"#,
+            )
+            .is_err());
+        prices
+            .refresh(
+                CollateralToken::Beth,
+                41,
+                r#"{"rate":"4200","last_updated_base":0,"last_updated_quote":0}"#,
+            )
+            .unwrap();
+
+        assert_eq!(42, prices.height);
+        assert_eq!(
+            Some(Decimal::new(365, 1)),
+            prices.get(CollateralToken::Bluna)
+        );
+        assert_eq!(
+            Some(Decimal::new(4200, 0)),
+            prices.get(CollateralToken::Beth)
+        );
+    }
+}
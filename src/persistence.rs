@@ -0,0 +1,392 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::cache::{BlockEvents, Loan, LoanBook};
+use crate::queue::QueueEvent;
+use crate::snapshot::{write_snapshot, Snapshot, SnapshotFormat};
+use crate::types::Address;
+use crate::whitelist::WhitelistUpdate;
+
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+/// Snapshots used to be written in JSON
+const LEGACY_SNAPSHOT_FILE: &str = "snapshot.json";
+const WAL_FILE: &str = "wal.jsonl";
+
+/// Default number of blocks between two snapshots (roughly one hour on Columbus-5)
+pub const SNAPSHOT_INTERVAL: u64 = 600;
+
+/// Shared by the listener, which records the blocks, and the reconciler, which records its corrections
+pub type SharedPersistence = Arc<Mutex<Persistence>>;
+
+/// An entry of the WAL. Blocks are written as is, so older WALs can still be replayed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum WalEntry {
+    Block(BlockEvents),
+    /// Loans overwritten with their on-chain state at the end of block `correction`,
+    /// see `LoanStore::correct`
+    Correction {
+        correction: u64,
+        loans: Vec<(Address, Loan)>,
+    },
+}
+
+impl WalEntry {
+    /// The order entries are replayed in: by height, a correction after the block it follows
+    /// (the reconciler may write it before the listener writes the block)
+    fn replay_order(&self) -> (u64, bool) {
+        match self {
+            WalEntry::Block(block) => (block.height, false),
+            WalEntry::Correction { correction, .. } => (*correction, true),
+        }
+    }
+}
+
+/// The state rebuilt from the latest snapshot and the WAL
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Restored {
+    /// Height of the last block applied
+    pub height: u64,
+    pub borrowers: LoanBook,
+    /// Whitelist updates of the replayed blocks, along with their height
+    pub whitelist: Vec<(u64, WhitelistUpdate)>,
+    /// Liquidation queue events of the replayed blocks
+    pub queue: Vec<(u64, QueueEvent)>,
+}
+
+/// Persists the borrowers as periodic snapshots plus a write-ahead log (WAL)
+/// of every block applied and every correction made since the last snapshot
+pub struct Persistence {
+    dir: PathBuf,
+    wal: BufWriter<File>,
+    snapshot_interval: u64,
+    snapshot_height: u64,
+}
+
+impl Persistence {
+    /// Opens (or creates) the persistence directory, taking a snapshot every `snapshot_interval` blocks
+    pub fn open<P: AsRef<Path>>(dir: P, snapshot_interval: u64) -> Result<Persistence> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Error creating persistence dir {:?}", dir))?;
+        let snapshot_height = read_snapshot(&snapshot_path(&dir))?
+            .map(|s| s.height)
+            .unwrap_or(0);
+
+        Ok(Persistence {
+            wal: open_wal(&dir)?,
+            dir,
+            snapshot_interval,
+            snapshot_height,
+        })
+    }
+
+    /// Loads the latest snapshot and replays the WAL on top of it.
+    /// Returns `None` if neither exist.
+    pub fn restore(&self) -> Result<Option<Restored>> {
+        let snapshot = read_snapshot(&snapshot_path(&self.dir))?;
+        let mut entries = read_wal(&self.dir.join(WAL_FILE))?;
+        if snapshot.is_none() && entries.is_empty() {
+            return Ok(None);
+        }
+
+        let mut restored = snapshot
+            .map(|s| Restored {
+                height: s.height,
+                borrowers: s.borrowers,
+                ..Restored::default()
+            })
+            .unwrap_or_default();
+        info!(
+            "Restoring snapshot at height {} and {} WAL entries",
+            restored.height,
+            entries.len()
+        );
+
+        let snapshot_height = restored.height;
+        entries.sort_by_key(WalEntry::replay_order);
+        for entry in entries {
+            let block = match entry {
+                WalEntry::Block(block) if block.height > snapshot_height => block,
+                WalEntry::Block(_) => continue,
+                // a correction at the snapshot height may have been made after it was taken,
+                // and writing the same loans twice is harmless
+                WalEntry::Correction { correction, loans } => {
+                    if correction >= snapshot_height {
+                        restored.borrowers.extend(loans);
+                    }
+                    continue;
+                }
+            };
+            block.apply_to(&mut restored.borrowers);
+            restored.whitelist.extend(
+                block
+                    .whitelist
+                    .iter()
+                    .map(|update| (block.height, update.clone())),
+            );
+            restored.queue.extend(
+                block
+                    .queue
+                    .iter()
+                    .map(|event| (block.height, event.clone())),
+            );
+            restored.height = block.height;
+        }
+        Ok(Some(restored))
+    }
+
+    /// Appends a block which has just been applied to the WAL.
+    /// Every block is written, even without any event, so that the WAL ends at the last height applied.
+    pub fn append(&mut self, block: &BlockEvents) -> Result<()> {
+        serde_json::to_writer(&mut self.wal, block)?;
+        self.sync_entry()
+    }
+
+    /// Appends loans which have just been corrected at the end of block `height` to the WAL
+    pub fn append_correction(&mut self, height: u64, loans: Vec<(Address, Loan)>) -> Result<()> {
+        let entry = WalEntry::Correction {
+            correction: height,
+            loans,
+        };
+        serde_json::to_writer(&mut self.wal, &entry)?;
+        self.sync_entry()
+    }
+
+    fn sync_entry(&mut self) -> Result<()> {
+        self.wal.write_all(b"\n")?;
+        self.wal.flush()?;
+        self.wal.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Whether `snapshot_interval` blocks have passed since the last snapshot
+    pub fn snapshot_due(&self, height: u64) -> bool {
+        height >= self.snapshot_height + self.snapshot_interval
+    }
+
+    /// Writes a snapshot of `borrowers` at `height` and truncates the WAL
+    pub fn snapshot(&mut self, height: u64, borrowers: &LoanBook) -> Result<()> {
+        write_snapshot(
+            self.dir.join(SNAPSHOT_FILE),
+            height,
+            borrowers,
+            SnapshotFormat::Binary,
+        )?;
+        let legacy = self.dir.join(LEGACY_SNAPSHOT_FILE);
+        if legacy.exists() {
+            fs::remove_file(legacy)?;
+        }
+
+        // every block in the WAL is now covered by the snapshot
+        self.wal = BufWriter::new(File::create(self.dir.join(WAL_FILE))?);
+        self.snapshot_height = height;
+        debug!("Wrote snapshot at height {}", height);
+        Ok(())
+    }
+}
+
+fn open_wal(dir: &Path) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(WAL_FILE))?;
+    Ok(BufWriter::new(file))
+}
+
+/// The latest snapshot in `dir`, in the current or the legacy format
+fn snapshot_path(dir: &Path) -> PathBuf {
+    let path = dir.join(SNAPSHOT_FILE);
+    let legacy = dir.join(LEGACY_SNAPSHOT_FILE);
+    if !path.exists() && legacy.exists() {
+        legacy
+    } else {
+        path
+    }
+}
+
+fn read_snapshot(path: &Path) -> Result<Option<Snapshot>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Snapshot::read(path).map(Some)
+}
+
+fn read_wal(path: &Path) -> Result<Vec<WalEntry>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut entries = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                // only the last entry can be partially written, e.g. after a crash
+                warn!("Ignoring the rest of the WAL after a corrupt entry: {}", e);
+                break;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{borrower, loan, loan_book};
+    use crate::cache::{CacheEvent, TxEvent};
+    use crate::types::{CollateralToken, Micro};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "liquidation_monitor_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn borrowers() -> LoanBook {
+        loan_book(vec![loan(
+            1_000_000_000,
+            &[(CollateralToken::Bluna, 100_000_000)],
+        )])
+    }
+
+    fn borrow(height: u64, amount: i64) -> BlockEvents {
+        BlockEvents {
+            height,
+            timestamp: None,
+            events: vec![TxEvent {
+                txhash: "TXHASH".to_string(),
+                event: CacheEvent::BorrowStable {
+                    address: borrower(0),
+                    amount: Micro::new(amount),
+                },
+            }],
+            whitelist: vec![],
+            queue: vec![],
+        }
+    }
+
+    #[test]
+    fn restores_nothing_from_empty_dir() {
+        let dir = temp_dir("empty");
+        let persistence = Persistence::open(&dir, SNAPSHOT_INTERVAL).unwrap();
+        assert_eq!(None, persistence.restore().unwrap());
+    }
+
+    #[test]
+    fn restores_snapshot_and_replays_wal() {
+        let dir = temp_dir("replay");
+        let mut borrowers = borrowers();
+        let mut persistence = Persistence::open(&dir, 10).unwrap();
+        persistence.snapshot(100, &borrowers).unwrap();
+
+        for block in &[borrow(101, 1_000_000), borrow(105, 2_000_000)] {
+            block.apply_to(&mut borrowers);
+            persistence.append(block).unwrap();
+        }
+        drop(persistence);
+
+        let restored = Persistence::open(&dir, 10).unwrap().restore().unwrap();
+        assert_eq!(
+            Some(Restored {
+                height: 105,
+                borrowers,
+                ..Restored::default()
+            }),
+            restored
+        );
+    }
+
+    #[test]
+    fn restores_blocks_without_loan_events() {
+        let dir = temp_dir("empty_blocks");
+        let borrowers = borrowers();
+        let mut persistence = Persistence::open(&dir, 10).unwrap();
+        persistence.snapshot(100, &borrowers).unwrap();
+
+        let update = WhitelistUpdate {
+            collateral_token: CollateralToken::Beth,
+            max_ltv: rust_decimal::Decimal::new(5, 1),
+        };
+        let mut whitelisted = borrow(101, 0);
+        whitelisted.events.clear();
+        whitelisted.whitelist.push(update.clone());
+        let mut empty = borrow(102, 0);
+        empty.events.clear();
+        persistence.append(&whitelisted).unwrap();
+        persistence.append(&empty).unwrap();
+        drop(persistence);
+
+        let restored = Persistence::open(&dir, 10)
+            .unwrap()
+            .restore()
+            .unwrap()
+            .unwrap();
+        assert_eq!(102, restored.height);
+        assert_eq!(borrowers, restored.borrowers);
+        assert_eq!(vec![(101, update)], restored.whitelist);
+    }
+
+    #[test]
+    fn replays_corrections_after_their_block() {
+        let dir = temp_dir("corrections");
+        let mut borrowers = borrowers();
+        let mut persistence = Persistence::open(&dir, 10).unwrap();
+        persistence.snapshot(100, &borrowers).unwrap();
+
+        let mut corrected = borrowers[&borrower(0)].clone();
+        corrected.amount = Micro::new(5_000_000_000);
+        // made on top of block 101, but written before it
+        persistence
+            .append_correction(101, vec![(borrower(0), corrected.clone())])
+            .unwrap();
+        // already covered by the snapshot
+        persistence
+            .append_correction(99, vec![(borrower(0), borrowers[&borrower(0)].clone())])
+            .unwrap();
+        for block in &[borrow(101, 1_000_000), borrow(102, 2_000_000)] {
+            persistence.append(block).unwrap();
+        }
+        drop(persistence);
+
+        borrowers.insert(borrower(0), corrected);
+        borrow(102, 2_000_000).apply_to(&mut borrowers);
+        let restored = Persistence::open(&dir, 10)
+            .unwrap()
+            .restore()
+            .unwrap()
+            .unwrap();
+        assert_eq!(102, restored.height);
+        assert_eq!(borrowers, restored.borrowers);
+    }
+
+    #[test]
+    fn truncates_wal_after_snapshot() {
+        let dir = temp_dir("truncate");
+        let mut borrowers = borrowers();
+        let mut persistence = Persistence::open(&dir, 10).unwrap();
+        persistence.snapshot(100, &borrowers).unwrap();
+
+        let block = borrow(110, 1_000_000);
+        block.apply_to(&mut borrowers);
+        persistence.append(&block).unwrap();
+        assert!(persistence.snapshot_due(110));
+        persistence.snapshot(110, &borrowers).unwrap();
+
+        assert!(read_wal(&dir.join(WAL_FILE)).unwrap().is_empty());
+        let snapshot = read_snapshot(&dir.join(SNAPSHOT_FILE)).unwrap().unwrap();
+        assert_eq!(110, snapshot.height);
+        assert_eq!(borrowers, snapshot.borrowers);
+    }
+}
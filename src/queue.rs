@@ -0,0 +1,821 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{debug, error, info};
+
+use crate::anchor::LiquidationPrice;
+use crate::cache::LoanBook;
+use crate::mantle::client::query_contract_store;
+use crate::mantle::queries::{
+    FromJson, LiquidationQueueBid, LiquidationQueueBidPools, LiquidationQueueBidPoolsQuery,
+    LiquidationQueueBidQuery,
+};
+use crate::mantle::Contracts;
+use crate::oracle::Prices;
+use crate::types::{CollateralToken, Micro};
+use crate::whitelist::MaxLtvs;
+
+/// Default delay between two syncs of the bid pools with the chain
+pub const QUEUE_INTERVAL: Duration = Duration::from_secs(60);
+/// Highest premium slot of the liquidation queue
+pub const MAX_PREMIUM_SLOT: u8 = 30;
+
+pub type SharedQueue = Arc<RwLock<LiquidationQueue>>;
+
+/// An event of the liquidation queue contract
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueEvent {
+    /// A bid of `amount` UST, pending until activated
+    SubmitBid {
+        bid_idx: u64,
+        amount: Micro,
+    },
+    /// Pending bids of the sender, worth `amount` UST, joined their pools
+    ActivateBids {
+        amount: Micro,
+    },
+    RetractBid {
+        bid_idx: u64,
+        amount: Micro,
+    },
+    /// Collateral bought by the pools, for the debt repaid plus fees
+    ExecuteBid {
+        collateral_token: CollateralToken,
+        collateral_amount: Micro,
+        repay_amount: Micro,
+        bid_fee: Micro,
+        liquidator_fee: Micro,
+    },
+}
+
+/// A bid of known collateral and premium slot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingBid {
+    pub collateral_token: CollateralToken,
+    pub premium_slot: u8,
+    pub amount: Micro,
+}
+
+/// The active bids of a premium slot, which buy collateral at a discount of `premium_rate`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BidPool {
+    pub premium_rate: Decimal,
+    pub total_bid_amount: Micro,
+}
+
+/// A change of the bid pools applied from an event
+#[derive(Debug, Clone, PartialEq)]
+enum PoolChange {
+    /// Bids of `amount` UST joining a slot, or leaving it if negative
+    Bids {
+        collateral: CollateralToken,
+        premium_slot: u8,
+        amount: Micro,
+    },
+    /// UST spent by a liquidation, the lowest premium first
+    Spent {
+        collateral: CollateralToken,
+        amount: Micro,
+    },
+}
+
+impl PoolChange {
+    fn collateral(&self) -> CollateralToken {
+        match self {
+            PoolChange::Bids { collateral, .. } | PoolChange::Spent { collateral, .. } => {
+                *collateral
+            }
+        }
+    }
+}
+
+/// The state of a tracked bid, as read from the chain
+#[derive(Debug, Clone, PartialEq)]
+pub enum BidState {
+    Pending(PendingBid),
+    Active(PendingBid),
+    /// Retracted or fully consumed
+    Gone,
+    /// Of a collateral which isn't tracked
+    Untracked,
+}
+
+/// The bid pools of every collateral, synced from the chain and kept up to date from events
+/// in between. Only the bids seen submitted or activated are tracked one by one.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LiquidationQueue {
+    /// Height of the last sync with the chain, 0 until the first one
+    pub height: u64,
+    pub pools: BTreeMap<CollateralToken, BTreeMap<u8, BidPool>>,
+    pub pending: BTreeMap<u64, PendingBid>,
+    /// Bids seen submitted, whose collateral and slot aren't known until the next sync
+    pub unresolved: BTreeMap<u64, Micro>,
+    /// Bids seen activated, with the amount they joined their pool with,
+    /// so that retracting them takes them out of it
+    pub active: BTreeMap<u64, PendingBid>,
+    /// Changes of the pools applied while a sync reads the chain, with their height,
+    /// replayed on top of the pools it read
+    #[serde(skip)]
+    syncing: Option<Vec<(u64, PoolChange)>>,
+}
+
+impl LiquidationQueue {
+    /// Applies an event emitted at `height`, returning whether the bid pools changed.
+    /// Pending bids join their pool once activated, and bids whose collateral and slot
+    /// are still unknown once the next sync sees them in it.
+    /// Changes of the pools the last sync already saw are ignored (e.g. while backfilling).
+    pub fn apply(&mut self, height: u64, event: &QueueEvent) -> bool {
+        match event {
+            QueueEvent::SubmitBid { bid_idx, amount } => {
+                self.unresolved.insert(*bid_idx, *amount);
+                false
+            }
+            QueueEvent::ActivateBids { .. } | QueueEvent::ExecuteBid { .. }
+                if height <= self.height =>
+            {
+                false
+            }
+            QueueEvent::ActivateBids { amount } => self.activate(height, *amount),
+            QueueEvent::RetractBid { bid_idx, amount } => {
+                if let Some(bid) = self.unresolved.get_mut(bid_idx) {
+                    *bid -= *amount;
+                    if bid.is_negative() || bid.is_zero() {
+                        self.unresolved.remove(bid_idx);
+                    }
+                    false
+                } else if let Some(bid) = self.pending.get_mut(bid_idx) {
+                    bid.amount -= *amount;
+                    if bid.amount.is_negative() || bid.amount.is_zero() {
+                        self.pending.remove(bid_idx);
+                    }
+                    false
+                } else {
+                    self.retract_active(height, *bid_idx, *amount)
+                }
+            }
+            QueueEvent::ExecuteBid {
+                collateral_token,
+                repay_amount,
+                bid_fee,
+                liquidator_fee,
+                ..
+            } => {
+                self.change(
+                    height,
+                    PoolChange::Spent {
+                        collateral: *collateral_token,
+                        amount: *repay_amount + *bid_fee + *liquidator_fee,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Moves pending bids worth `amount` UST into their pool.
+    /// The event doesn't name the bids, so they're matched by amount, the oldest first;
+    /// whatever doesn't match is left to the next sync.
+    fn activate(&mut self, height: u64, amount: Micro) -> bool {
+        let mut remaining = amount;
+        let activated: Vec<u64> = self
+            .pending
+            .iter()
+            .filter_map(|(idx, bid)| {
+                if bid.amount > remaining {
+                    return None;
+                }
+                remaining -= bid.amount;
+                Some(*idx)
+            })
+            .collect();
+        for idx in &activated {
+            if let Some(bid) = self.pending.remove(idx) {
+                self.change(
+                    height,
+                    PoolChange::Bids {
+                        collateral: bid.collateral_token,
+                        premium_slot: bid.premium_slot,
+                        amount: bid.amount,
+                    },
+                );
+                self.active.insert(*idx, bid);
+            }
+        }
+        !activated.is_empty()
+    }
+
+    /// Takes a retracted active bid out of its pool, if its slot is known.
+    /// Otherwise the next sync catches up.
+    fn retract_active(&mut self, height: u64, bid_idx: u64, amount: Micro) -> bool {
+        if height <= self.height {
+            return false;
+        }
+        let bid = match self.active.get_mut(&bid_idx) {
+            Some(bid) => bid,
+            None => return false,
+        };
+        let change = PoolChange::Bids {
+            collateral: bid.collateral_token,
+            premium_slot: bid.premium_slot,
+            amount: -amount,
+        };
+        bid.amount -= amount;
+        if bid.amount.is_negative() || bid.amount.is_zero() {
+            self.active.remove(&bid_idx);
+        }
+        self.change(height, change);
+        true
+    }
+
+    /// Applies `change` emitted at `height`, logging it for the sync reading the chain, if any
+    fn change(&mut self, height: u64, change: PoolChange) {
+        self.apply_change(&change);
+        if let Some(changes) = &mut self.syncing {
+            changes.push((height, change));
+        }
+    }
+
+    fn apply_change(&mut self, change: &PoolChange) {
+        match *change {
+            PoolChange::Bids {
+                collateral,
+                premium_slot,
+                amount,
+            } => {
+                let pool = self
+                    .pools
+                    .entry(collateral)
+                    .or_default()
+                    .entry(premium_slot)
+                    .or_insert_with(|| BidPool {
+                        premium_rate: Decimal::new(premium_slot.into(), 2),
+                        total_bid_amount: Micro::zero(),
+                    });
+                pool.total_bid_amount = (pool.total_bid_amount + amount).max(Micro::zero());
+            }
+            PoolChange::Spent { collateral, amount } => self.consume(collateral, amount),
+        }
+    }
+
+    /// Takes `spent` UST out of the pools of `collateral`, the lowest premium first
+    fn consume(&mut self, collateral: CollateralToken, mut spent: Micro) {
+        for pool in self.pools.entry(collateral).or_default().values_mut() {
+            if spent.is_zero() || spent.is_negative() {
+                break;
+            }
+            let consumed = spent.min(pool.total_bid_amount);
+            pool.total_bid_amount -= consumed;
+            spent -= consumed;
+        }
+    }
+
+    /// The active bids of `collateral`, in UST
+    pub fn total_bid_amount(&self, collateral: CollateralToken) -> Micro {
+        self.pools
+            .get(&collateral)
+            .map(|pools| pools.values().map(|pool| pool.total_bid_amount).sum())
+            .unwrap_or_default()
+    }
+
+    /// Simulates the liquidation of `path`'s collateral amounts at each price, in turn,
+    /// by the active bids of `collateral` as they stand
+    pub fn simulate(&self, collateral: CollateralToken, path: &[(Decimal, Micro)]) -> Simulation {
+        let mut pools: Vec<(u8, Decimal, Decimal)> = self
+            .pools
+            .get(&collateral)
+            .map(|pools| {
+                pools
+                    .iter()
+                    .map(|(slot, pool)| (*slot, pool.premium_rate, pool.total_bid_amount.units()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut slots: BTreeMap<u8, SlotFill> = BTreeMap::new();
+        let mut steps = vec![];
+        for (price, liquidated) in path {
+            let mut remaining = liquidated.units();
+            let mut paid = Decimal::ZERO;
+            for (slot, premium_rate, bids) in pools.iter_mut() {
+                let bid_price = price * (Decimal::ONE - *premium_rate);
+                if remaining.is_zero() || bids.is_zero() || bid_price <= Decimal::ZERO {
+                    continue;
+                }
+                let absorbed = remaining.min(*bids / bid_price);
+                let cost = (absorbed * bid_price).min(*bids);
+                *bids -= cost;
+                remaining -= absorbed;
+                paid += cost;
+                let fill = slots.entry(*slot).or_insert_with(|| SlotFill {
+                    premium_slot: *slot,
+                    premium_rate: *premium_rate,
+                    collateral: Micro::zero(),
+                    paid: Decimal::ZERO,
+                });
+                fill.collateral += Micro::from_units(absorbed);
+                fill.paid += cost;
+            }
+            steps.push(StepFill {
+                price: *price,
+                liquidated: *liquidated,
+                absorbed: *liquidated - Micro::from_units(remaining),
+                unfilled: Micro::from_units(remaining),
+                paid,
+            });
+        }
+        let value: Decimal = steps
+            .iter()
+            .map(|step| step.absorbed.units() * step.price)
+            .sum();
+        let paid: Decimal = steps.iter().map(|step| step.paid).sum();
+        Simulation {
+            collateral_token: collateral,
+            absorbed: steps.iter().map(|step| step.absorbed).sum(),
+            unfilled: steps.iter().map(|step| step.unfilled).sum(),
+            paid,
+            effective_premium: Some(value)
+                .filter(|value| !value.is_zero())
+                .map(|value| Decimal::ONE - paid / value),
+            steps,
+            slots: slots.into_values().collect(),
+        }
+    }
+
+    /// Reads the bid pools of every collateral, and the state of the tracked bids, from the chain.
+    /// The changes of the pools applied in the meantime are replayed unless the pools read include them.
+    pub async fn sync(queue: &SharedQueue, host: &str) -> Result<()> {
+        queue.write().await.syncing = Some(vec![]);
+        let read = Self::read_chain(queue, host).await;
+
+        let mut queue = queue.write().await;
+        let changes = queue.syncing.take().unwrap_or_default();
+        let (pools, bids) = read?;
+        queue.reset_pools(pools, changes);
+        queue.resolve(bids);
+        Ok(())
+    }
+
+    /// Replaces the bid pools with the ones read from the chain at the given heights,
+    /// then replays the `changes` which happened after
+    fn reset_pools(
+        &mut self,
+        pools: BTreeMap<CollateralToken, (u64, BTreeMap<u8, BidPool>)>,
+        changes: Vec<(u64, PoolChange)>,
+    ) {
+        self.height = pools.values().map(|(at, _)| *at).max().unwrap_or_default();
+        self.pools = pools
+            .iter()
+            .map(|(token, (_, pools))| (*token, pools.clone()))
+            .collect();
+        for (height, change) in changes {
+            if pools
+                .get(&change.collateral())
+                .is_some_and(|(at, _)| height > *at)
+            {
+                self.apply_change(&change);
+            }
+        }
+    }
+
+    /// Updates the tracked bids with their state read from the chain.
+    /// Bids submitted while syncing are resolved next time.
+    fn resolve(&mut self, bids: BTreeMap<u64, BidState>) {
+        let mut pending = BTreeMap::new();
+        for (idx, state) in bids {
+            self.unresolved.remove(&idx);
+            match state {
+                // unless activated while syncing
+                BidState::Pending(bid) if !self.active.contains_key(&idx) => {
+                    pending.insert(idx, bid);
+                }
+                BidState::Pending(_) => {}
+                BidState::Active(bid) => {
+                    self.active.entry(idx).or_insert(bid);
+                }
+                BidState::Gone | BidState::Untracked => {
+                    self.active.remove(&idx);
+                }
+            }
+        }
+        self.pending = pending;
+    }
+
+    /// The bid pools of every collateral with the height they were read at,
+    /// and the pending and unresolved bids of `queue` as they stand on chain
+    async fn read_chain(
+        queue: &SharedQueue,
+        host: &str,
+    ) -> Result<(
+        BTreeMap<CollateralToken, (u64, BTreeMap<u8, BidPool>)>,
+        BTreeMap<u64, BidState>,
+    )> {
+        let mut pools = BTreeMap::new();
+        for token in CollateralToken::ALL.iter().copied() {
+            let query = LiquidationQueueBidPoolsQuery::new(token.contract_address());
+            let (at, result) =
+                query_contract_store(host, Contracts::LIQUIDATION_QUEUE, &query).await?;
+            pools.insert(token, (at, parse_bid_pools(&result)?));
+        }
+
+        let bids: Vec<u64> = {
+            let queue = queue.read().await;
+            queue
+                .pending
+                .keys()
+                .chain(queue.unresolved.keys())
+                .chain(queue.active.keys())
+                .copied()
+                .collect()
+        };
+        let mut resolved = BTreeMap::new();
+        for idx in bids {
+            let query = LiquidationQueueBidQuery::new(idx.to_string());
+            let state = match query_contract_store(host, Contracts::LIQUIDATION_QUEUE, &query).await
+            {
+                Ok((_, result)) => parse_bid(&result)?,
+                // retracted or fully consumed since
+                Err(e) => {
+                    debug!("Error reading bid {}: {}", idx, e);
+                    BidState::Gone
+                }
+            };
+            resolved.insert(idx, state);
+        }
+        Ok((pools, resolved))
+    }
+
+    /// Syncs the queue with the chain every `interval`, in the background
+    pub fn spawn_sync(queue: SharedQueue, host: String, interval: Duration) {
+        info!("Syncing the liquidation queue every {:?}", interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = Self::sync(&queue, &host).await {
+                    error!("Error syncing the liquidation queue: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// The active bid pools of a `bid_pools_by_collateral` query result, by premium slot
+pub fn parse_bid_pools(result: &str) -> Result<BTreeMap<u8, BidPool>> {
+    LiquidationQueueBidPools::from_json(&result)?
+        .bid_pools
+        .into_iter()
+        .map(|pool| {
+            let premium_rate: Decimal = pool
+                .premium_rate
+                .parse()
+                .map_err(|e| anyhow!("Invalid premium rate '{}': {}", pool.premium_rate, e))?;
+            let slot = (premium_rate * Decimal::ONE_HUNDRED)
+                .round()
+                .to_u8()
+                .filter(|slot| *slot <= MAX_PREMIUM_SLOT)
+                .ok_or_else(|| anyhow!("Invalid premium rate {}", premium_rate))?;
+            Ok((
+                slot,
+                BidPool {
+                    premium_rate,
+                    total_bid_amount: Micro::parse_micro(&pool.total_bid_amount)?,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// The state of the bid of a `bid` query result
+pub fn parse_bid(result: &str) -> Result<BidState> {
+    let bid = LiquidationQueueBid::from_json(&result)?;
+    let collateral_token = match CollateralToken::from_contract_address(&bid.collateral_token) {
+        Some(token) => token,
+        None => return Ok(BidState::Untracked),
+    };
+    let pending = PendingBid {
+        collateral_token,
+        premium_slot: bid.premium_slot,
+        amount: Micro::parse_micro(&bid.amount).unwrap_or_default(),
+    };
+    Ok(match bid.wait_end {
+        Some(_) => BidState::Pending(pending),
+        None => BidState::Active(pending),
+    })
+}
+
+/// What a premium slot absorbed over a simulation
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SlotFill {
+    pub premium_slot: u8,
+    pub premium_rate: Decimal,
+    pub collateral: Micro,
+    /// In UST
+    pub paid: Decimal,
+}
+
+/// What the queue absorbed at a step of a simulation
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepFill {
+    pub price: Decimal,
+    pub liquidated: Micro,
+    pub absorbed: Micro,
+    pub unfilled: Micro,
+    /// In UST
+    pub paid: Decimal,
+}
+
+/// The outcome of liquidations along a price path
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Simulation {
+    pub collateral_token: CollateralToken,
+    pub absorbed: Micro,
+    /// Collateral left over once the bids ran out
+    pub unfilled: Micro,
+    pub paid: Decimal,
+    /// The discount paid overall to the oracle price, `None` if nothing was absorbed
+    pub effective_premium: Option<Decimal>,
+    pub steps: Vec<StepFill>,
+    pub slots: Vec<SlotFill>,
+}
+
+/// The `collateral` of the loans liquidated at each price of `path`, as the price goes through it,
+/// the other collaterals being valued at `prices`. Loans are liquidated at the first price
+/// below their liquidation price, with the whole of their `collateral`.
+pub fn liquidated_along(
+    borrowers: &LoanBook,
+    collateral: CollateralToken,
+    prices: &Prices,
+    max_ltvs: &MaxLtvs,
+    path: &[Decimal],
+) -> Vec<(Decimal, Micro)> {
+    let mut liquidated = vec![Micro::zero(); path.len()];
+    for loan in borrowers.values() {
+        let amount = match loan.collaterals.get(&collateral) {
+            Some(amount) if !amount.is_zero() => *amount,
+            _ => continue,
+        };
+        let step = match loan.liquidation_price(collateral, prices, max_ltvs) {
+            Some(LiquidationPrice::Price(price)) => path.iter().position(|p| *p < price),
+            Some(LiquidationPrice::AlreadyLiquidatable) => Some(0),
+            _ => None,
+        };
+        if let Some(step) = step.filter(|_| !path.is_empty()) {
+            liquidated[step] += amount;
+        }
+    }
+    path.iter().copied().zip(liquidated).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue() -> LiquidationQueue {
+        let result = r#"{"bid_pools":[
+            {"sum_snapshot":"0","product_snapshot":"1","total_bid_amount":"990000000","premium_rate":"0.01","current_epoch":"0","current_scale":"0","residue_collateral":"0","residue_bid":"0"},
+            {"sum_snapshot":"0","product_snapshot":"1","total_bid_amount":"1900000000","premium_rate":"0.05","current_epoch":"0","current_scale":"0","residue_collateral":"0","residue_bid":"0"}
+        ]}"#;
+        let mut queue = LiquidationQueue::default();
+        queue
+            .pools
+            .insert(CollateralToken::Bluna, parse_bid_pools(result).unwrap());
+        queue
+    }
+
+    #[test]
+    fn tracks_bid_pools_from_events() {
+        let mut queue = queue();
+        assert_eq!(
+            vec![1, 5],
+            queue.pools[&CollateralToken::Bluna]
+                .keys()
+                .copied()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Micro::new(2_890_000_000),
+            queue.total_bid_amount(CollateralToken::Bluna)
+        );
+
+        assert!(!queue.apply(
+            42,
+            &QueueEvent::SubmitBid {
+                bid_idx: 7,
+                amount: Micro::new(500_000_000),
+            }
+        ));
+        assert_eq!(Some(&Micro::new(500_000_000)), queue.unresolved.get(&7));
+        queue.apply(
+            42,
+            &QueueEvent::RetractBid {
+                bid_idx: 7,
+                amount: Micro::new(500_000_000),
+            },
+        );
+        assert!(queue.unresolved.is_empty());
+
+        // 1,200 UST taken from the 1% pool, then the 5% pool
+        assert!(queue.apply(
+            42,
+            &QueueEvent::ExecuteBid {
+                collateral_token: CollateralToken::Bluna,
+                collateral_amount: Micro::new(30_000_000),
+                repay_amount: Micro::new(1_188_000_000),
+                bid_fee: Micro::new(6_000_000),
+                liquidator_fee: Micro::new(6_000_000),
+            }
+        ));
+        assert_eq!(
+            Micro::zero(),
+            queue.pools[&CollateralToken::Bluna][&1].total_bid_amount
+        );
+        assert_eq!(
+            Micro::new(1_690_000_000),
+            queue.pools[&CollateralToken::Bluna][&5].total_bid_amount
+        );
+    }
+
+    fn bid(collateral_token: CollateralToken, premium_slot: u8, amount: i64) -> PendingBid {
+        PendingBid {
+            collateral_token,
+            premium_slot,
+            amount: Micro::new(amount),
+        }
+    }
+
+    #[test]
+    fn moves_activated_bids_into_their_slot() {
+        let mut queue = queue();
+        queue.height = 42;
+        queue
+            .pending
+            .insert(3, bid(CollateralToken::Bluna, 5, 100_000_000));
+        queue
+            .pending
+            .insert(4, bid(CollateralToken::Beth, 2, 50_000_000));
+        queue
+            .pending
+            .insert(5, bid(CollateralToken::Bluna, 1, 1_000_000_000));
+        let activate = |amount| QueueEvent::ActivateBids {
+            amount: Micro::new(amount),
+        };
+
+        // already seen by the last sync
+        assert!(!queue.apply(42, &activate(150_000_000)));
+        assert_eq!(3, queue.pending.len());
+
+        assert!(queue.apply(43, &activate(150_000_000)));
+        assert_eq!(vec![5], queue.pending.keys().copied().collect::<Vec<_>>());
+        assert_eq!(vec![3, 4], queue.active.keys().copied().collect::<Vec<_>>());
+        assert_eq!(
+            Micro::new(2_000_000_000),
+            queue.pools[&CollateralToken::Bluna][&5].total_bid_amount
+        );
+        let beth = &queue.pools[&CollateralToken::Beth][&2];
+        assert_eq!(Decimal::new(2, 2), beth.premium_rate);
+        assert_eq!(Micro::new(50_000_000), beth.total_bid_amount);
+
+        // no pending bid fits
+        assert!(!queue.apply(44, &activate(10_000_000)));
+    }
+
+    #[test]
+    fn takes_retracted_active_bids_out_of_their_slot() {
+        let mut queue = queue();
+        queue.height = 42;
+        queue
+            .active
+            .insert(3, bid(CollateralToken::Bluna, 5, 100_000_000));
+        let retract = |bid_idx, amount| QueueEvent::RetractBid {
+            bid_idx,
+            amount: Micro::new(amount),
+        };
+
+        // already seen by the last sync, or of an unknown slot
+        assert!(!queue.apply(42, &retract(3, 40_000_000)));
+        assert!(!queue.apply(43, &retract(9, 40_000_000)));
+        assert_eq!(
+            Micro::new(1_900_000_000),
+            queue.pools[&CollateralToken::Bluna][&5].total_bid_amount
+        );
+
+        assert!(queue.apply(43, &retract(3, 40_000_000)));
+        assert_eq!(Micro::new(60_000_000), queue.active[&3].amount);
+        assert!(queue.apply(44, &retract(3, 60_000_000)));
+        assert!(queue.active.is_empty());
+        assert_eq!(
+            Micro::new(1_800_000_000),
+            queue.pools[&CollateralToken::Bluna][&5].total_bid_amount
+        );
+    }
+
+    #[test]
+    fn resolves_tracked_bids_on_sync() {
+        let mut queue = queue();
+        queue.unresolved.insert(1, Micro::new(100_000_000));
+        queue.unresolved.insert(2, Micro::new(100_000_000));
+        queue
+            .pending
+            .insert(3, bid(CollateralToken::Bluna, 5, 100_000_000));
+        queue
+            .active
+            .insert(4, bid(CollateralToken::Bluna, 1, 100_000_000));
+        // submitted while syncing
+        queue.unresolved.insert(5, Micro::new(100_000_000));
+
+        let mut bids = BTreeMap::new();
+        bids.insert(
+            1,
+            BidState::Pending(bid(CollateralToken::Beth, 3, 100_000_000)),
+        );
+        bids.insert(2, BidState::Untracked);
+        bids.insert(
+            3,
+            BidState::Active(bid(CollateralToken::Bluna, 5, 100_000_000)),
+        );
+        bids.insert(4, BidState::Gone);
+        queue.resolve(bids);
+
+        assert_eq!(vec![1], queue.pending.keys().copied().collect::<Vec<_>>());
+        assert_eq!(
+            vec![5],
+            queue.unresolved.keys().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(vec![3], queue.active.keys().copied().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn simulates_liquidations_along_a_price_path() {
+        let queue = queue();
+        let simulation = queue.simulate(
+            CollateralToken::Bluna,
+            &[
+                // 10 bLUNA at $99 empty the 1% pool
+                (Decimal::new(100, 0), Micro::new(10_000_000)),
+                // 1,900 UST at $47.5 buy 40 bLUNA
+                (Decimal::new(50, 0), Micro::new(100_000_000)),
+            ],
+        );
+        assert_eq!(Micro::new(10_000_000), simulation.steps[0].absorbed);
+        assert_eq!(Micro::zero(), simulation.steps[0].unfilled);
+        assert_eq!(Micro::new(40_000_000), simulation.steps[1].absorbed);
+        assert_eq!(Micro::new(60_000_000), simulation.unfilled);
+        assert_eq!(Decimal::new(2_890, 0), simulation.paid);
+        assert_eq!(2, simulation.slots.len());
+        assert_eq!(Micro::new(40_000_000), simulation.slots[1].collateral);
+        // 2,890 UST paid for 1,000 + 2,000 UST worth of bLUNA
+        assert_eq!(
+            Decimal::new(367, 4),
+            simulation.effective_premium.unwrap().round_dp(4)
+        );
+
+        assert!(queue
+            .simulate(CollateralToken::Beth, &[(Decimal::ONE, Micro::new(1))])
+            .effective_premium
+            .is_none());
+        // nothing is bought for free
+        let free = queue.simulate(CollateralToken::Bluna, &[(Decimal::ZERO, Micro::new(1))]);
+        assert_eq!(Micro::new(1), free.unfilled);
+        assert!(free.slots.is_empty());
+    }
+
+    #[test]
+    fn replays_liquidations_applied_while_syncing() {
+        let execute = |repay_amount| QueueEvent::ExecuteBid {
+            collateral_token: CollateralToken::Bluna,
+            collateral_amount: Micro::new(1_000_000),
+            repay_amount: Micro::new(repay_amount),
+            bid_fee: Micro::zero(),
+            liquidator_fee: Micro::zero(),
+        };
+        let mut queue = queue();
+        let read = queue.pools[&CollateralToken::Bluna].clone();
+        queue.syncing = Some(vec![]);
+        queue.apply(43, &execute(100_000_000));
+        queue.apply(45, &execute(200_000_000));
+
+        // the pools were read at 44, after the first liquidation
+        let mut pools = BTreeMap::new();
+        pools.insert(CollateralToken::Bluna, (44, read));
+        let executed = queue.syncing.take().unwrap();
+        queue.reset_pools(pools, executed);
+        assert_eq!(44, queue.height);
+        assert_eq!(
+            Micro::new(790_000_000),
+            queue.pools[&CollateralToken::Bluna][&1].total_bid_amount
+        );
+
+        // already seen by the sync
+        assert!(!queue.apply(44, &execute(100_000_000)));
+        assert_eq!(
+            Micro::new(2_690_000_000),
+            queue.total_bid_amount(CollateralToken::Bluna)
+        );
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+
+use crate::anchor;
+use crate::cache::{Loan, LoanBook, DEFAULT_BETH_PRICE};
+use crate::mantle::client::{MantleClient, MantleExt};
+use crate::persistence::SharedPersistence;
+use crate::storage::{Correction, LoanStore};
+use crate::types::{Address, CollateralToken, Micro};
+use crate::whitelist::MaxLtvs;
+
+/// Default delay between two reconciliation rounds
+pub const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+/// Default number of borrowers closest to liquidation checked every round
+pub const PRIORITY_SAMPLE: usize = 10;
+/// Default number of other borrowers checked every round, in turn
+pub const ROTATING_SAMPLE: usize = 10;
+/// Number of drifted loans kept in the stats
+const RECENT_DRIFTS: usize = 20;
+
+pub type SharedDriftStats = Arc<RwLock<DriftStats>>;
+
+/// The difference between a cached loan and its on-chain state (on-chain minus cached)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoanDrift {
+    pub address: Address,
+    pub height: u64,
+    pub debt: Micro,
+    pub collaterals: HashMap<CollateralToken, Micro>,
+}
+
+/// How far the loan book built from events is from the chain
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DriftStats {
+    /// Loans compared against their on-chain state
+    pub checked: u64,
+    /// Loans which had drifted, and were corrected
+    pub drifted: u64,
+    /// Loans which couldn't be fetched from Mantle
+    pub errors: u64,
+    /// Loans read by Mantle at another height than the loan book's, left for a later round
+    pub skipped: u64,
+    /// Sum of the absolute debt differences found
+    pub debt_delta: Micro,
+    /// Sum of the absolute collateral differences found, per token
+    pub collateral_delta: HashMap<CollateralToken, Micro>,
+    pub last_round: Option<DateTime<Utc>>,
+    /// The latest drifted loans, newest first
+    pub recent: VecDeque<LoanDrift>,
+}
+
+impl DriftStats {
+    fn record(&mut self, drift: LoanDrift) {
+        self.drifted += 1;
+        self.debt_delta += abs(drift.debt);
+        for (token, delta) in &drift.collaterals {
+            *self.collateral_delta.entry(*token).or_default() += abs(*delta);
+        }
+        self.recent.push_front(drift);
+        self.recent.truncate(RECENT_DRIFTS);
+    }
+}
+
+fn abs(amount: Micro) -> Micro {
+    if amount.is_negative() {
+        -amount
+    } else {
+        amount
+    }
+}
+
+/// The drift of a cached loan from its on-chain state, `None` if they match
+pub fn drift(address: &Address, height: u64, cached: &Loan, actual: &Loan) -> Option<LoanDrift> {
+    let tokens: HashSet<CollateralToken> = cached
+        .collaterals
+        .keys()
+        .chain(actual.collaterals.keys())
+        .copied()
+        .collect();
+    let collaterals: HashMap<CollateralToken, Micro> = tokens
+        .into_iter()
+        .map(|token| {
+            let amount = |loan: &Loan| loan.collaterals.get(&token).copied().unwrap_or_default();
+            (token, amount(actual) - amount(cached))
+        })
+        .filter(|(_, delta)| !delta.is_zero())
+        .collect();
+    let debt = actual.amount - cached.amount;
+
+    if debt.is_zero() && collaterals.is_empty() {
+        return None;
+    }
+    Some(LoanDrift {
+        address: address.clone(),
+        height,
+        debt,
+        collaterals,
+    })
+}
+
+/// Periodically compares sampled loans with their on-chain state and corrects those which drifted,
+/// to catch the events the pipeline missed or misparsed
+pub struct Reconciler {
+    store: Arc<dyn LoanStore>,
+    /// Where the corrections are recorded, so that a restart doesn't bring the drift back
+    persistence: Option<SharedPersistence>,
+    stats: SharedDriftStats,
+    priority: usize,
+    rotating: usize,
+    /// Last borrower checked in turn
+    cursor: Option<Address>,
+}
+
+impl Reconciler {
+    pub fn new(store: Arc<dyn LoanStore>) -> Self {
+        Self::with_sample(store, PRIORITY_SAMPLE, ROTATING_SAMPLE)
+    }
+
+    pub fn with_sample(store: Arc<dyn LoanStore>, priority: usize, rotating: usize) -> Self {
+        Reconciler {
+            store,
+            persistence: None,
+            stats: Arc::new(RwLock::new(DriftStats::default())),
+            priority,
+            rotating,
+            cursor: None,
+        }
+    }
+
+    /// Records the corrections in the WAL of `persistence`
+    pub fn with_persistence(mut self, persistence: SharedPersistence) -> Self {
+        self.persistence = Some(persistence);
+        self
+    }
+
+    pub fn stats(&self) -> SharedDriftStats {
+        self.stats.clone()
+    }
+
+    /// Runs a reconciliation round every `interval`, in the background
+    pub fn spawn(mut self, interval: Duration) {
+        info!("Reconciling loans with Mantle every {:?}", interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.run_round().await {
+                    error!("Error reconciling loans: {}", e);
+                }
+            }
+        });
+    }
+
+    async fn run_round(&mut self) -> Result<()> {
+        let borrowers = self.store.all().await?;
+        let mut corrected = 0;
+        for address in self.sample(&borrowers) {
+            match MantleClient::query_loan(&address).await {
+                // reconciled right away, while the loan book is still at the height it was read at
+                Ok((height, loan)) => {
+                    let cached = borrowers[&address].clone();
+                    corrected += self
+                        .reconcile(vec![(address, cached, height, loan)])
+                        .await?
+                        .len();
+                }
+                Err(e) => {
+                    debug!("Error fetching loan of {}: {}", address, e);
+                    self.stats.write().await.errors += 1;
+                }
+            }
+        }
+        if corrected > 0 {
+            warn!("Corrected {} drifted loans", corrected);
+        }
+        self.stats.write().await.last_round = Some(Utc::now());
+        Ok(())
+    }
+
+    /// The borrowers to check next: those closest to liquidation, then the others in turn
+    pub fn sample(&mut self, borrowers: &LoanBook) -> Vec<Address> {
+        let mut ranked: Vec<(&Address, Decimal)> = borrowers
+            .iter()
+            .filter_map(|(address, loan)| {
+                reference_liquidation_price(loan).map(|price| (address, price))
+            })
+            .collect();
+        // the higher the liquidation price, the smaller the drop which triggers it
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        let mut sample: Vec<Address> = ranked
+            .into_iter()
+            .take(self.priority)
+            .map(|(address, _)| address.clone())
+            .collect();
+
+        let after_cursor = match &self.cursor {
+            Some(cursor) => borrowers.range::<_, Address>((Excluded(cursor), Unbounded)),
+            None => borrowers.range::<_, Address>(..),
+        };
+        let in_turn: Vec<Address> = after_cursor
+            .chain(borrowers.iter())
+            .map(|(address, _)| address)
+            .take(borrowers.len())
+            .filter(|address| !sample.contains(address))
+            .take(self.rotating)
+            .cloned()
+            .collect();
+        if let Some(last) = in_turn.last() {
+            self.cursor = Some(last.clone());
+        }
+        sample.extend(in_turn);
+        sample
+    }
+
+    /// Compares fetched on-chain loans, each read at a height, with their `cached` state
+    /// when sampled, and corrects the store where they differ. The ANC rewards of the loans
+    /// are refreshed as well. Only the loans read at the current height of the store are
+    /// compared, and the store leaves those modified since they were sampled untouched,
+    /// so both states are always from the same height.
+    /// The corrections are persisted before this returns.
+    pub async fn reconcile(
+        &self,
+        fetched: Vec<(Address, Loan, u64, Loan)>,
+    ) -> Result<Vec<LoanDrift>> {
+        let height = self.store.height().await?;
+        let mut checked = 0;
+        let mut skipped = 0;
+        let mut drifts = HashMap::new();
+        let mut corrections = vec![];
+        for (address, cached, read_at, actual) in fetched {
+            if read_at != height {
+                debug!(
+                    "Loan of {} read at {} while the loan book is at {}",
+                    address, read_at, height
+                );
+                skipped += 1;
+                continue;
+            }
+            checked += 1;
+
+            let loan = if let Some(drift) = drift(&address, height, &cached, &actual) {
+                debug!("Loan of {} drifted: {:?}", address, drift);
+                drifts.insert(address.clone(), drift);
+                Loan {
+                    last_updated: cached.last_updated.clone(),
+                    rewards: actual.rewards.or_else(|| cached.rewards.clone()),
+                    ..actual
+                }
+            } else if actual.rewards.is_some() && actual.rewards != cached.rewards {
+                // rewards accrue every block without any event, so they're refreshed here
+                Loan {
+                    rewards: actual.rewards,
+                    ..cached.clone()
+                }
+            } else {
+                continue;
+            };
+            corrections.push(Correction {
+                address,
+                expected: cached,
+                loan,
+            });
+        }
+
+        let loans: HashMap<Address, Loan> = corrections
+            .iter()
+            .map(|correction| (correction.address.clone(), correction.loan.clone()))
+            .collect();
+        let corrected = if corrections.is_empty() {
+            vec![]
+        } else {
+            self.store.correct(height, corrections).await?
+        };
+        if let Some(persistence) = self.persistence.as_ref().filter(|_| !corrected.is_empty()) {
+            let corrected = corrected
+                .iter()
+                .map(|address| (address.clone(), loans[address].clone()))
+                .collect();
+            persistence
+                .lock()
+                .await
+                .append_correction(height, corrected)?;
+        }
+        let drifts: Vec<LoanDrift> = corrected
+            .iter()
+            .filter_map(|address| drifts.remove(address))
+            .collect();
+        let mut stats = self.stats.write().await;
+        stats.checked += checked;
+        stats.skipped += skipped;
+        drifts.iter().cloned().for_each(|d| stats.record(d));
+        Ok(drifts)
+    }
+}
+
+/// The bLUNA liquidation price of a loan at the default bETH price and max LTVs,
+/// or `None` if it can't be liquidated by a bLUNA price drop.
+/// Only used to rank the loans to check, so it doesn't follow whitelist changes.
+fn reference_liquidation_price(loan: &Loan) -> Option<Decimal> {
+    let bluna = loan.collaterals.get(&CollateralToken::Bluna)?;
+    if bluna.is_zero() {
+        return None;
+    }
+    let beth = loan
+        .collaterals
+        .get(&CollateralToken::Beth)
+        .copied()
+        .unwrap_or_default();
+    let max_ltvs = MaxLtvs::default();
+    match anchor::liquidation_price_multi(
+        &loan.amount.units(),
+        &beth.units(),
+        &Micro::new(DEFAULT_BETH_PRICE).units(),
+        &max_ltvs.get(CollateralToken::Beth),
+        &bluna.units(),
+        &max_ltvs.get(CollateralToken::Bluna),
+    ) {
+        anchor::LiquidationPrice::Price(price) => Some(price),
+        anchor::LiquidationPrice::NeverLiquidatable
+        | anchor::LiquidationPrice::AlreadyLiquidatable
+        | anchor::LiquidationPrice::Overflow => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{borrower, loan, loan_book};
+    use crate::cache::Rewards;
+    use crate::persistence::Persistence;
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn samples_closest_to_liquidation_then_in_turn() {
+        let borrowers = loan_book(vec![
+            loan(1_200_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+            loan(600_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+            loan(3_000_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+        ]);
+        let store: Arc<dyn LoanStore> = Arc::new(MemoryStore::new());
+        let mut reconciler = Reconciler::with_sample(store, 1, 1);
+
+        assert_eq!(
+            vec![borrower(2), borrower(1)],
+            reconciler.sample(&borrowers)
+        );
+        assert_eq!(
+            vec![borrower(2), borrower(0)],
+            reconciler.sample(&borrowers)
+        );
+        // wraps around, skipping the priority borrower
+        assert_eq!(
+            vec![borrower(2), borrower(1)],
+            reconciler.sample(&borrowers)
+        );
+    }
+
+    #[tokio::test]
+    async fn corrections_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!(
+            "liquidation_monitor_reconcile_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let borrowers = loan_book(vec![loan(
+            1_200_000_000,
+            &[(CollateralToken::Bluna, 100_000_000)],
+        )]);
+        let mut persistence = Persistence::open(&dir, 10).unwrap();
+        persistence.snapshot(42, &borrowers).unwrap();
+
+        let store: Arc<dyn LoanStore> = Arc::new(MemoryStore::new());
+        store.load(42, borrowers).await.unwrap();
+        let persistence = Arc::new(tokio::sync::Mutex::new(persistence));
+        let reconciler = Reconciler::new(store.clone()).with_persistence(persistence.clone());
+        reconciler
+            .reconcile(vec![(
+                borrower(0),
+                loan(1_200_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+                42,
+                loan(1_000_000_000, &[(CollateralToken::Bluna, 150_000_000)]),
+            )])
+            .await
+            .unwrap();
+        drop(reconciler);
+        drop(persistence);
+
+        let restored = Persistence::open(&dir, 10)
+            .unwrap()
+            .restore()
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, restored.height);
+        assert_eq!(
+            Some(&loan(
+                1_000_000_000,
+                &[(CollateralToken::Bluna, 150_000_000)]
+            )),
+            restored.borrowers.get(&borrower(0))
+        );
+    }
+
+    fn rewarded(mut loan: Loan) -> Loan {
+        loan.rewards = Some(Rewards {
+            pending: Micro::new(7_000_000),
+            reward_index: Decimal::new(15, 1),
+            height: 42,
+        });
+        loan
+    }
+
+    #[tokio::test]
+    async fn corrects_drifted_loans() {
+        let store: Arc<dyn LoanStore> = Arc::new(MemoryStore::new());
+        let borrowers = loan_book(vec![
+            loan(1_200_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+            loan(600_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+            loan(3_000_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+        ]);
+        store.load(42, borrowers).await.unwrap();
+        let reconciler = Reconciler::new(store.clone());
+
+        let drifts = reconciler
+            .reconcile(vec![
+                (
+                    borrower(0),
+                    loan(1_200_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+                    42,
+                    loan(1_000_000_000, &[(CollateralToken::Bluna, 150_000_000)]),
+                ),
+                // Mantle lags behind
+                (
+                    borrower(1),
+                    loan(600_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+                    41,
+                    loan(0, &[(CollateralToken::Bluna, 0)]),
+                ),
+                (
+                    borrower(2),
+                    loan(3_000_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+                    42,
+                    rewarded(loan(
+                        3_000_000_000,
+                        &[(CollateralToken::Bluna, 100_000_000)],
+                    )),
+                ),
+            ])
+            .await
+            .unwrap();
+
+        let mut collaterals = HashMap::new();
+        collaterals.insert(CollateralToken::Bluna, Micro::new(50_000_000));
+        assert_eq!(
+            vec![LoanDrift {
+                address: borrower(0),
+                height: 42,
+                debt: Micro::new(-200_000_000),
+                collaterals,
+            }],
+            drifts
+        );
+        assert_eq!(
+            Some(loan(
+                1_000_000_000,
+                &[(CollateralToken::Bluna, 150_000_000)]
+            )),
+            store.get(&borrower(0)).await.unwrap()
+        );
+        assert_eq!(
+            Some(loan(600_000_000, &[(CollateralToken::Bluna, 100_000_000)])),
+            store.get(&borrower(1)).await.unwrap()
+        );
+        // only the rewards were refreshed
+        assert_eq!(
+            Some(rewarded(loan(
+                3_000_000_000,
+                &[(CollateralToken::Bluna, 100_000_000)]
+            ))),
+            store.get(&borrower(2)).await.unwrap()
+        );
+        // corrections are part of the history
+        assert_eq!(
+            Some(loan(
+                1_000_000_000,
+                &[(CollateralToken::Bluna, 150_000_000)]
+            )),
+            store.loan_at(&borrower(0), 42).await.unwrap()
+        );
+        let history = store.history(&borrower(0)).await.unwrap().unwrap();
+        assert_eq!(
+            vec![
+                (None, Micro::new(-200_000_000)),
+                (Some(CollateralToken::Bluna), Micro::new(50_000_000)),
+            ],
+            history
+                .events
+                .iter()
+                .rev()
+                .map(|entry| (entry.contract_address, entry.delta))
+                .collect::<Vec<_>>()
+        );
+
+        let stats = reconciler.stats();
+        {
+            let stats = stats.read().await;
+            assert_eq!(
+                (2, 1, 0, 1),
+                (stats.checked, stats.drifted, stats.errors, stats.skipped)
+            );
+            assert_eq!(Micro::new(200_000_000), stats.debt_delta);
+            assert_eq!(
+                Some(&Micro::new(50_000_000)),
+                stats.collateral_delta.get(&CollateralToken::Bluna)
+            );
+        }
+
+        // the loan was modified since it was sampled, so the correction would overwrite it
+        let drifts = reconciler
+            .reconcile(vec![(
+                borrower(1),
+                loan(500_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+                42,
+                loan(0, &[(CollateralToken::Bluna, 0)]),
+            )])
+            .await
+            .unwrap();
+        assert!(drifts.is_empty());
+        assert_eq!(
+            Some(loan(600_000_000, &[(CollateralToken::Bluna, 100_000_000)])),
+            store.get(&borrower(1)).await.unwrap()
+        );
+        assert_eq!(1, stats.read().await.drifted);
+    }
+}
@@ -0,0 +1,276 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+
+use crate::cache::{Loan, LoanBook};
+use crate::oracle::{Prices, SharedPrices};
+use crate::storage::LoanStore;
+use crate::types::{Address, Micro};
+use crate::whitelist::{MaxLtvs, SharedMaxLtvs};
+
+/// Default delay between two checks for new prices or blocks to rank loans at
+pub const RISK_INTERVAL: Duration = Duration::from_secs(6);
+
+pub type SharedRiskIndex = Arc<RwLock<RiskIndex>>;
+
+/// How close a loan is to liquidation, at given prices
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoanRisk {
+    pub address: Address,
+    pub debt: Micro,
+    pub borrow_limit: Decimal,
+    /// Borrow limit over debt, the loan is liquidatable below 1
+    pub health_factor: Decimal,
+    /// The drop of every collateral price, in percent, which makes the loan liquidatable.
+    /// Negative once it is, `None` without any collateral value to drop.
+    pub price_drop: Option<Decimal>,
+    /// Borrow limit minus debt, in UST
+    pub buffer: Decimal,
+}
+
+impl LoanRisk {
+    /// The risk of a loan at `prices`, `None` without debt or if a collateral price is unknown
+    pub fn of(
+        address: &Address,
+        loan: &Loan,
+        prices: &Prices,
+        max_ltvs: &MaxLtvs,
+    ) -> Option<LoanRisk> {
+        if loan.amount.is_zero() || loan.amount.is_negative() {
+            return None;
+        }
+        let debt = loan.amount.units();
+        let borrow_limit = match loan.metrics(prices, max_ltvs) {
+            Ok(metrics) => metrics?.borrow_limit,
+            Err(e) => {
+                warn!("Error ranking {}: {}", address, e);
+                return None;
+            }
+        };
+        let ratios = || {
+            let health_factor = borrow_limit.checked_div(debt)?;
+            // the borrow limit is proportional to the prices, so it drops by the same percentage
+            let price_drop = if borrow_limit.is_zero() {
+                None
+            } else {
+                let ratio = debt.checked_div(borrow_limit)?;
+                Some((Decimal::ONE - ratio).checked_mul(Decimal::ONE_HUNDRED)?)
+            };
+            Some((health_factor, price_drop))
+        };
+        let (health_factor, price_drop) = match ratios() {
+            Some(ratios) => ratios,
+            None => {
+                warn!("Risk of {} overflows", address);
+                return None;
+            }
+        };
+        Some(LoanRisk {
+            address: address.clone(),
+            debt: loan.amount,
+            borrow_limit,
+            health_factor,
+            price_drop,
+            buffer: borrow_limit - debt,
+        })
+    }
+}
+
+/// Every loan with debt, ranked from the most to the least at risk
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RiskIndex {
+    /// Height of the loan book
+    pub height: u64,
+    /// Height of the prices
+    pub price_height: u64,
+    ranked: Vec<LoanRisk>,
+}
+
+impl RiskIndex {
+    pub fn build(height: u64, borrowers: &LoanBook, prices: &Prices, max_ltvs: &MaxLtvs) -> Self {
+        let mut ranked: Vec<LoanRisk> = borrowers
+            .iter()
+            .filter_map(|(address, loan)| LoanRisk::of(address, loan, prices, max_ltvs))
+            .collect();
+        // the price drop grows with the health factor, so both are sorted at once
+        ranked.sort_by(|a, b| {
+            a.health_factor
+                .cmp(&b.health_factor)
+                .then_with(|| a.address.cmp(&b.address))
+        });
+        RiskIndex {
+            height,
+            price_height: prices.height,
+            ranked,
+        }
+    }
+
+    /// Whether loans were ranked at oracle prices yet
+    pub fn is_ready(&self) -> bool {
+        self.price_height > 0
+    }
+
+    /// The `n` loans with the lowest health factor
+    pub fn most_at_risk(&self, n: usize) -> &[LoanRisk] {
+        &self.ranked[..n.min(self.ranked.len())]
+    }
+
+    /// The loans which a drop of every collateral price by `percent` makes liquidatable,
+    /// including those which already are
+    pub fn within(&self, percent: &Decimal) -> &[LoanRisk] {
+        let end = self
+            .ranked
+            .partition_point(|risk| risk.price_drop.is_none_or(|drop| drop <= *percent));
+        &self.ranked[..end]
+    }
+}
+
+/// Ranks the loans again whenever a block is applied or new oracle prices are read
+pub struct RiskMonitor {
+    store: Arc<dyn LoanStore>,
+    prices: SharedPrices,
+    max_ltvs: SharedMaxLtvs,
+    index: SharedRiskIndex,
+}
+
+impl RiskMonitor {
+    pub fn new(store: Arc<dyn LoanStore>, prices: SharedPrices, max_ltvs: SharedMaxLtvs) -> Self {
+        RiskMonitor {
+            store,
+            prices,
+            max_ltvs,
+            index: Arc::new(RwLock::new(RiskIndex::default())),
+        }
+    }
+
+    pub fn index(&self) -> SharedRiskIndex {
+        self.index.clone()
+    }
+
+    /// Checks for new blocks or prices every `interval`, in the background
+    pub fn spawn(self, interval: Duration) {
+        info!("Ranking loans by health factor every {:?}", interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            let mut max_ltvs = MaxLtvs::default();
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.update(&mut max_ltvs).await {
+                    error!("Error ranking loans: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Ranks the loans again if anything changed since they were, given the `last_max_ltvs` they were ranked with
+    async fn update(&self, last_max_ltvs: &mut MaxLtvs) -> Result<()> {
+        let prices = self.prices.read().await.clone();
+        if prices.height == 0 {
+            return Ok(());
+        }
+        let height = self.store.height().await?;
+        let max_ltvs = self.max_ltvs.read().await.clone();
+        {
+            let index = self.index.read().await;
+            if index.height == height
+                && index.price_height == prices.height
+                && *last_max_ltvs == max_ltvs
+            {
+                return Ok(());
+            }
+        }
+        let borrowers = self.store.all().await?;
+        let index = RiskIndex::build(height, &borrowers, &prices, &max_ltvs);
+        debug!(
+            "Ranked {} loans at height {} and prices of height {}",
+            index.ranked.len(),
+            height,
+            prices.height
+        );
+        *self.index.write().await = index;
+        *last_max_ltvs = max_ltvs;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{borrower, loan, loan_book, BORROWERS};
+    use crate::types::CollateralToken;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn ranks_loans_by_health_factor() {
+        let loan = |amount, bluna| loan(amount, &[(CollateralToken::Bluna, bluna)]);
+        let borrowers = loan_book(vec![
+            // limit 100 * 40 * 0.6 = 2,400
+            loan(1_200_000_000, 100_000_000),
+            loan(2_000_000_000, 100_000_000),
+            loan(3_000_000_000, 100_000_000),
+            loan(1_000_000, 0),
+            loan(0, 100_000_000),
+        ]);
+        let mut prices = Prices {
+            height: 42,
+            prices: BTreeMap::new(),
+        };
+        prices
+            .prices
+            .insert(CollateralToken::Bluna, Decimal::new(40, 0));
+        let index = RiskIndex::build(41, &borrowers, &prices, &MaxLtvs::default());
+        assert!(index.is_ready());
+
+        let ranked: Vec<&str> = index
+            .most_at_risk(10)
+            .iter()
+            .map(|risk| risk.address.as_str())
+            .collect();
+        assert_eq!(
+            vec![BORROWERS[3], BORROWERS[2], BORROWERS[1], BORROWERS[0]],
+            ranked
+        );
+        let market = &index.most_at_risk(4)[3];
+        assert_eq!(Decimal::new(2, 0), market.health_factor);
+        assert_eq!(Some(Decimal::new(50, 0)), market.price_drop);
+        assert_eq!(Decimal::new(1_200, 0), market.buffer);
+        let liquidatable = &index.most_at_risk(2)[1];
+        assert_eq!(Some(Decimal::new(-25, 0)), liquidatable.price_drop);
+
+        assert_eq!(1, index.most_at_risk(1).len());
+        assert_eq!(2, index.within(&Decimal::ZERO).len());
+        // 2,000 / 2,400: a 16.67% drop
+        assert_eq!(2, index.within(&Decimal::new(16, 0)).len());
+        assert_eq!(3, index.within(&Decimal::new(17, 0)).len());
+        assert_eq!(4, index.within(&Decimal::ONE_HUNDRED).len());
+    }
+
+    #[test]
+    fn skips_loans_whose_risk_overflows() {
+        let loan = |amount| {
+            loan(
+                amount,
+                &[(CollateralToken::Bluna, 1_000_000_000_000_000_000)],
+            )
+        };
+        let mut prices = Prices::default();
+        let risk = |amount, price, prices: &mut Prices| {
+            prices.prices.insert(CollateralToken::Bluna, price);
+            LoanRisk::of(&borrower(0), &loan(amount), prices, &MaxLtvs::default())
+        };
+
+        // a borrow limit of 6e27 UST over 1 uusd of debt
+        assert_eq!(
+            None,
+            risk(1, Decimal::new(10_000_000_000_000_000, 0), &mut prices)
+        );
+        // 9e12 UST of debt over a borrow limit of 6e-17 UST
+        assert_eq!(None, risk(i64::MAX, Decimal::new(1, 28), &mut prices));
+        assert!(risk(1_000_000, Decimal::ONE, &mut prices).is_some());
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::anchor;
+use crate::cache::LoanBook;
+use crate::oracle::Prices;
+use crate::types::{Address, CollateralToken, Micro};
+use crate::whitelist::{parse_max_ltv, MaxLtvs, WhitelistUpdate};
+
+/// Default number of the largest affected loans reported
+pub const DEFAULT_TOP: usize = 10;
+
+/// A set of shocks to apply to the loan book at once. Tokens are keyed by contract address.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Scenario {
+    /// Prices to start from instead of the latest oracle prices, in UST
+    #[serde(default)]
+    pub prices: BTreeMap<CollateralToken, Decimal>,
+    /// Price moves in percent, e.g. `-20` for a 20% drop
+    #[serde(default)]
+    pub price_moves: BTreeMap<CollateralToken, Decimal>,
+    /// Max LTVs replacing the current ones
+    #[serde(default)]
+    pub max_ltvs: BTreeMap<CollateralToken, Decimal>,
+    /// Interest added to every debt, in percent
+    #[serde(default)]
+    pub interest: Decimal,
+    /// Number of the largest affected loans to report, `DEFAULT_TOP` if not set
+    #[serde(default)]
+    pub top: Option<usize>,
+}
+
+/// A loan liquidatable in a scenario
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AffectedLoan {
+    pub address: Address,
+    /// Including the added interest
+    pub debt: Micro,
+    pub borrow_limit: Decimal,
+    pub collaterals: HashMap<CollateralToken, Micro>,
+}
+
+/// What a scenario liquidates
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScenarioResult {
+    /// The prices after the shocks
+    pub prices: BTreeMap<CollateralToken, Decimal>,
+    pub borrowers: u64,
+    /// Debt of the liquidatable loans, including the added interest
+    pub debt: Micro,
+    /// Collateral of the liquidatable loans, by token
+    pub collateral: BTreeMap<CollateralToken, Micro>,
+    /// The liquidatable loans with the largest debt, largest first
+    pub largest: Vec<AffectedLoan>,
+}
+
+/// Applies `scenario` to `borrowers`, starting from `prices` and `max_ltvs`.
+/// A loan is liquidatable once its borrow limit at the shocked prices is below its debt.
+/// Fails if a shock is out of range, or takes an amount out of the range of `Decimal`.
+pub fn run(
+    borrowers: &LoanBook,
+    prices: &Prices,
+    max_ltvs: &MaxLtvs,
+    scenario: &Scenario,
+) -> Result<ScenarioResult> {
+    let mut shocked = prices.prices.clone();
+    for (token, price) in &scenario.prices {
+        if *price <= Decimal::ZERO {
+            return Err(anyhow!("Price {} of {} must be positive", price, token));
+        }
+        shocked.insert(*token, *price);
+    }
+    for (token, change) in &scenario.price_moves {
+        if *change < -Decimal::ONE_HUNDRED {
+            return Err(anyhow!("Price move {}% of {} out of range", change, token));
+        }
+        let price = shocked
+            .get_mut(token)
+            .ok_or_else(|| anyhow!("Missing price of {}", token))?;
+        *price = change
+            .checked_div(Decimal::ONE_HUNDRED)
+            .and_then(|change| Decimal::ONE.checked_add(change))
+            .and_then(|factor| price.checked_mul(factor))
+            .ok_or_else(|| anyhow!("Price of {} overflows with a {}% move", token, change))?;
+    }
+
+    let mut max_ltvs = max_ltvs.clone();
+    for (token, max_ltv) in &scenario.max_ltvs {
+        let update = WhitelistUpdate {
+            collateral_token: *token,
+            max_ltv: parse_max_ltv(&max_ltv.to_string())?,
+        };
+        max_ltvs.apply(u64::MAX, &update);
+    }
+
+    if scenario.interest.is_sign_negative() {
+        return Err(anyhow!(
+            "Added interest {}% can't be negative",
+            scenario.interest
+        ));
+    }
+    let accrued = scenario
+        .interest
+        .checked_div(Decimal::ONE_HUNDRED)
+        .and_then(|interest| Decimal::ONE.checked_add(interest))
+        .ok_or_else(|| anyhow!("Added interest {}% out of range", scenario.interest))?;
+
+    let mut affected = vec![];
+    for (address, loan) in borrowers {
+        let values = loan
+            .collaterals
+            .iter()
+            .map(|(token, amount)| {
+                let price = shocked
+                    .get(token)
+                    .ok_or_else(|| anyhow!("Missing price of {}", token))?;
+                let value = amount.units().checked_mul(*price).ok_or_else(|| {
+                    anyhow!("Collateral value of {} overflows for {}", token, address)
+                })?;
+                Ok((value, max_ltvs.get(*token)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let borrow_limit = anchor::checked_borrow_limit(values)
+            .ok_or_else(|| anyhow!("Borrow limit overflows for {}", address))?;
+        let debt = loan
+            .amount
+            .units()
+            .checked_mul(accrued)
+            .map(Micro::from_units)
+            .ok_or_else(|| anyhow!("Debt with the added interest overflows for {}", address))?;
+        if borrow_limit < debt.units() {
+            affected.push(AffectedLoan {
+                address: address.clone(),
+                debt,
+                borrow_limit,
+                collaterals: loan.collaterals.clone(),
+            });
+        }
+    }
+
+    let collateral = affected
+        .iter()
+        .flat_map(|loan| &loan.collaterals)
+        .try_fold(BTreeMap::new(), |mut collateral, (token, amount)| {
+            let total: &mut Micro = collateral.entry(*token).or_default();
+            *total = total.checked_add(*amount)?;
+            Some(collateral)
+        })
+        .ok_or_else(|| anyhow!("Collateral of the liquidatable loans overflows"))?;
+    let borrowers = affected.len() as u64;
+    let debt = affected
+        .iter()
+        .try_fold(Decimal::ZERO, |debt, loan| {
+            debt.checked_add(loan.debt.units())
+        })
+        .map(Micro::from_units)
+        .ok_or_else(|| anyhow!("Debt of the liquidatable loans overflows"))?;
+    affected.sort_by(|a, b| b.debt.cmp(&a.debt).then_with(|| a.address.cmp(&b.address)));
+    affected.truncate(scenario.top.unwrap_or(DEFAULT_TOP));
+    Ok(ScenarioResult {
+        prices: shocked,
+        borrowers,
+        debt,
+        collateral,
+        largest: affected,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{loan, loan_book, BORROWERS};
+    use crate::mantle::Contracts;
+
+    #[test]
+    fn runs_scenarios_over_the_loan_book() {
+        let loan = |amount, bluna, beth| {
+            loan(
+                amount,
+                &[
+                    (CollateralToken::Bluna, bluna),
+                    (CollateralToken::Beth, beth),
+                ],
+            )
+        };
+        let borrowers = loan_book(vec![
+            // limit 100 * 40 * 0.6 + 1 * 2,000 * 0.6 = 3,600
+            loan(3_000_000_000, 100_000_000, 1_000_000),
+            // limit 2,400
+            loan(1_500_000_000, 100_000_000, 0),
+            // limit 1,200
+            loan(1_000_000_000, 0, 1_000_000),
+        ]);
+        let mut prices = Prices::default();
+        prices
+            .prices
+            .insert(CollateralToken::Bluna, Decimal::new(40, 0));
+        prices
+            .prices
+            .insert(CollateralToken::Beth, Decimal::new(2_000, 0));
+        let max_ltvs = MaxLtvs::default();
+
+        let calm = run(&borrowers, &prices, &max_ltvs, &Scenario::default()).unwrap();
+        assert_eq!(0, calm.borrowers);
+
+        let scenario: Scenario = serde_json::from_str(&format!(
+            r#"{{"price_moves":{{"{}":"-20"}},"interest":"5","top":1}}"#,
+            Contracts::BLUNA
+        ))
+        .unwrap();
+        // limits of 3,120, 1,920 and 1,200 against debts of 3,150, 1,575 and 1,050
+        let result = run(&borrowers, &prices, &max_ltvs, &scenario).unwrap();
+        assert_eq!(Decimal::new(32, 0), result.prices[&CollateralToken::Bluna]);
+        assert_eq!(1, result.borrowers);
+        assert_eq!(Micro::new(3_150_000_000), result.debt);
+        assert_eq!(
+            Micro::new(100_000_000),
+            result.collateral[&CollateralToken::Bluna]
+        );
+
+        let mut max_ltvs = BTreeMap::new();
+        max_ltvs.insert(CollateralToken::Beth, Decimal::new(4, 1));
+        let scenario = Scenario {
+            max_ltvs,
+            top: Some(1),
+            ..scenario
+        };
+        // bETH now backs 800 per unit: limits of 2,720, 1,920 and 800
+        let result = run(&borrowers, &prices, &MaxLtvs::default(), &scenario).unwrap();
+        assert_eq!(2, result.borrowers);
+        assert_eq!(Micro::new(4_200_000_000), result.debt);
+        assert_eq!(1, result.largest.len());
+        assert_eq!(BORROWERS[0], result.largest[0].address.as_str());
+
+        let crash = Scenario {
+            price_moves: vec![(CollateralToken::Beth, Decimal::new(-101, 0))]
+                .into_iter()
+                .collect(),
+            ..Scenario::default()
+        };
+        assert!(run(&borrowers, &prices, &MaxLtvs::default(), &crash).is_err());
+    }
+
+    #[test]
+    fn rejects_scenarios_out_of_range() {
+        let borrowers = loan_book(vec![loan(
+            1_000_000_000,
+            &[(CollateralToken::Bluna, 100_000_000)],
+        )]);
+        let mut prices = Prices::default();
+        prices
+            .prices
+            .insert(CollateralToken::Bluna, Decimal::new(40, 0));
+        let run = |scenario: &str| {
+            let scenario =
+                serde_json::from_str(&scenario.replace("BLUNA", Contracts::BLUNA)).unwrap();
+            run(&borrowers, &prices, &MaxLtvs::default(), &scenario)
+        };
+
+        assert!(run(r#"{"interest":"10000000000000000000000000000"}"#).is_err());
+        assert!(run(r#"{"interest":1e27}"#).is_ok());
+        // 100 bLUNA at $1e27
+        assert!(run(r#"{"prices":{"BLUNA":"1000000000000000000000000000"}}"#).is_err());
+        assert!(run(
+            r#"{"prices":{"BLUNA":"1000000000000000000000000000"},"price_moves":{"BLUNA":"10000"}}"#
+        )
+        .is_err());
+        assert!(run(r#"{"prices":{"BLUNA":"0"}}"#).is_err());
+        assert!(run(r#"{"prices":{"BLUNA":"-1"}}"#).is_err());
+        assert_eq!(1, run(r#"{"prices":{"BLUNA":"1"}}"#).unwrap().borrowers);
+    }
+
+    #[test]
+    fn fails_when_the_liquidated_collateral_overflows() {
+        let loan = || {
+            let mut loan = loan(1_000_000_000, &[]);
+            loan.collaterals.insert(
+                CollateralToken::Bluna,
+                Micro::from_units(Decimal::MAX * Decimal::new(6, 1)),
+            );
+            loan
+        };
+        let mut prices = Prices::default();
+        // each loan's collateral is worth about $5 against a debt of 1,000
+        prices
+            .prices
+            .insert(CollateralToken::Bluna, Decimal::new(1, 28));
+        let run = |borrowers| {
+            run(
+                &borrowers,
+                &prices,
+                &MaxLtvs::default(),
+                &Scenario::default(),
+            )
+        };
+
+        assert_eq!(1, run(loan_book(vec![loan()])).unwrap().borrowers);
+        assert!(run(loan_book(vec![loan(), loan()])).is_err());
+    }
+}
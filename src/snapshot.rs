@@ -0,0 +1,597 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::cache::{Loan, LoanBook, Provenance, Rewards};
+use crate::mantle::Contracts;
+use crate::types::{Address, CollateralToken, Micro};
+
+/// Version of the snapshot format written by this build.
+/// Version 2 added the ANC rewards of the borrowers.
+pub const FORMAT_VERSION: u32 = 2;
+/// The chain the loan book is tracked on
+pub const CHAIN_ID: &str = "columbus-5";
+
+/// Leading bytes of the binary encoding
+const MAGIC: &[u8; 4] = b"LMSB";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Json,
+    /// Compact encoding, much faster to load than JSON
+    Binary,
+}
+
+impl SnapshotFormat {
+    /// Binary for `.bin` files, JSON otherwise
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("bin") => SnapshotFormat::Binary,
+            _ => SnapshotFormat::Json,
+        }
+    }
+}
+
+/// The Anchor contracts a loan book was built from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContractSet {
+    pub market: String,
+    pub overseer: String,
+    pub bluna: String,
+    pub beth: String,
+}
+
+impl ContractSet {
+    pub fn current() -> Self {
+        ContractSet {
+            market: Contracts::MARKET.to_string(),
+            overseer: Contracts::OVERSEER.to_string(),
+            bluna: Contracts::BLUNA.to_string(),
+            beth: Contracts::BETH.to_string(),
+        }
+    }
+}
+
+/// The full loan book at the end of a given block, as seeded or persisted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub chain_id: String,
+    pub height: u64,
+    /// When the snapshot was taken
+    pub timestamp: DateTime<Utc>,
+    pub contracts: ContractSet,
+    /// SHA-256 of the height and borrowers, see `content_hash`
+    pub hash: String,
+    pub borrowers: LoanBook,
+}
+
+impl Snapshot {
+    /// A snapshot of `borrowers` at `height`, taken now on the current chain and contracts
+    pub fn new(height: u64, borrowers: LoanBook) -> Self {
+        Snapshot {
+            version: FORMAT_VERSION,
+            chain_id: CHAIN_ID.to_string(),
+            height,
+            timestamp: Utc::now(),
+            contracts: ContractSet::current(),
+            hash: content_hash(height, &borrowers),
+            borrowers,
+        }
+    }
+
+    /// Checks the snapshot can be loaded: known format, same chain and contracts,
+    /// no negative amounts and an intact content.
+    /// Unknown collateral tokens are already rejected when decoding.
+    pub fn validate(&self) -> Result<()> {
+        if self.version == 0 || self.version > FORMAT_VERSION {
+            return Err(anyhow!(
+                "Unsupported snapshot format version {}",
+                self.version
+            ));
+        }
+        if self.chain_id != CHAIN_ID {
+            return Err(anyhow!(
+                "Snapshot of chain {} can't be loaded on {}",
+                self.chain_id,
+                CHAIN_ID
+            ));
+        }
+        if self.contracts != ContractSet::current() {
+            return Err(anyhow!(
+                "Snapshot was taken with other Anchor contracts: {:?}",
+                self.contracts
+            ));
+        }
+        for (address, loan) in &self.borrowers {
+            if loan.amount.is_negative()
+                || loan.collaterals.values().any(|c| c.is_negative())
+                || loan
+                    .rewards
+                    .as_ref()
+                    .is_some_and(|r| r.pending.is_negative())
+            {
+                return Err(anyhow!("Negative amount in the loan of {}", address));
+            }
+        }
+        if self.hash != content_hash(self.height, &self.borrowers) {
+            return Err(anyhow!("Snapshot hash mismatch, it may be corrupt"));
+        }
+        Ok(())
+    }
+
+    /// Reads and validates a snapshot in either encoding.
+    /// Unversioned files (a bare map of the borrowers, optionally with their height)
+    /// are still accepted, but their chain and integrity can't be checked.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).with_context(|| format!("Error reading snapshot {:?}", path))?;
+
+        let snapshot = if bytes.starts_with(MAGIC) {
+            Snapshot::decode(&bytes)
+        } else {
+            parse_json(&bytes)
+        }
+        .with_context(|| format!("Error reading snapshot {:?}", path))?;
+        snapshot
+            .validate()
+            .with_context(|| format!("Invalid snapshot {:?}", path))?;
+        Ok(snapshot)
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P, format: SnapshotFormat) -> Result<()> {
+        write_file(path.as_ref(), &SnapshotRef::from(self), format)
+    }
+
+    /// The binary encoding of the snapshot
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        SnapshotRef::from(self).encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut r = Reader(bytes);
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(anyhow!("Not a binary snapshot"));
+        }
+        let version = r.u32()?;
+        if version > FORMAT_VERSION {
+            return Err(anyhow!("Unsupported snapshot format version {}", version));
+        }
+        let chain_id = r.str()?.to_string();
+        let height = r.u64()?;
+        let timestamp = Utc
+            .timestamp_millis_opt(r.i64()?)
+            .single()
+            .ok_or_else(|| anyhow!("Invalid snapshot timestamp"))?;
+        let contracts = ContractSet {
+            market: r.str()?.to_string(),
+            overseer: r.str()?.to_string(),
+            bluna: r.str()?.to_string(),
+            beth: r.str()?.to_string(),
+        };
+        let hash = r.str()?.to_string();
+
+        let mut borrowers = LoanBook::new();
+        for _ in 0..r.u32()? {
+            let address: Address = r.str()?.parse()?;
+            let amount = Micro::new(r.i64()?);
+            let mut collaterals = HashMap::new();
+            for _ in 0..r.u8()? {
+                let token = *CollateralToken::ALL.get(r.u8()? as usize).ok_or_else(|| {
+                    anyhow!("Unknown collateral token in the loan of {}", address)
+                })?;
+                collaterals.insert(token, Micro::new(r.i64()?));
+            }
+            let last_updated = match r.u8()? {
+                0 => None,
+                _ => Some(Provenance {
+                    height: r.u64()?,
+                    txhash: r.str()?.to_string(),
+                }),
+            };
+            let rewards = match version {
+                1 => None,
+                _ => match r.u8()? {
+                    0 => None,
+                    _ => Some(Rewards {
+                        pending: Micro::new(r.i64()?),
+                        reward_index: r.str()?.parse()?,
+                        height: r.u64()?,
+                    }),
+                },
+            };
+            borrowers.insert(
+                address,
+                Loan {
+                    amount,
+                    collaterals,
+                    last_updated,
+                    rewards,
+                },
+            );
+        }
+        if !r.0.is_empty() {
+            return Err(anyhow!("Trailing bytes after the snapshot"));
+        }
+
+        Ok(Snapshot {
+            version,
+            chain_id,
+            height,
+            timestamp,
+            contracts,
+            hash,
+            borrowers,
+        })
+    }
+}
+
+/// Writes a snapshot of `borrowers` at `height` taken now, without copying them.
+/// The file is replaced atomically, so a crash leaves either the old or the new snapshot.
+pub fn write_snapshot<P: AsRef<Path>>(
+    path: P,
+    height: u64,
+    borrowers: &LoanBook,
+    format: SnapshotFormat,
+) -> Result<()> {
+    let hash = content_hash(height, borrowers);
+    let contracts = ContractSet::current();
+    let snapshot = SnapshotRef {
+        version: FORMAT_VERSION,
+        chain_id: CHAIN_ID,
+        height,
+        timestamp: Utc::now(),
+        contracts: &contracts,
+        hash: &hash,
+        borrowers,
+    };
+    write_file(path.as_ref(), &snapshot, format)
+}
+
+/// SHA-256 of the height and borrowers, with amounts in micro-units and collaterals sorted,
+/// so it doesn't depend on the encoding
+pub fn content_hash(height: u64, borrowers: &LoanBook) -> String {
+    let canonical: Vec<_> = borrowers
+        .iter()
+        .map(|(address, loan)| {
+            let collaterals: BTreeMap<CollateralToken, i128> = loan
+                .collaterals
+                .iter()
+                .map(|(token, amount)| (*token, amount.micro_units()))
+                .collect();
+            let amount = loan.amount.micro_units();
+            match &loan.rewards {
+                None => {
+                    CanonicalLoan::WithoutRewards(address, amount, collaterals, &loan.last_updated)
+                }
+                Some(rewards) => CanonicalLoan::WithRewards(
+                    address,
+                    amount,
+                    collaterals,
+                    &loan.last_updated,
+                    (
+                        rewards.pending.micro_units(),
+                        rewards.reward_index.normalize().to_string(),
+                        rewards.height,
+                    ),
+                ),
+            }
+        })
+        .collect();
+    let bytes = serde_json::to_vec(&(height, canonical)).expect("Error serializing borrowers");
+    format!("{:x}", Sha256::digest(&bytes))
+}
+
+/// A loan as hashed. Loans without rewards hash as in version 1,
+/// so older snapshots stay valid.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum CanonicalLoan<'a> {
+    WithoutRewards(
+        &'a Address,
+        i128,
+        BTreeMap<CollateralToken, i128>,
+        &'a Option<Provenance>,
+    ),
+    WithRewards(
+        &'a Address,
+        i128,
+        BTreeMap<CollateralToken, i128>,
+        &'a Option<Provenance>,
+        (i128, String, u64),
+    ),
+}
+
+fn parse_json(bytes: &[u8]) -> Result<Snapshot> {
+    let json: serde_json::Value = serde_json::from_slice(bytes)?;
+    if json.get("version").is_some() {
+        return Ok(serde_json::from_value(json)?);
+    }
+
+    warn!("Loading an unversioned snapshot, its chain and integrity can't be checked");
+    let (height, borrowers) = match json.get("borrowers") {
+        Some(borrowers) => (
+            json.get("height").and_then(|h| h.as_u64()).unwrap_or(0),
+            serde_json::from_value(borrowers.clone())?,
+        ),
+        None => (0, serde_json::from_value(json)?),
+    };
+    Ok(Snapshot::new(height, borrowers))
+}
+
+fn write_file(path: &Path, snapshot: &SnapshotRef, format: SnapshotFormat) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut writer = BufWriter::new(
+            File::create(&tmp).with_context(|| format!("Error creating snapshot {:?}", tmp))?,
+        );
+        match format {
+            SnapshotFormat::Json => serde_json::to_writer(&mut writer, snapshot)?,
+            SnapshotFormat::Binary => writer.write_all(&snapshot.encode()?)?,
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// A borrowed `Snapshot`, to write the live loan book without copying it
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    chain_id: &'a str,
+    height: u64,
+    timestamp: DateTime<Utc>,
+    contracts: &'a ContractSet,
+    hash: &'a str,
+    borrowers: &'a LoanBook,
+}
+
+impl<'a> From<&'a Snapshot> for SnapshotRef<'a> {
+    fn from(s: &'a Snapshot) -> Self {
+        SnapshotRef {
+            version: s.version,
+            chain_id: &s.chain_id,
+            height: s.height,
+            timestamp: s.timestamp,
+            contracts: &s.contracts,
+            hash: &s.hash,
+            borrowers: &s.borrowers,
+        }
+    }
+}
+
+impl SnapshotRef<'_> {
+    /// Little-endian integers, strings prefixed with their u16 length
+    /// and amounts as i64 micro-units
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut w = Writer(Vec::with_capacity(64 + self.borrowers.len() * 96));
+        w.0.extend_from_slice(MAGIC);
+        w.u32(self.version);
+        w.str(self.chain_id)?;
+        w.u64(self.height);
+        w.i64(self.timestamp.timestamp_millis());
+        w.str(&self.contracts.market)?;
+        w.str(&self.contracts.overseer)?;
+        w.str(&self.contracts.bluna)?;
+        w.str(&self.contracts.beth)?;
+        w.str(self.hash)?;
+
+        w.u32(u32::try_from(self.borrowers.len())?);
+        for (address, loan) in self.borrowers {
+            w.str(address)?;
+            w.micro(loan.amount)?;
+            w.u8(u8::try_from(loan.collaterals.len())?);
+            for (token, amount) in &loan.collaterals {
+                let index = CollateralToken::ALL
+                    .iter()
+                    .position(|t| t == token)
+                    .expect("Collateral token missing from the registry");
+                w.u8(index as u8);
+                w.micro(*amount)?;
+            }
+            match &loan.last_updated {
+                Some(provenance) => {
+                    w.u8(1);
+                    w.u64(provenance.height);
+                    w.str(&provenance.txhash)?;
+                }
+                None => w.u8(0),
+            }
+            if self.version >= 2 {
+                match &loan.rewards {
+                    Some(rewards) => {
+                        w.u8(1);
+                        w.micro(rewards.pending)?;
+                        w.str(&rewards.reward_index.to_string())?;
+                        w.u64(rewards.height);
+                    }
+                    None => w.u8(0),
+                }
+            }
+        }
+        Ok(w.0)
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn i64(&mut self, v: i64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn micro(&mut self, amount: Micro) -> Result<()> {
+        let micro = i64::try_from(amount.micro_units())
+            .map_err(|_| anyhow!("Amount {} is too large for a snapshot", amount))?;
+        self.i64(micro);
+        Ok(())
+    }
+
+    fn str(&mut self, s: &str) -> Result<()> {
+        self.0
+            .extend_from_slice(&u16::try_from(s.len())?.to_le_bytes());
+        self.0.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(anyhow!("Truncated snapshot"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(<[u8; N]>::try_from(self.take(N)?)?)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        let len = u16::from_le_bytes(self.array()?) as usize;
+        Ok(std::str::from_utf8(self.take(len)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{borrower, loan, loan_book};
+    use rust_decimal::Decimal;
+
+    fn borrowers() -> LoanBook {
+        let rewarded = Loan {
+            last_updated: Some(Provenance {
+                height: 99,
+                txhash: "TXHASH".to_string(),
+            }),
+            rewards: Some(Rewards {
+                pending: Micro::new(2_500_000),
+                reward_index: Decimal::new(1_234_567, 6),
+                height: 99,
+            }),
+            ..loan(
+                1_000_000_000,
+                &[
+                    (CollateralToken::Bluna, 100_000_000),
+                    (CollateralToken::Beth, 1_000),
+                ],
+            )
+        };
+        loan_book(vec![rewarded, loan(0, &[])])
+    }
+
+    #[test]
+    fn round_trips_both_encodings() {
+        let snapshot = Snapshot::new(100, borrowers());
+        snapshot.validate().unwrap();
+
+        let decoded = Snapshot::decode(&snapshot.encode().unwrap()).unwrap();
+        decoded.validate().unwrap();
+        assert_eq!(snapshot.borrowers, decoded.borrowers);
+        assert_eq!(
+            snapshot.timestamp.timestamp_millis(),
+            decoded.timestamp.timestamp_millis()
+        );
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let parsed = parse_json(json.as_bytes()).unwrap();
+        parsed.validate().unwrap();
+        assert_eq!(snapshot, parsed);
+        // written before the rewards were tracked
+        let borrowers = borrowers()
+            .into_iter()
+            .map(|(address, loan)| {
+                (
+                    address,
+                    Loan {
+                        rewards: None,
+                        ..loan
+                    },
+                )
+            })
+            .collect();
+        let mut v1 = Snapshot::new(100, borrowers);
+        v1.version = 1;
+        let decoded = Snapshot::decode(&v1.encode().unwrap()).unwrap();
+        decoded.validate().unwrap();
+        assert_eq!(v1.borrowers, decoded.borrowers);
+    }
+
+    #[test]
+    fn rejects_invalid_snapshots() {
+        let mut snapshot = Snapshot::new(100, borrowers());
+        snapshot.chain_id = "bombay-12".to_string();
+        assert!(snapshot.validate().is_err());
+
+        let mut snapshot = Snapshot::new(100, borrowers());
+        snapshot.height = 101;
+        assert!(snapshot.validate().is_err());
+
+        let mut borrowers = borrowers();
+        borrowers.get_mut(&borrower(0)).unwrap().amount = Micro::new(-1);
+        assert!(Snapshot::new(100, borrowers).validate().is_err());
+
+        let json = serde_json::to_string(&Snapshot::new(100, self::borrowers()))
+            .unwrap()
+            .replace(Contracts::BETH, Contracts::MARKET);
+        assert!(parse_json(json.as_bytes()).is_err());
+
+        let bytes = Snapshot::new(100, self::borrowers()).encode().unwrap();
+        assert!(Snapshot::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn loads_unversioned_seeds() {
+        let snapshot = parse_json(br#"{"terra1003n7ppfqy9fvv0xxr0n24aqrsh0pkhwcsyxrf": {"amount": "1", "collaterals": {}}}"#)
+            .unwrap();
+        assert_eq!(0, snapshot.height);
+        assert_eq!(
+            Micro::new(1_000_000),
+            snapshot.borrowers[&borrower(0)].amount
+        );
+        snapshot.validate().unwrap();
+    }
+}
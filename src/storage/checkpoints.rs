@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::cache::{BlockEvents, Borrowers, Loan, LoanBook};
+use crate::types::Address;
+
+/// Default number of blocks between two checkpoints (roughly one hour on Columbus-5)
+pub const CHECKPOINT_INTERVAL: u64 = 600;
+/// Default number of checkpoints retained, i.e. about two days of history
+pub const MAX_CHECKPOINTS: usize = 48;
+
+/// A modification of the loan book, replayed on top of the checkpoints
+enum Logged {
+    Block(BlockEvents),
+    /// Loans overwritten at a height with their on-chain state, see `LoanStore::correct`
+    Correction(u64, Vec<(Address, Loan)>),
+}
+
+impl Logged {
+    fn height(&self) -> u64 {
+        match self {
+            Logged::Block(block) => block.height,
+            Logged::Correction(height, _) => *height,
+        }
+    }
+
+    fn apply_to(&self, borrowers: &mut LoanBook) {
+        match self {
+            Logged::Block(block) => {
+                block.apply_to(borrowers);
+            }
+            Logged::Correction(_, loans) => {
+                for (address, loan) in loans {
+                    // replays narrowed to other loans leave it out
+                    if let Some(cached) = borrowers.get_mut(address) {
+                        *cached = loan.clone();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Versioned state of the loan book: periodic full checkpoints plus the log of every block
+/// and correction applied since the oldest one, from which the state at any retained height
+/// is replayed
+pub struct Checkpoints {
+    interval: u64,
+    max_checkpoints: usize,
+    /// Shared with the published versions of the loan book, so they cost no copy
+    checkpoints: VecDeque<(u64, Borrowers)>,
+    /// Blocks with events and corrections applied after the oldest checkpoint,
+    /// by ascending height
+    log: VecDeque<Logged>,
+    height: u64,
+}
+
+impl Default for Checkpoints {
+    fn default() -> Self {
+        Self::new(CHECKPOINT_INTERVAL, MAX_CHECKPOINTS)
+    }
+}
+
+impl Checkpoints {
+    pub fn new(interval: u64, max_checkpoints: usize) -> Self {
+        Checkpoints {
+            interval,
+            max_checkpoints: max_checkpoints.max(1),
+            checkpoints: VecDeque::new(),
+            log: VecDeque::new(),
+            height: 0,
+        }
+    }
+
+    /// Drops the whole history, starting over from `borrowers` as of `height`
+    pub fn reset(&mut self, height: u64, borrowers: &Borrowers) {
+        self.checkpoints.clear();
+        self.log.clear();
+        self.checkpoints.push_back((height, borrowers.clone()));
+        self.height = height;
+    }
+
+    /// Records a block which has just been applied, resulting in `borrowers`
+    pub fn record(&mut self, block: &BlockEvents, borrowers: &Borrowers) {
+        if !block.events.is_empty() {
+            self.log.push_back(Logged::Block(block.clone()));
+        }
+        self.height = block.height;
+
+        let last = self.checkpoints.back().map_or(0, |(height, _)| *height);
+        if block.height >= last + self.interval {
+            self.checkpoints
+                .push_back((block.height, borrowers.clone()));
+        }
+        while self.checkpoints.len() > self.max_checkpoints {
+            self.checkpoints.pop_front();
+        }
+        if let Some((oldest, _)) = self.checkpoints.front() {
+            while self.log.front().is_some_and(|l| l.height() <= *oldest) {
+                self.log.pop_front();
+            }
+        }
+    }
+
+    /// Records loans which have just been corrected at the current height, resulting in `borrowers`
+    pub fn record_correction(&mut self, loans: Vec<(Address, Loan)>, borrowers: &Borrowers) {
+        match self.checkpoints.back_mut() {
+            // the checkpoint holds the state at the end of its block, corrections included
+            Some((height, checkpoint)) if *height == self.height => *checkpoint = borrowers.clone(),
+            _ => self.log.push_back(Logged::Correction(self.height, loans)),
+        }
+    }
+
+    /// Height of the oldest retained state, `None` if nothing was recorded yet
+    pub fn oldest(&self) -> Option<u64> {
+        self.checkpoints.front().map(|(height, _)| *height)
+    }
+
+    /// The loan book as it was at the end of block `height`,
+    /// `None` if `height` is outside of the retained history
+    pub fn state_at(&self, height: u64) -> Option<Borrowers> {
+        let (checkpoint, borrowers) = self.closest(height)?;
+        if !self.log_between(checkpoint, height).any(|_| true) {
+            return Some(borrowers.clone());
+        }
+        self.replay(height, |borrowers| (**borrowers).clone())
+            .map(Arc::new)
+    }
+
+    /// The loan of `address` as it was at the end of block `height`,
+    /// `None` if `height` is outside of the retained history
+    pub fn loan_at(&self, address: &Address, height: u64) -> Option<Option<Loan>> {
+        self.replay(height, |borrowers| {
+            borrowers
+                .get(address)
+                .map(|loan| (address.clone(), loan.clone()))
+                .into_iter()
+                .collect()
+        })
+        .map(|mut borrowers| borrowers.remove(address))
+    }
+
+    /// Replays the log up to `height` on top of the closest checkpoint, narrowed by `select`
+    fn replay<F>(&self, height: u64, select: F) -> Option<LoanBook>
+    where
+        F: FnOnce(&Borrowers) -> LoanBook,
+    {
+        let (checkpoint, borrowers) = self.closest(height)?;
+
+        let mut borrowers = select(borrowers);
+        self.log_between(checkpoint, height)
+            .for_each(|l| l.apply_to(&mut borrowers));
+        Some(borrowers)
+    }
+
+    /// The closest checkpoint at or below `height`
+    fn closest(&self, height: u64) -> Option<(u64, &Borrowers)> {
+        if height > self.height {
+            return None;
+        }
+        self.checkpoints
+            .iter()
+            .rev()
+            .find(|(h, _)| *h <= height)
+            .map(|(h, borrowers)| (*h, borrowers))
+    }
+
+    /// The recorded blocks and corrections in `from + 1..=to`
+    fn log_between(&self, from: u64, to: u64) -> impl Iterator<Item = &Logged> {
+        self.log
+            .iter()
+            .skip_while(move |l| l.height() <= from)
+            .take_while(move |l| l.height() <= to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{borrower, loan, loan_book};
+    use crate::cache::{CacheEvent, TxEvent};
+    use crate::types::Micro;
+
+    fn borrow(height: u64, amount: i64) -> BlockEvents {
+        BlockEvents {
+            height,
+            timestamp: None,
+            events: vec![TxEvent {
+                txhash: format!("TXHASH{}", height),
+                event: CacheEvent::BorrowStable {
+                    address: borrower(0),
+                    amount: Micro::new(amount),
+                },
+            }],
+            whitelist: vec![],
+            queue: vec![],
+        }
+    }
+
+    fn amount_at(checkpoints: &Checkpoints, height: u64) -> Option<Micro> {
+        checkpoints
+            .loan_at(&borrower(0), height)
+            .map(|loan| loan.unwrap().amount)
+    }
+
+    #[test]
+    fn replays_state_at_height() {
+        let mut borrowers = loan_book(vec![loan(1_000_000, &[])]);
+        let mut checkpoints = Checkpoints::new(10, 2);
+        checkpoints.reset(100, &Arc::new(borrowers.clone()));
+
+        for height in 101..=125 {
+            let block = borrow(height, 1_000_000);
+            block.apply_to(&mut borrowers);
+            checkpoints.record(&block, &Arc::new(borrowers.clone()));
+        }
+
+        // checkpoints at 110 and 120 are retained, 100 was dropped
+        assert_eq!(Some(110), checkpoints.oldest());
+        assert_eq!(None, amount_at(&checkpoints, 109));
+        assert_eq!(Some(Micro::new(11_000_000)), amount_at(&checkpoints, 110));
+        assert_eq!(Some(Micro::new(18_000_000)), amount_at(&checkpoints, 117));
+        assert_eq!(Some(Micro::new(26_000_000)), amount_at(&checkpoints, 125));
+        assert_eq!(None, amount_at(&checkpoints, 126));
+        assert_eq!(Some(Arc::new(borrowers)), checkpoints.state_at(125));
+    }
+
+    #[test]
+    fn replays_corrections() {
+        let loan = |amount| loan(amount, &[]);
+        let mut borrowers = loan_book(vec![loan(1_000_000)]);
+        let mut checkpoints = Checkpoints::new(10, 2);
+        checkpoints.reset(100, &Arc::new(borrowers.clone()));
+
+        // on the checkpoint, then between two blocks
+        borrowers.insert(borrower(0), loan(5_000_000));
+        checkpoints.record_correction(
+            vec![(borrower(0), loan(5_000_000))],
+            &Arc::new(borrowers.clone()),
+        );
+        let block = borrow(101, 1_000_000);
+        block.apply_to(&mut borrowers);
+        checkpoints.record(&block, &Arc::new(borrowers.clone()));
+        borrowers.insert(borrower(0), loan(3_000_000));
+        checkpoints.record_correction(
+            vec![(borrower(0), loan(3_000_000))],
+            &Arc::new(borrowers.clone()),
+        );
+        let block = borrow(102, 1_000_000);
+        block.apply_to(&mut borrowers);
+        checkpoints.record(&block, &Arc::new(borrowers.clone()));
+
+        assert_eq!(Some(Micro::new(5_000_000)), amount_at(&checkpoints, 100));
+        assert_eq!(Some(Micro::new(3_000_000)), amount_at(&checkpoints, 101));
+        assert_eq!(Some(Micro::new(4_000_000)), amount_at(&checkpoints, 102));
+        assert_eq!(Some(Arc::new(borrowers)), checkpoints.state_at(102));
+    }
+}
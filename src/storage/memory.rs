@@ -0,0 +1,306 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::cache::{
+    AuditEntry, BlockEvents, Borrowers, Loan, LoanBook, LoanHistory, AUDIT_TRAIL_LEN,
+};
+use crate::storage::{Checkpoints, Correction, LoanStore};
+use crate::types::Address;
+
+/// The loan book as it was at the end of a block
+struct Version {
+    height: u64,
+    borrowers: Borrowers,
+}
+
+/// The default, process-local loan book.
+///
+/// A new immutable `Version` is published after each block, so readers get a consistent view
+/// without locking or copying, and the writer never waits on them.
+/// Each version shares every loan the block didn't touch with the previous one,
+/// so applying a block only copies the loans it modifies.
+pub struct MemoryStore {
+    current: ArcSwap<Version>,
+    /// Serializes the writers
+    writer: Mutex<()>,
+    /// The recent `AuditEntry`s of every loan, only kept since the process started
+    audit: RwLock<HashMap<Address, VecDeque<AuditEntry>>>,
+    checkpoints: RwLock<Checkpoints>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            current: ArcSwap::from_pointee(Version {
+                height: 0,
+                borrowers: Arc::new(LoanBook::new()),
+            }),
+            writer: Mutex::new(()),
+            audit: RwLock::new(HashMap::new()),
+            checkpoints: RwLock::new(Checkpoints::default()),
+        }
+    }
+
+    async fn record_audit(&self, entries: Vec<(Address, AuditEntry)>) {
+        let mut audit = self.audit.write().await;
+        for (address, entry) in entries {
+            let trail = audit.entry(address).or_default();
+            trail.push_front(entry);
+            trail.truncate(AUDIT_TRAIL_LEN);
+        }
+    }
+}
+
+#[async_trait]
+impl LoanStore for MemoryStore {
+    async fn height(&self) -> Result<u64> {
+        Ok(self.current.load().height)
+    }
+
+    async fn get(&self, address: &Address) -> Result<Option<Loan>> {
+        Ok(self.current.load().borrowers.get(address).cloned())
+    }
+
+    async fn all(&self) -> Result<Borrowers> {
+        Ok(self.current.load().borrowers.clone())
+    }
+
+    async fn load(&self, height: u64, borrowers: LoanBook) -> Result<()> {
+        let _writer = self.writer.lock().await;
+        let borrowers = Arc::new(borrowers);
+        self.checkpoints.write().await.reset(height, &borrowers);
+        self.audit.write().await.clear();
+        self.current.store(Arc::new(Version { height, borrowers }));
+        Ok(())
+    }
+
+    async fn apply_block(&self, block: &BlockEvents) -> Result<bool> {
+        let _writer = self.writer.lock().await;
+        let current = self.current.load_full();
+        if block.height <= current.height {
+            return Ok(false);
+        }
+
+        // the next version is a modified copy sharing the untouched loans,
+        // the current one stays untouched for its readers
+        let (borrowers, entries) = if block.events.is_empty() {
+            (current.borrowers.clone(), vec![])
+        } else {
+            let mut borrowers = (*current.borrowers).clone();
+            let entries = block.apply_to(&mut borrowers);
+            (Arc::new(borrowers), entries)
+        };
+        self.checkpoints.write().await.record(block, &borrowers);
+        self.current.store(Arc::new(Version {
+            height: block.height,
+            borrowers,
+        }));
+
+        self.record_audit(entries).await;
+        Ok(true)
+    }
+
+    async fn correct(&self, height: u64, corrections: Vec<Correction>) -> Result<Vec<Address>> {
+        let _writer = self.writer.lock().await;
+        let current = self.current.load_full();
+        if current.height != height {
+            return Ok(vec![]);
+        }
+
+        let mut borrowers = (*current.borrowers).clone();
+        let mut corrected = vec![];
+        let mut entries = vec![];
+        for correction in corrections {
+            if borrowers.get(&correction.address) != Some(&correction.expected) {
+                continue;
+            }
+            entries.extend(
+                AuditEntry::corrections(height, &correction.expected, &correction.loan)
+                    .into_iter()
+                    .map(|entry| (correction.address.clone(), entry)),
+            );
+            borrowers.insert(correction.address.clone(), correction.loan.clone());
+            corrected.push((correction.address, correction.loan));
+        }
+        if corrected.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let borrowers = Arc::new(borrowers);
+        let addresses = corrected
+            .iter()
+            .map(|(address, _)| address.clone())
+            .collect();
+        self.checkpoints
+            .write()
+            .await
+            .record_correction(corrected, &borrowers);
+        self.current.store(Arc::new(Version { height, borrowers }));
+        self.record_audit(entries).await;
+        Ok(addresses)
+    }
+
+    async fn history(&self, address: &Address) -> Result<Option<LoanHistory>> {
+        let last_updated = match self.current.load().borrowers.get(address) {
+            Some(loan) => loan.last_updated.clone(),
+            None => return Ok(None),
+        };
+        let events = self
+            .audit
+            .read()
+            .await
+            .get(address)
+            .map(|trail| trail.iter().cloned().collect())
+            .unwrap_or_default();
+        Ok(Some(LoanHistory {
+            last_updated,
+            events,
+        }))
+    }
+
+    async fn loan_at(&self, address: &Address, height: u64) -> Result<Option<Loan>> {
+        Ok(self
+            .checkpoints
+            .read()
+            .await
+            .loan_at(address, height)
+            .flatten())
+    }
+
+    async fn all_at(&self, height: u64) -> Result<Option<Borrowers>> {
+        Ok(self.checkpoints.read().await.state_at(height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{borrower, loan, loan_book};
+    use crate::cache::{CacheEvent, Provenance, TxEvent};
+    use crate::types::{CollateralToken, Micro};
+
+    #[tokio::test]
+    async fn applies_each_block_once() {
+        let store = MemoryStore::new();
+        let borrowers = loan_book(vec![loan(
+            1_200_000_000,
+            &[(CollateralToken::Bluna, 100_000_000)],
+        )]);
+        store.load(42, borrowers).await.unwrap();
+
+        let block = BlockEvents {
+            height: 43,
+            timestamp: None,
+            events: vec![TxEvent {
+                txhash: "TXHASH".to_string(),
+                event: CacheEvent::BorrowStable {
+                    address: borrower(0),
+                    amount: Micro::new(600_000_000),
+                },
+            }],
+            whitelist: vec![],
+            queue: vec![],
+        };
+        assert!(store.apply_block(&block).await.unwrap());
+        assert!(!store.apply_block(&block).await.unwrap());
+
+        assert_eq!(43, store.height().await.unwrap());
+        assert_eq!(
+            Some(Micro::new(1_800_000_000)),
+            store.get(&borrower(0)).await.unwrap().map(|l| l.amount)
+        );
+    }
+
+    #[tokio::test]
+    async fn records_audit_trail() {
+        let store = MemoryStore::new();
+        let borrowers = loan_book(vec![loan(
+            1_200_000_000,
+            &[(CollateralToken::Bluna, 100_000_000)],
+        )]);
+        store.load(42, borrowers).await.unwrap();
+
+        for height in 43..(43 + AUDIT_TRAIL_LEN as u64 + 5) {
+            let block = BlockEvents {
+                height,
+                timestamp: None,
+                events: vec![TxEvent {
+                    txhash: format!("TXHASH{}", height),
+                    event: CacheEvent::RepayStable {
+                        address: borrower(0),
+                        amount: Micro::new(1_000_000),
+                    },
+                }],
+                whitelist: vec![],
+                queue: vec![],
+            };
+            store.apply_block(&block).await.unwrap();
+        }
+
+        let history = store.history(&borrower(0)).await.unwrap().unwrap();
+        assert_eq!(
+            Some(Provenance {
+                height: 67,
+                txhash: "TXHASH67".to_string()
+            }),
+            history.last_updated
+        );
+        assert_eq!(AUDIT_TRAIL_LEN, history.events.len());
+        assert_eq!(
+            AuditEntry {
+                height: 67,
+                txhash: "TXHASH67".to_string(),
+                action: "repay_stable".to_string(),
+                contract_address: None,
+                delta: Micro::new(-1_000_000),
+                before: Micro::new(1_176_000_000),
+                after: Micro::new(1_175_000_000),
+            },
+            history.events[0]
+        );
+        assert_eq!(None, store.history(&borrower(3)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn readers_keep_their_version() {
+        let store = MemoryStore::new();
+        let borrowers = loan_book(vec![loan(
+            1_200_000_000,
+            &[(CollateralToken::Bluna, 100_000_000)],
+        )]);
+        store.load(42, borrowers.clone()).await.unwrap();
+
+        let before = store.all().await.unwrap();
+        let block = BlockEvents {
+            height: 43,
+            timestamp: None,
+            events: vec![TxEvent {
+                txhash: "TXHASH".to_string(),
+                event: CacheEvent::RepayStable {
+                    address: borrower(0),
+                    amount: Micro::new(200_000_000),
+                },
+            }],
+            whitelist: vec![],
+            queue: vec![],
+        };
+        store.apply_block(&block).await.unwrap();
+
+        assert_eq!(borrowers, *before);
+        assert_eq!(
+            Micro::new(1_000_000_000),
+            store.all().await.unwrap()[&borrower(0)].amount
+        );
+    }
+}
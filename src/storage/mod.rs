@@ -0,0 +1,68 @@
+pub mod checkpoints;
+pub mod memory;
+pub mod redis;
+pub mod sqlite;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+
+use crate::cache::{BlockEvents, Borrowers, Loan, LoanBook, LoanHistory};
+use crate::types::Address;
+
+pub use self::checkpoints::Checkpoints;
+pub use self::memory::MemoryStore;
+pub use self::redis::RedisStore;
+pub use self::sqlite::SqliteStore;
+
+/// The on-chain state of a loan, replacing the cached state it was compared with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Correction {
+    pub address: Address,
+    /// The cached state, left untouched if the loan was modified meanwhile
+    pub expected: Loan,
+    pub loan: Loan,
+}
+
+/// A storage backend for the loan book
+#[async_trait]
+pub trait LoanStore: Send + Sync {
+    /// Height of the last block applied to the store, 0 if unknown
+    async fn height(&self) -> Result<u64>;
+
+    async fn get(&self, address: &Address) -> Result<Option<Loan>>;
+
+    /// A consistent view of the whole loan book at the current height
+    async fn all(&self) -> Result<Borrowers>;
+
+    /// Replaces the whole loan book with `borrowers` as of `height`
+    async fn load(&self, height: u64, borrowers: LoanBook) -> Result<()>;
+
+    /// Applies all the events of a block.
+    /// Returns `false` if the block had already been applied, e.g. by another instance.
+    async fn apply_block(&self, block: &BlockEvents) -> Result<bool>;
+
+    /// Overwrites loans with their on-chain state read at `height`, e.g. after a reconciliation
+    /// found they had drifted, recording the modified balances in their audit trail.
+    /// Checked and written atomically: nothing is written unless the store is still at `height`,
+    /// and each loan only if it is still as `expected`.
+    /// Returns the addresses of the corrected loans.
+    async fn correct(&self, height: u64, corrections: Vec<Correction>) -> Result<Vec<Address>>;
+
+    /// The provenance and recent events of the loan of `address`, `None` if unknown
+    async fn history(&self, address: &Address) -> Result<Option<LoanHistory>>;
+
+    /// The loan of `address` as it was at the end of block `height`
+    async fn loan_at(&self, _address: &Address, _height: u64) -> Result<Option<Loan>> {
+        Err(anyhow!(
+            "Loan history isn't supported by this storage backend"
+        ))
+    }
+
+    /// The whole loan book as it was at the end of block `height`,
+    /// `None` if `height` is outside of the stored history
+    async fn all_at(&self, _height: u64) -> Result<Option<Borrowers>> {
+        Err(anyhow!(
+            "Loan history isn't supported by this storage backend"
+        ))
+    }
+}
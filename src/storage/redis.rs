@@ -0,0 +1,422 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use redis::aio::{Connection, ConnectionManager};
+use redis::{AsyncCommands, Client, Pipeline, Value};
+use rust_decimal::prelude::*;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::cache::{
+    AuditEntry, BlockEvents, Borrowers, CacheEvent, Loan, LoanBook, LoanHistory, Provenance,
+    AUDIT_TRAIL_LEN,
+};
+use crate::storage::{Correction, LoanStore};
+use crate::types::{Address, Micro};
+
+const KEY_PREFIX: &str = "liquidation_monitor";
+const AMOUNT_FIELD: &str = "amount";
+const COLLATERAL_FIELD_PREFIX: &str = "collateral:";
+const UPDATED_HEIGHT_FIELD: &str = "updated_height";
+const UPDATED_TXHASH_FIELD: &str = "updated_txhash";
+/// JSON of the `Rewards` of the loan
+const REWARDS_FIELD: &str = "rewards";
+/// Max number of commands sent in a single pipeline when reading the whole loan book
+const READ_CHUNK_SIZE: usize = 1000;
+
+/// A loan book shared between instances through Redis.
+///
+/// Every loan is stored as a hash (`amount` plus one `collateral:<token>` field per collateral),
+/// the addresses of all borrowers as a set, and their bLUNA liquidation prices
+/// at the default bETH price as a sorted set.
+/// The audit trail of each loan is a capped list of JSON `AuditEntry`s, latest first.
+/// Every write increments a version, which readers of the whole loan book watch.
+pub struct RedisStore {
+    manager: ConnectionManager,
+    /// Dedicated connection for the `WATCH`/`MULTI` transactions, which can't be multiplexed
+    writer: Mutex<Connection>,
+    /// Dedicated connection watching the version while reading the whole loan book
+    reader: Mutex<Connection>,
+    prefix: String,
+}
+
+impl RedisStore {
+    /// Connects to the Redis server at `url`, e.g. `redis://127.0.0.1/`
+    pub async fn connect<T: AsRef<str>>(url: T) -> Result<RedisStore> {
+        Self::connect_with_prefix(url, KEY_PREFIX).await
+    }
+
+    /// Connects to the Redis server at `url`, namespacing every key with `prefix`
+    pub async fn connect_with_prefix<T, P>(url: T, prefix: P) -> Result<RedisStore>
+    where
+        T: AsRef<str>,
+        P: ToString,
+    {
+        let client = Client::open(url.as_ref())?;
+        Ok(RedisStore {
+            manager: ConnectionManager::new(client.clone()).await?,
+            writer: Mutex::new(client.get_async_connection().await?),
+            reader: Mutex::new(client.get_async_connection().await?),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn height_key(&self) -> String {
+        format!("{}:height", self.prefix)
+    }
+
+    /// Incremented by every write to the loan book
+    fn version_key(&self) -> String {
+        format!("{}:version", self.prefix)
+    }
+
+    fn borrowers_key(&self) -> String {
+        format!("{}:borrowers", self.prefix)
+    }
+
+    fn loan_key(&self, address: &str) -> String {
+        format!("{}:loan:{}", self.prefix, address)
+    }
+
+    fn audit_key(&self, address: &str) -> String {
+        format!("{}:audit:{}", self.prefix, address)
+    }
+
+    /// Queues the commands replacing the stored `loan` of `address`
+    fn write_loan(&self, pipe: &mut Pipeline, address: &str, loan: &Loan) {
+        let key = self.loan_key(address);
+        let mut fields = vec![(AMOUNT_FIELD.to_string(), loan.amount.to_string())];
+        fields.extend(loan.collaterals.iter().map(|(token, amount)| {
+            (
+                format!("{}{}", COLLATERAL_FIELD_PREFIX, token),
+                amount.to_string(),
+            )
+        }));
+        if let Some(provenance) = &loan.last_updated {
+            fields.push((
+                UPDATED_HEIGHT_FIELD.to_string(),
+                provenance.height.to_string(),
+            ));
+            fields.push((UPDATED_TXHASH_FIELD.to_string(), provenance.txhash.clone()));
+        }
+        if let Some(rewards) = &loan.rewards {
+            fields.push((
+                REWARDS_FIELD.to_string(),
+                serde_json::to_string(rewards).expect("Error serializing rewards"),
+            ));
+        }
+
+        pipe.del(&key).ignore();
+        pipe.hset_multiple(&key, &fields).ignore();
+        pipe.sadd(self.borrowers_key(), address).ignore();
+    }
+}
+
+fn parse_loan(fields: HashMap<String, String>) -> Result<Option<Loan>> {
+    if fields.is_empty() {
+        return Ok(None);
+    }
+
+    let mut amount = None;
+    let mut collaterals = HashMap::new();
+    let mut updated_height = None;
+    let mut updated_txhash = None;
+    let mut rewards = None;
+    for (field, value) in fields {
+        if field == AMOUNT_FIELD {
+            amount = Some(Micro::try_from(Decimal::from_str(&value)?)?);
+        } else if let Some(token) = field.strip_prefix(COLLATERAL_FIELD_PREFIX) {
+            collaterals.insert(token.parse()?, Micro::try_from(Decimal::from_str(&value)?)?);
+        } else if field == UPDATED_HEIGHT_FIELD {
+            updated_height = Some(value.parse()?);
+        } else if field == UPDATED_TXHASH_FIELD {
+            updated_txhash = Some(value);
+        } else if field == REWARDS_FIELD {
+            rewards = Some(serde_json::from_str(&value)?);
+        }
+    }
+    let last_updated = match (updated_height, updated_txhash) {
+        (Some(height), Some(txhash)) => Some(Provenance { height, txhash }),
+        _ => None,
+    };
+
+    amount
+        .map(|amount| Loan {
+            amount,
+            collaterals,
+            last_updated,
+            rewards,
+        })
+        .map(Some)
+        .ok_or_else(|| anyhow!("Stored loan without an amount"))
+}
+
+#[async_trait]
+impl LoanStore for RedisStore {
+    async fn height(&self) -> Result<u64> {
+        let height: Option<u64> = self.manager.clone().get(self.height_key()).await?;
+        Ok(height.unwrap_or(0))
+    }
+
+    async fn get(&self, address: &Address) -> Result<Option<Loan>> {
+        let fields: HashMap<String, String> =
+            self.manager.clone().hgetall(self.loan_key(address)).await?;
+        parse_loan(fields)
+    }
+
+    async fn all(&self) -> Result<Borrowers> {
+        let mut con = self.reader.lock().await;
+        // the chunks are read one by one, so the read is retried if any write happened meanwhile
+        loop {
+            redis::cmd("WATCH")
+                .arg(self.version_key())
+                .query_async::<_, ()>(&mut *con)
+                .await?;
+            let addresses: Vec<String> = con.smembers(self.borrowers_key()).await?;
+
+            let mut borrowers = LoanBook::new();
+            for chunk in addresses.chunks(READ_CHUNK_SIZE) {
+                let mut pipe = redis::pipe();
+                chunk.iter().for_each(|address| {
+                    pipe.hgetall(self.loan_key(address));
+                });
+                let loans: Vec<HashMap<String, String>> = pipe.query_async(&mut *con).await?;
+                for (address, fields) in chunk.iter().zip(loans) {
+                    if let Some(loan) = parse_loan(fields)? {
+                        borrowers.insert(address.parse()?, loan);
+                    }
+                }
+            }
+
+            // an empty transaction would be skipped, and fails like any other if the version changed
+            let mut pipe = redis::pipe();
+            pipe.atomic().get(self.version_key());
+            let res: Option<Value> = pipe.query_async(&mut *con).await?;
+            if res.is_some() {
+                return Ok(Arc::new(borrowers));
+            }
+            debug!("Loan book modified while reading it, retrying");
+        }
+    }
+
+    async fn load(&self, height: u64, borrowers: LoanBook) -> Result<()> {
+        let mut con = self.writer.lock().await;
+        let existing: Vec<String> = con.smembers(self.borrowers_key()).await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        existing.iter().for_each(|address| {
+            pipe.del(self.loan_key(address)).ignore();
+            pipe.del(self.audit_key(address)).ignore();
+        });
+        pipe.del(self.borrowers_key()).ignore();
+        borrowers
+            .iter()
+            .for_each(|(address, loan)| self.write_loan(&mut pipe, address, loan));
+        pipe.set(self.height_key(), height).ignore();
+        pipe.incr(self.version_key(), 1).ignore();
+        pipe.query_async::<_, ()>(&mut *con).await?;
+        Ok(())
+    }
+
+    async fn apply_block(&self, block: &BlockEvents) -> Result<bool> {
+        let addresses: HashSet<&Address> = block.cache_events().map(CacheEvent::address).collect();
+        let mut watched = vec![self.height_key()];
+        watched.extend(addresses.iter().map(|address| self.loan_key(address)));
+
+        let mut con = self.writer.lock().await;
+        // optimistic transaction, retried if another instance modified a watched key meanwhile
+        loop {
+            redis::cmd("WATCH")
+                .arg(&watched)
+                .query_async::<_, ()>(&mut *con)
+                .await?;
+
+            let height: Option<u64> = con.get(self.height_key()).await?;
+            if height.unwrap_or(0) >= block.height {
+                redis::cmd("UNWATCH")
+                    .query_async::<_, ()>(&mut *con)
+                    .await?;
+                return Ok(false);
+            }
+
+            let mut loans = LoanBook::new();
+            for address in &addresses {
+                let fields: HashMap<String, String> = con.hgetall(self.loan_key(address)).await?;
+                if let Some(loan) = parse_loan(fields)? {
+                    loans.insert((*address).clone(), loan);
+                }
+            }
+            let entries = block.apply_to(&mut loans);
+
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            loans
+                .iter()
+                .for_each(|(address, loan)| self.write_loan(&mut pipe, address, loan));
+            for (address, entry) in &entries {
+                let key = self.audit_key(address);
+                pipe.lpush(&key, serde_json::to_string(entry)?).ignore();
+                pipe.ltrim(&key, 0, AUDIT_TRAIL_LEN as isize - 1).ignore();
+            }
+            pipe.incr(self.version_key(), 1).ignore();
+            pipe.set(self.height_key(), block.height);
+
+            let res: Option<Value> = pipe.query_async(&mut *con).await?;
+            if res.is_some() {
+                return Ok(true);
+            }
+            debug!("Transaction for block {} aborted, retrying", block.height);
+        }
+    }
+
+    async fn correct(&self, height: u64, corrections: Vec<Correction>) -> Result<Vec<Address>> {
+        let mut watched = vec![self.height_key()];
+        watched.extend(corrections.iter().map(|c| self.loan_key(&c.address)));
+
+        let mut con = self.writer.lock().await;
+        // optimistic transaction, retried if a block was applied to a watched key meanwhile
+        loop {
+            redis::cmd("WATCH")
+                .arg(&watched)
+                .query_async::<_, ()>(&mut *con)
+                .await?;
+
+            let current: Option<u64> = con.get(self.height_key()).await?;
+            let mut corrected = vec![];
+            if current.unwrap_or(0) == height {
+                for correction in &corrections {
+                    let fields: HashMap<String, String> =
+                        con.hgetall(self.loan_key(&correction.address)).await?;
+                    if parse_loan(fields)?.as_ref() == Some(&correction.expected) {
+                        corrected.push(correction);
+                    }
+                }
+            }
+            if corrected.is_empty() {
+                redis::cmd("UNWATCH")
+                    .query_async::<_, ()>(&mut *con)
+                    .await?;
+                return Ok(vec![]);
+            }
+
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for correction in &corrected {
+                self.write_loan(&mut pipe, &correction.address, &correction.loan);
+                let key = self.audit_key(&correction.address);
+                for entry in AuditEntry::corrections(height, &correction.expected, &correction.loan)
+                {
+                    pipe.lpush(&key, serde_json::to_string(&entry)?).ignore();
+                }
+                pipe.ltrim(&key, 0, AUDIT_TRAIL_LEN as isize - 1).ignore();
+            }
+            pipe.incr(self.version_key(), 1).ignore();
+
+            let res: Option<Value> = pipe.query_async(&mut *con).await?;
+            if res.is_some() {
+                return Ok(corrected.into_iter().map(|c| c.address.clone()).collect());
+            }
+            debug!("Correction at height {} aborted, retrying", height);
+        }
+    }
+
+    async fn history(&self, address: &Address) -> Result<Option<LoanHistory>> {
+        let last_updated = match self.get(address).await? {
+            Some(loan) => loan.last_updated,
+            None => return Ok(None),
+        };
+        let entries: Vec<String> = self
+            .manager
+            .clone()
+            .lrange(self.audit_key(address), 0, -1)
+            .await?;
+        let events = entries
+            .iter()
+            .map(|entry| serde_json::from_str::<AuditEntry>(entry))
+            .collect::<Result<_, _>>()?;
+        Ok(Some(LoanHistory {
+            last_updated,
+            events,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! These tests need a local redis-server, run them with `cargo test -- --ignored`
+
+    use super::*;
+    use crate::cache::testing::{borrower, loan, loan_book};
+    use crate::cache::TxEvent;
+    use crate::types::{CollateralToken, Micro};
+
+    const REDIS_URL: &str = "redis://127.0.0.1/";
+
+    async fn store(prefix: &str) -> RedisStore {
+        let store = RedisStore::connect_with_prefix(REDIS_URL, format!("test_{}", prefix))
+            .await
+            .expect("Could not connect to redis-server");
+        store.load(0, LoanBook::new()).await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn loads_and_reads_loans() {
+        let store = store("load").await;
+        let borrowers = loan_book(vec![
+            loan(1_200_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+            loan(600_000_000, &[(CollateralToken::Bluna, 100_000_000)]),
+        ]);
+        store.load(42, borrowers.clone()).await.unwrap();
+
+        assert_eq!(42, store.height().await.unwrap());
+        assert_eq!(borrowers, *store.all().await.unwrap());
+        assert_eq!(
+            Some(loan(600_000_000, &[(CollateralToken::Bluna, 100_000_000)])),
+            store.get(&borrower(1)).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn applies_each_block_once() {
+        let store = store("apply").await;
+        let borrowers = loan_book(vec![loan(
+            1_200_000_000,
+            &[(CollateralToken::Bluna, 100_000_000)],
+        )]);
+        store.load(42, borrowers).await.unwrap();
+
+        let block = BlockEvents {
+            height: 43,
+            timestamp: None,
+            events: vec![TxEvent {
+                txhash: "TXHASH".to_string(),
+                event: CacheEvent::BorrowStable {
+                    address: borrower(0),
+                    amount: Micro::new(600_000_000),
+                },
+            }],
+            whitelist: vec![],
+            queue: vec![],
+        };
+        assert!(store.apply_block(&block).await.unwrap());
+        assert!(!store.apply_block(&block).await.unwrap());
+
+        assert_eq!(43, store.height().await.unwrap());
+        let mut expected = loan(1_800_000_000, &[(CollateralToken::Bluna, 100_000_000)]);
+        expected.last_updated = Some(Provenance {
+            height: 43,
+            txhash: "TXHASH".to_string(),
+        });
+        assert_eq!(Some(expected), store.get(&borrower(0)).await.unwrap());
+        let history = store.history(&borrower(0)).await.unwrap().unwrap();
+        assert_eq!(1, history.events.len());
+    }
+}
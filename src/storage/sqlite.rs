@@ -0,0 +1,767 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use rust_decimal::Decimal;
+
+use crate::cache::{
+    apply_tx_event, AnchorAction, AuditEntry, BlockEvents, Borrowers, Loan, LoanBook, LoanHistory,
+    Provenance, AUDIT_TRAIL_LEN, CORRECTION_ACTION,
+};
+use crate::storage::{Correction, LoanStore};
+use crate::types::{Address, CollateralToken, Micro};
+
+/// `events` holds every applied `CacheEvent`, even those of unknown borrowers,
+/// whose `delta`, `before` and `after` are then `NULL`.
+/// `loan_states` holds the state of a loan after each event (`event_id`),
+/// as it was loaded from a seed or snapshot, or corrected by the reconciler (`source`).
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS loans (
+    address TEXT PRIMARY KEY,
+    amount TEXT NOT NULL,
+    collaterals TEXT NOT NULL,
+    updated_height INTEGER,
+    updated_txhash TEXT,
+    rewards TEXT
+);
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    height INTEGER NOT NULL,
+    txhash TEXT NOT NULL,
+    timestamp TEXT,
+    action TEXT NOT NULL,
+    address TEXT NOT NULL,
+    amount TEXT NOT NULL,
+    contract_address TEXT,
+    delta TEXT,
+    before TEXT,
+    after TEXT
+);
+CREATE INDEX IF NOT EXISTS events_address ON events (address, height);
+CREATE TABLE IF NOT EXISTS loan_states (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event_id INTEGER REFERENCES events (id),
+    address TEXT NOT NULL,
+    height INTEGER NOT NULL,
+    amount TEXT NOT NULL,
+    collaterals TEXT NOT NULL,
+    rewards TEXT,
+    source TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS loan_states_address ON loan_states (address, height);
+"#;
+
+/// An embedded SQLite loan book, which also keeps the full history of every loan
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteStore> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteStore> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<SqliteStore> {
+        conn.execute_batch(SCHEMA)?;
+        // databases created before rewards were tracked
+        for table in &["loans", "loan_states"] {
+            if !has_column(&conn, table, "rewards")? {
+                conn.execute(
+                    &format!("ALTER TABLE {} ADD COLUMN rewards TEXT", table),
+                    [],
+                )?;
+            }
+        }
+        // databases created before the source of the states was recorded,
+        // where corrections are only told from loads by their `correction` events
+        if !has_column(&conn, "loan_states", "source")? {
+            conn.execute_batch(
+                "ALTER TABLE loan_states ADD COLUMN source TEXT NOT NULL DEFAULT 'event';
+                 UPDATE loan_states SET source = CASE
+                     WHEN EXISTS (
+                         SELECT 1 FROM events e WHERE e.action = 'correction'
+                         AND e.address = loan_states.address AND e.height = loan_states.height
+                     ) THEN 'correction'
+                     ELSE 'load'
+                 END
+                 WHERE event_id IS NULL;",
+            )?;
+        }
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` on the blocking thread pool, since rusqlite is synchronous
+    async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|_| anyhow!("SQLite connection lock poisoned"))?;
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for name in names {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn parse_loan(
+    amount: String,
+    collaterals: String,
+    last_updated: Option<Provenance>,
+    rewards: Option<String>,
+) -> Result<Loan> {
+    Ok(Loan {
+        amount: Micro::try_from(Decimal::from_str(&amount)?)?,
+        collaterals: serde_json::from_str::<HashMap<CollateralToken, Micro>>(&collaterals)?,
+        last_updated,
+        rewards: rewards.map(|r| serde_json::from_str(&r)).transpose()?,
+    })
+}
+
+fn parse_provenance(height: Option<i64>, txhash: Option<String>) -> Option<Provenance> {
+    Some(Provenance {
+        height: height? as u64,
+        txhash: txhash?,
+    })
+}
+
+fn read_height(tx: &Connection) -> Result<u64> {
+    let height: Option<String> = tx
+        .query_row("SELECT value FROM meta WHERE key = 'height'", [], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    Ok(height.map(|h| h.parse()).transpose()?.unwrap_or(0))
+}
+
+fn write_height(tx: &Transaction, height: u64) -> Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES ('height', ?1)",
+        params![height.to_string()],
+    )?;
+    Ok(())
+}
+
+fn read_loan(tx: &Connection, address: &str) -> Result<Option<Loan>> {
+    tx.query_row(
+        "SELECT amount, collaterals, updated_height, updated_txhash, rewards
+         FROM loans WHERE address = ?1",
+        params![address],
+        |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                parse_provenance(row.get(2)?, row.get(3)?),
+                row.get(4)?,
+            ))
+        },
+    )
+    .optional()?
+    .map(|(amount, collaterals, last_updated, rewards)| {
+        parse_loan(amount, collaterals, last_updated, rewards)
+    })
+    .transpose()
+}
+
+/// What a state of a loan results from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StateSource {
+    /// A seed or snapshot
+    Load,
+    /// The event of this id
+    Event(i64),
+    /// A correction by the reconciler
+    Correction,
+}
+
+impl StateSource {
+    fn name(self) -> &'static str {
+        match self {
+            StateSource::Load => "load",
+            StateSource::Event(_) => "event",
+            StateSource::Correction => CORRECTION_ACTION,
+        }
+    }
+
+    fn event_id(self) -> Option<i64> {
+        match self {
+            StateSource::Event(id) => Some(id),
+            StateSource::Load | StateSource::Correction => None,
+        }
+    }
+}
+
+/// Writes the current `loan` of `address` along with a new entry in its history
+fn write_loan(
+    tx: &Transaction,
+    address: &str,
+    loan: &Loan,
+    height: u64,
+    source: StateSource,
+) -> Result<()> {
+    let amount = loan.amount.to_string();
+    let collaterals = serde_json::to_string(&loan.collaterals)?;
+    let rewards = loan
+        .rewards
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let (updated_height, updated_txhash) = match &loan.last_updated {
+        Some(p) => (Some(p.height as i64), Some(p.txhash.as_str())),
+        None => (None, None),
+    };
+    tx.execute(
+        "INSERT OR REPLACE INTO loans
+         (address, amount, collaterals, updated_height, updated_txhash, rewards)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            address,
+            amount,
+            collaterals,
+            updated_height,
+            updated_txhash,
+            rewards
+        ],
+    )?;
+    tx.execute(
+        "INSERT INTO loan_states
+         (event_id, address, height, amount, collaterals, rewards, source)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            source.event_id(),
+            address,
+            height as i64,
+            amount,
+            collaterals,
+            rewards,
+            source.name()
+        ],
+    )?;
+    Ok(())
+}
+
+#[async_trait]
+impl LoanStore for SqliteStore {
+    async fn height(&self) -> Result<u64> {
+        self.run(|conn| read_height(conn)).await
+    }
+
+    async fn get(&self, address: &Address) -> Result<Option<Loan>> {
+        let address = address.to_string();
+        self.run(move |conn| read_loan(conn, &address)).await
+    }
+
+    async fn all(&self) -> Result<Borrowers> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT address, amount, collaterals, updated_height, updated_txhash, rewards
+                 FROM loans",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    parse_provenance(row.get(3)?, row.get(4)?),
+                    row.get(5)?,
+                ))
+            })?;
+
+            let mut borrowers = LoanBook::new();
+            for row in rows {
+                let (address, amount, collaterals, last_updated, rewards): (
+                    String,
+                    String,
+                    String,
+                    _,
+                    _,
+                ) = row?;
+                borrowers.insert(
+                    address.parse()?,
+                    parse_loan(amount, collaterals, last_updated, rewards)?,
+                );
+            }
+            Ok(Arc::new(borrowers))
+        })
+        .await
+    }
+
+    async fn load(&self, height: u64, borrowers: LoanBook) -> Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            // the history before the new seed or snapshot no longer leads up to it
+            tx.execute("DELETE FROM loan_states", [])?;
+            tx.execute("DELETE FROM events", [])?;
+            tx.execute("DELETE FROM loans", [])?;
+            for (address, loan) in &borrowers {
+                write_loan(&tx, address, loan, height, StateSource::Load)?;
+            }
+            write_height(&tx, height)?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn apply_block(&self, block: &BlockEvents) -> Result<bool> {
+        let block = block.clone();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            if read_height(&tx)? >= block.height {
+                return Ok(false);
+            }
+
+            let timestamp = block.timestamp.map(|t| t.to_rfc3339());
+            for tx_event in &block.events {
+                let event = &tx_event.event;
+                // unknown borrowers are only recorded in `events`
+                let mut loans = LoanBook::new();
+                if let Some(loan) = read_loan(&tx, event.address())? {
+                    loans.insert(event.address().clone(), loan);
+                }
+                let entry = apply_tx_event(&mut loans, block.height, tx_event);
+
+                tx.execute(
+                    "INSERT INTO events
+                     (height, txhash, timestamp, action, address, amount, contract_address,
+                      delta, before, after)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                    params![
+                        block.height as i64,
+                        tx_event.txhash,
+                        timestamp,
+                        AnchorAction::from(event).to_string(),
+                        event.address().as_str(),
+                        event.amount().to_string(),
+                        event
+                            .contract_address()
+                            .map(CollateralToken::contract_address),
+                        entry.as_ref().map(|e| e.delta.to_string()),
+                        entry.as_ref().map(|e| e.before.to_string()),
+                        entry.as_ref().map(|e| e.after.to_string()),
+                    ],
+                )?;
+
+                if entry.is_some() {
+                    write_loan(
+                        &tx,
+                        event.address(),
+                        &loans[event.address()],
+                        block.height,
+                        StateSource::Event(tx.last_insert_rowid()),
+                    )?;
+                }
+            }
+            write_height(&tx, block.height)?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn correct(&self, height: u64, corrections: Vec<Correction>) -> Result<Vec<Address>> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            if read_height(&tx)? != height {
+                return Ok(vec![]);
+            }
+
+            let mut corrected = vec![];
+            for correction in corrections {
+                if read_loan(&tx, &correction.address)?.as_ref() != Some(&correction.expected) {
+                    continue;
+                }
+                for entry in AuditEntry::corrections(height, &correction.expected, &correction.loan)
+                {
+                    let amount = if entry.delta.is_negative() {
+                        -entry.delta
+                    } else {
+                        entry.delta
+                    };
+                    tx.execute(
+                        "INSERT INTO events
+                         (height, txhash, action, address, amount, contract_address,
+                          delta, before, after)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                        params![
+                            height as i64,
+                            entry.txhash,
+                            entry.action,
+                            correction.address.as_str(),
+                            amount.to_string(),
+                            entry
+                                .contract_address
+                                .map(CollateralToken::contract_address),
+                            entry.delta.to_string(),
+                            entry.before.to_string(),
+                            entry.after.to_string(),
+                        ],
+                    )?;
+                }
+                write_loan(
+                    &tx,
+                    &correction.address,
+                    &correction.loan,
+                    height,
+                    StateSource::Correction,
+                )?;
+                corrected.push(correction.address);
+            }
+            tx.commit()?;
+            Ok(corrected)
+        })
+        .await
+    }
+
+    async fn loan_at(&self, address: &Address, height: u64) -> Result<Option<Loan>> {
+        let address = address.to_string();
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT s.amount, s.collaterals, e.height, e.txhash, s.rewards
+                 FROM loan_states s LEFT JOIN events e ON e.id = s.event_id
+                 WHERE s.address = ?1 AND s.height <= ?2
+                 ORDER BY s.height DESC, s.id DESC LIMIT 1",
+                params![address, height as i64],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        parse_provenance(row.get(2)?, row.get(3)?),
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .optional()?
+            .map(|(amount, collaterals, last_updated, rewards)| {
+                parse_loan(amount, collaterals, last_updated, rewards)
+            })
+            .transpose()
+        })
+        .await
+    }
+
+    async fn all_at(&self, height: u64) -> Result<Option<Borrowers>> {
+        self.run(move |conn| {
+            let oldest: Option<i64> = conn.query_row(
+                "SELECT MIN(height) FROM loan_states WHERE source = 'load'",
+                [],
+                |row| row.get(0),
+            )?;
+            match oldest {
+                Some(oldest) if oldest as u64 <= height && height <= read_height(conn)? => {}
+                _ => return Ok(None),
+            }
+
+            let mut stmt = conn.prepare(
+                "SELECT s.address, s.amount, s.collaterals, e.height, e.txhash, s.rewards
+                 FROM (
+                     SELECT *, ROW_NUMBER() OVER (
+                         PARTITION BY address ORDER BY height DESC, id DESC
+                     ) AS rank
+                     FROM loan_states WHERE height <= ?1
+                 ) s LEFT JOIN events e ON e.id = s.event_id
+                 WHERE s.rank = 1",
+            )?;
+            let rows = stmt.query_map(params![height as i64], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    parse_provenance(row.get(3)?, row.get(4)?),
+                    row.get(5)?,
+                ))
+            })?;
+
+            let mut borrowers = LoanBook::new();
+            for row in rows {
+                let (address, amount, collaterals, last_updated, rewards): (
+                    String,
+                    String,
+                    String,
+                    _,
+                    _,
+                ) = row?;
+                borrowers.insert(
+                    address.parse()?,
+                    parse_loan(amount, collaterals, last_updated, rewards)?,
+                );
+            }
+            Ok(Some(Arc::new(borrowers)))
+        })
+        .await
+    }
+
+    async fn history(&self, address: &Address) -> Result<Option<LoanHistory>> {
+        let address = address.to_string();
+        self.run(move |conn| {
+            let last_updated = match read_loan(conn, &address)? {
+                Some(loan) => loan.last_updated,
+                None => return Ok(None),
+            };
+
+            let mut stmt = conn.prepare(
+                "SELECT height, txhash, action, contract_address, delta, before, after
+                 FROM events WHERE address = ?1 AND delta IS NOT NULL
+                 ORDER BY id DESC LIMIT ?2",
+            )?;
+            let rows = stmt.query_map(params![address, AUDIT_TRAIL_LEN as i64], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(5)?,
+                    row.get::<_, String>(6)?,
+                ))
+            })?;
+
+            let mut events = vec![];
+            for row in rows {
+                let (height, txhash, action, contract_address, delta, before, after) = row?;
+                events.push(AuditEntry {
+                    height: height as u64,
+                    txhash,
+                    action,
+                    contract_address: contract_address.map(|c| c.parse()).transpose()?,
+                    delta: Micro::try_from(Decimal::from_str(&delta)?)?,
+                    before: Micro::try_from(Decimal::from_str(&before)?)?,
+                    after: Micro::try_from(Decimal::from_str(&after)?)?,
+                });
+            }
+            Ok(Some(LoanHistory {
+                last_updated,
+                events,
+            }))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::{borrower, loan, loan_book};
+    use crate::cache::{CacheEvent, Rewards, TxEvent};
+    use crate::types::{CollateralToken, Micro};
+
+    /// A loan backed by `bluna`, whose rewards are stored along with it
+    fn rewarded_loan(amount: i64, bluna: i64) -> Loan {
+        Loan {
+            rewards: Some(Rewards {
+                pending: Micro::new(1_500_000),
+                reward_index: Decimal::new(125, 2),
+                height: 42,
+            }),
+            ..loan(amount, &[(CollateralToken::Bluna, bluna)])
+        }
+    }
+
+    fn borrow(height: u64, borrower: Address, amount: i64) -> BlockEvents {
+        BlockEvents {
+            height,
+            timestamp: None,
+            events: vec![TxEvent {
+                txhash: format!("TXHASH{}", height),
+                event: CacheEvent::BorrowStable {
+                    address: borrower,
+                    amount: Micro::new(amount),
+                },
+            }],
+            whitelist: vec![],
+            queue: vec![],
+        }
+    }
+
+    /// The source of every state of the history, oldest first
+    async fn sources(store: &SqliteStore) -> Vec<String> {
+        store
+            .run(|conn| {
+                let mut stmt = conn.prepare("SELECT source FROM loan_states ORDER BY id")?;
+                let sources = stmt.query_map([], |row| row.get(0))?;
+                Ok(sources.collect::<rusqlite::Result<_>>()?)
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn applies_each_block_once() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let borrowers = loan_book(vec![rewarded_loan(1_200_000_000, 100_000_000)]);
+        store.load(42, borrowers).await.unwrap();
+
+        let block = borrow(43, borrower(0), 600_000_000);
+        assert!(store.apply_block(&block).await.unwrap());
+        assert!(!store.apply_block(&block).await.unwrap());
+
+        assert_eq!(43, store.height().await.unwrap());
+        let mut expected = rewarded_loan(1_800_000_000, 100_000_000);
+        expected.last_updated = Some(Provenance {
+            height: 43,
+            txhash: "TXHASH43".to_string(),
+        });
+        assert_eq!(Some(expected), store.get(&borrower(0)).await.unwrap());
+
+        let history = store.history(&borrower(0)).await.unwrap().unwrap();
+        assert_eq!(1, history.events.len());
+        assert_eq!(Micro::new(600_000_000), history.events[0].delta);
+        assert_eq!(Micro::new(1_200_000_000), history.events[0].before);
+        assert_eq!(None, store.history(&borrower(3)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn answers_loan_at_height() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let borrowers = loan_book(vec![rewarded_loan(1_000_000_000, 100_000_000)]);
+        store.load(42, borrowers).await.unwrap();
+
+        store
+            .apply_block(&borrow(45, borrower(0), 500_000_000))
+            .await
+            .unwrap();
+        store
+            .apply_block(&borrow(50, borrower(0), 500_000_000))
+            .await
+            .unwrap();
+        store
+            .apply_block(&borrow(51, borrower(3), 500_000_000))
+            .await
+            .unwrap();
+
+        assert_eq!(None, store.loan_at(&borrower(0), 41).await.unwrap());
+        assert_eq!(
+            Some(rewarded_loan(1_000_000_000, 100_000_000)),
+            store.loan_at(&borrower(0), 44).await.unwrap()
+        );
+        assert_eq!(
+            Some(Micro::new(1_500_000_000)),
+            store
+                .loan_at(&borrower(0), 49)
+                .await
+                .unwrap()
+                .map(|l| l.amount)
+        );
+        assert_eq!(
+            Some(Micro::new(2_000_000_000)),
+            store
+                .loan_at(&borrower(0), 50)
+                .await
+                .unwrap()
+                .map(|l| l.amount)
+        );
+        assert_eq!(None, store.loan_at(&borrower(3), 51).await.unwrap());
+
+        assert_eq!(None, store.all_at(41).await.unwrap());
+        assert_eq!(None, store.all_at(52).await.unwrap());
+        let borrowers = store.all_at(49).await.unwrap().unwrap();
+        assert_eq!(1, borrowers.len());
+        assert_eq!(Micro::new(1_500_000_000), borrowers[&borrower(0)].amount);
+    }
+
+    #[tokio::test]
+    async fn forgets_the_history_on_reload() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let borrowers = loan_book(vec![rewarded_loan(1_000_000_000, 100_000_000)]);
+        store.load(42, borrowers).await.unwrap();
+        store
+            .apply_block(&borrow(45, borrower(0), 500_000_000))
+            .await
+            .unwrap();
+
+        let mut borrowers = LoanBook::new();
+        borrowers.insert(borrower(3), rewarded_loan(700_000_000, 50_000_000));
+        store.load(60, borrowers).await.unwrap();
+
+        assert_eq!(None, store.loan_at(&borrower(0), 50).await.unwrap());
+        assert_eq!(None, store.loan_at(&borrower(0), 60).await.unwrap());
+        assert_eq!(None, store.all_at(50).await.unwrap());
+        let borrowers = store.all_at(60).await.unwrap().unwrap();
+        assert_eq!(1, borrowers.len());
+        assert!(borrowers.contains_key(&borrower(3)));
+        assert_eq!(None, store.history(&borrower(0)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn corrects_unmodified_loans_at_the_current_height() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let borrowers = loan_book(vec![rewarded_loan(1_000_000_000, 100_000_000)]);
+        store.load(42, borrowers).await.unwrap();
+        let correction = |expected| Correction {
+            address: borrower(0),
+            expected,
+            loan: rewarded_loan(900_000_000, 100_000_000),
+        };
+
+        // read at another height, or from a stale loan
+        assert!(store
+            .correct(
+                41,
+                vec![correction(rewarded_loan(1_000_000_000, 100_000_000))]
+            )
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(store
+            .correct(
+                42,
+                vec![correction(rewarded_loan(800_000_000, 100_000_000))]
+            )
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            vec![borrower(0)],
+            store
+                .correct(
+                    42,
+                    vec![correction(rewarded_loan(1_000_000_000, 100_000_000))]
+                )
+                .await
+                .unwrap()
+        );
+
+        assert_eq!(
+            Some(rewarded_loan(900_000_000, 100_000_000)),
+            store.get(&borrower(0)).await.unwrap()
+        );
+        let history = store.history(&borrower(0)).await.unwrap().unwrap();
+        assert_eq!(1, history.events.len());
+        assert_eq!(CORRECTION_ACTION, history.events[0].action);
+        assert_eq!(Micro::new(-100_000_000), history.events[0].delta);
+        assert_eq!(
+            vec!["load".to_string(), CORRECTION_ACTION.to_string()],
+            sources(&store).await
+        );
+    }
+}
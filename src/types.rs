@@ -0,0 +1,380 @@
+use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Deref, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use bech32::{FromBase32, Variant};
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::request::FromParam;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::mantle::Contracts;
+
+const ADDRESS_PREFIX: &str = "terra";
+
+/// A validated bech32 Terra address, of an account or a contract
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Address(String);
+
+impl Address {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (hrp, data, variant) =
+            bech32::decode(s).map_err(|e| anyhow!("Invalid Terra address '{}': {}", s, e))?;
+        if hrp != ADDRESS_PREFIX || variant != Variant::Bech32 {
+            return Err(anyhow!(
+                "Invalid Terra address '{}': not a terra1 address",
+                s
+            ));
+        }
+        // accounts and (pre Columbus-5) contracts are 20 bytes long, newer contracts 32 bytes
+        match Vec::<u8>::from_base32(&data) {
+            Ok(bytes) if bytes.len() == 20 || bytes.len() == 32 => Ok(Address(s.to_lowercase())),
+            _ => Err(anyhow!("Invalid Terra address '{}': wrong length", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Address> for String {
+    fn from(address: Address) -> Self {
+        address.0
+    }
+}
+
+impl AsRef<str> for Address {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Address {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Allows looking up maps keyed by `Address` with a `&str`
+impl Borrow<str> for Address {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<'a> FromParam<'a> for Address {
+    type Error = Error;
+
+    fn from_param(param: &'a str) -> Result<Self> {
+        param.parse()
+    }
+}
+
+/// The registry of the collateral tokens accepted by Anchor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CollateralToken {
+    Bluna,
+    Beth,
+}
+
+impl CollateralToken {
+    pub const ALL: [CollateralToken; 2] = [CollateralToken::Bluna, CollateralToken::Beth];
+
+    /// Address of the CW20 contract of the token
+    pub fn contract_address(self) -> &'static str {
+        match self {
+            CollateralToken::Bluna => Contracts::BLUNA,
+            CollateralToken::Beth => Contracts::BETH,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            CollateralToken::Bluna => "bLUNA",
+            CollateralToken::Beth => "bETH",
+        }
+    }
+
+    pub fn from_contract_address(contract_address: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|token| token.contract_address() == contract_address)
+    }
+
+    /// The token of a symbol, ignoring case (e.g. `beth`)
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|token| token.symbol().eq_ignore_ascii_case(symbol))
+    }
+}
+
+/// A token in a query string, either by symbol or contract address
+impl<'v> FromFormField<'v> for CollateralToken {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Self::from_symbol(field.value)
+            .or_else(|| Self::from_contract_address(field.value))
+            .ok_or_else(|| {
+                form::Error::validation(format!("Unknown collateral token '{}'", field.value))
+                    .into()
+            })
+    }
+}
+
+impl FromStr for CollateralToken {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_contract_address(s).ok_or_else(|| anyhow!("Unknown collateral token '{}'", s))
+    }
+}
+
+impl TryFrom<String> for CollateralToken {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<CollateralToken> for String {
+    fn from(token: CollateralToken) -> Self {
+        token.contract_address().to_string()
+    }
+}
+
+impl fmt::Display for CollateralToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.contract_address())
+    }
+}
+
+/// An amount of UST or of a collateral token, with the micro-unit precision of the chain.
+///
+/// Serialized as a decimal amount of whole tokens (e.g. `"1.000000"` for 1 UST),
+/// while `Micro::new` and `Micro::parse_micro` take on-chain micro-units (e.g. `1000000` uusd).
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "Decimal", into = "Decimal")]
+pub struct Micro(Decimal);
+
+impl Micro {
+    pub const DECIMALS: u32 = 6;
+
+    /// An amount of `micro` micro-units
+    pub fn new(micro: i64) -> Self {
+        Micro(Decimal::new(micro, Self::DECIMALS))
+    }
+
+    pub fn zero() -> Self {
+        Self::default()
+    }
+
+    /// Parses an unsigned integer amount of micro-units, as found in contract events
+    pub fn parse_micro(s: &str) -> Result<Self> {
+        let micro = s
+            .parse::<u64>()
+            .map_err(|e| anyhow!("Invalid micro-unit amount '{}': {}", s, e))?;
+        Decimal::try_from_i128_with_scale(micro as i128, Self::DECIMALS)
+            .map(Micro)
+            .map_err(|e| anyhow!("Invalid micro-unit amount '{}': {}", s, e))
+    }
+
+    /// An amount of whole tokens, rounded to the nearest micro-unit
+    pub fn from_units(mut units: Decimal) -> Self {
+        units.rescale(Self::DECIMALS);
+        Micro(units)
+    }
+
+    /// The amount in whole tokens
+    pub fn units(self) -> Decimal {
+        self.0
+    }
+
+    /// The amount in micro-units
+    pub fn micro_units(self) -> i128 {
+        let mut units = self.0;
+        units.rescale(Self::DECIMALS);
+        units.mantissa()
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0.is_zero()
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0.is_sign_negative() && !self.0.is_zero()
+    }
+
+    /// `None` if the sum doesn't fit in a decimal
+    pub fn checked_add(self, rhs: Micro) -> Option<Micro> {
+        self.0.checked_add(rhs.0).map(Micro)
+    }
+
+    /// `None` if the difference doesn't fit in a decimal
+    pub fn checked_sub(self, rhs: Micro) -> Option<Micro> {
+        self.0.checked_sub(rhs.0).map(Micro)
+    }
+}
+
+impl TryFrom<Decimal> for Micro {
+    type Error = Error;
+
+    fn try_from(units: Decimal) -> Result<Self> {
+        if units != units.round_dp(Self::DECIMALS) {
+            return Err(anyhow!(
+                "Amount {} is more precise than a micro-unit",
+                units
+            ));
+        }
+        Ok(Self::from_units(units))
+    }
+}
+
+impl From<Micro> for Decimal {
+    fn from(amount: Micro) -> Self {
+        amount.0
+    }
+}
+
+impl fmt::Display for Micro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Add for Micro {
+    type Output = Micro;
+
+    fn add(self, rhs: Micro) -> Micro {
+        Micro(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Micro {
+    type Output = Micro;
+
+    fn sub(self, rhs: Micro) -> Micro {
+        Micro(self.0 - rhs.0)
+    }
+}
+
+impl AddAssign for Micro {
+    fn add_assign(&mut self, rhs: Micro) {
+        self.0 += rhs.0;
+    }
+}
+
+impl SubAssign for Micro {
+    fn sub_assign(&mut self, rhs: Micro) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Neg for Micro {
+    type Output = Micro;
+
+    fn neg(self) -> Micro {
+        Micro(-self.0)
+    }
+}
+
+impl Sum for Micro {
+    fn sum<I: Iterator<Item = Micro>>(iter: I) -> Micro {
+        iter.fold(Micro::zero(), Add::add)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_addresses() {
+        assert!("terra1003n7ppfqy9fvv0xxr0n24aqrsh0pkhwcsyxrf"
+            .parse::<Address>()
+            .is_ok());
+        assert!(Contracts::BLUNA.parse::<Address>().is_ok());
+        // wrong checksum
+        assert!("terra1003n7ppfqy9fvv0xxr0n24aqrsh0pkhwcsyxrg"
+            .parse::<Address>()
+            .is_err());
+        // wrong prefix
+        assert!("cosmos1003n7ppfqy9fvv0xxr0n24aqrsh0pkhwcsyxrf"
+            .parse::<Address>()
+            .is_err());
+        assert!("abcd".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn maps_collateral_tokens() {
+        assert_eq!(
+            Some(CollateralToken::Beth),
+            CollateralToken::from_contract_address(Contracts::BETH)
+        );
+        assert!(Contracts::MARKET.parse::<CollateralToken>().is_err());
+        assert_eq!(
+            Some(CollateralToken::Beth),
+            CollateralToken::from_symbol("beth")
+        );
+        assert_eq!(
+            format!("\"{}\"", Contracts::BLUNA),
+            serde_json::to_string(&CollateralToken::Bluna).unwrap()
+        );
+    }
+
+    #[test]
+    fn converts_micro_units() {
+        assert_eq!(
+            Micro::new(1_500_000),
+            Micro::parse_micro("1500000").unwrap()
+        );
+        assert_eq!(Decimal::new(15, 1), Micro::new(1_500_000).units());
+        assert_eq!(
+            1_500_000,
+            Micro::from_units(Decimal::new(15, 1)).micro_units()
+        );
+        assert!(Micro::parse_micro("1.5").is_err());
+        assert!(Micro::parse_micro("-1").is_err());
+
+        assert_eq!(
+            "\"1.500000\"",
+            serde_json::to_string(&Micro::new(1_500_000)).unwrap()
+        );
+        assert_eq!(
+            Micro::new(1_500_000),
+            serde_json::from_str("\"1.5\"").unwrap()
+        );
+        assert!(serde_json::from_str::<Micro>("\"1.0000001\"").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use crate::mantle::client::query_contract_store;
+use crate::mantle::queries::{FromJson, OverseerWhitelist, OverseerWhitelistQuery};
+use crate::mantle::Contracts;
+use crate::types::CollateralToken;
+
+pub type SharedMaxLtvs = Arc<RwLock<MaxLtvs>>;
+
+/// A change of the max LTV of a collateral, voted by governance
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WhitelistUpdate {
+    pub collateral_token: CollateralToken,
+    pub max_ltv: Decimal,
+}
+
+/// The max LTV of a collateral, as of `height`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MaxLtv {
+    pub max_ltv: Decimal,
+    pub height: u64,
+}
+
+/// The max LTV of every collateral, as whitelisted by the overseer
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct MaxLtvs(HashMap<CollateralToken, MaxLtv>);
+
+impl Default for MaxLtvs {
+    /// The max LTV every collateral had on Columbus-5 launch, until the whitelist is fetched
+    fn default() -> Self {
+        let max_ltv = MaxLtv {
+            max_ltv: default_max_ltv(),
+            height: 0,
+        };
+        MaxLtvs(
+            CollateralToken::ALL
+                .iter()
+                .map(|token| (*token, max_ltv))
+                .collect(),
+        )
+    }
+}
+
+fn default_max_ltv() -> Decimal {
+    Decimal::new(6, 1)
+}
+
+impl MaxLtvs {
+    /// Parses the whitelist answered at `height`, ignoring untracked collaterals.
+    /// Collaterals missing from it keep the default max LTV.
+    pub fn from_whitelist(height: u64, whitelist: &OverseerWhitelist) -> Result<Self> {
+        let mut max_ltvs = MaxLtvs::default();
+        for elem in &whitelist.elems {
+            match CollateralToken::from_contract_address(&elem.collateral_token) {
+                Some(token) => {
+                    let max_ltv = parse_max_ltv(&elem.max_ltv)?;
+                    max_ltvs.0.insert(token, MaxLtv { max_ltv, height });
+                }
+                None => debug!("Ignoring whitelisted token {}", elem.collateral_token),
+            }
+        }
+        Ok(max_ltvs)
+    }
+
+    /// Reads the whole whitelist from the overseer through the Mantle at `host`
+    pub async fn fetch(host: &str) -> Result<Self> {
+        let (height, result) =
+            query_contract_store(host, Contracts::OVERSEER, &OverseerWhitelistQuery::all()).await?;
+        Self::from_whitelist(height, &OverseerWhitelist::from_json(&result)?)
+    }
+
+    pub fn get(&self, token: CollateralToken) -> Decimal {
+        self.0
+            .get(&token)
+            .map_or_else(default_max_ltv, |ltv| ltv.max_ltv)
+    }
+
+    /// Applies an update emitted at `height`, unless the max LTV is already known at a later height.
+    /// Returns whether it was applied.
+    pub fn apply(&mut self, height: u64, update: &WhitelistUpdate) -> bool {
+        let current = self.0.get(&update.collateral_token);
+        if current.is_some_and(|ltv| ltv.height > height) {
+            return false;
+        }
+        self.0.insert(
+            update.collateral_token,
+            MaxLtv {
+                max_ltv: update.max_ltv,
+                height,
+            },
+        );
+        true
+    }
+}
+
+/// Parses a max LTV, which must be in `[0, 1)`
+pub fn parse_max_ltv(s: &str) -> Result<Decimal> {
+    let max_ltv = Decimal::from_str(s).map_err(|e| anyhow!("Invalid max LTV '{}': {}", s, e))?;
+    if max_ltv.is_sign_negative() || max_ltv >= Decimal::ONE {
+        return Err(anyhow!("Max LTV {} out of range", max_ltv));
+    }
+    Ok(max_ltv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mantle::queries::Elem;
+
+    fn elem(token: &str, max_ltv: &str) -> Elem {
+        Elem {
+            collateral_token: token.to_string(),
+            custody_contract: Contracts::MARKET.to_string(),
+            max_ltv: max_ltv.to_string(),
+            name: String::new(),
+            symbol: String::new(),
+        }
+    }
+
+    #[test]
+    fn tracks_max_ltv_per_collateral() {
+        let whitelist = OverseerWhitelist {
+            elems: vec![
+                elem(Contracts::BLUNA, "0.5"),
+                elem(Contracts::BETH, "0.55"),
+                elem(Contracts::MARKET, "0.9"),
+            ],
+        };
+        let mut max_ltvs = MaxLtvs::from_whitelist(100, &whitelist).unwrap();
+        assert_eq!(Decimal::new(5, 1), max_ltvs.get(CollateralToken::Bluna));
+        assert_eq!(Decimal::new(55, 2), max_ltvs.get(CollateralToken::Beth));
+
+        let update = |max_ltv| WhitelistUpdate {
+            collateral_token: CollateralToken::Bluna,
+            max_ltv,
+        };
+        // replayed from before the whitelist was fetched
+        assert!(!max_ltvs.apply(99, &update(Decimal::new(6, 1))));
+        assert!(max_ltvs.apply(101, &update(Decimal::new(45, 2))));
+        assert_eq!(Decimal::new(45, 2), max_ltvs.get(CollateralToken::Bluna));
+
+        assert!(parse_max_ltv("1.2").is_err());
+        assert!(MaxLtvs::from_whitelist(
+            100,
+            &OverseerWhitelist {
+                elems: vec![elem(Contracts::BETH, "-0.1")],
+            }
+        )
+        .is_err());
+    }
+}